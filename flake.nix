@@ -97,7 +97,8 @@
               --set PATH ${
                 lib.makeBinPath [ pkgs.binutils-unwrapped pkgs.sbsigntool ]
               } \
-              --set LANZABOOTE_STUB ${stub}/bin/lanzaboote_stub.efi
+              --set LANZABOOTE_STUB ${stub}/bin/lanzaboote_stub.efi \
+              --set LANZABOOTE_FAT_STUB ${fatStub}/bin/lanzaboote_stub.efi
           '';
         in
         {
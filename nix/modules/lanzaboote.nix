@@ -15,6 +15,51 @@ let
   loaderConfigFile = loaderSettingsFormat.generate "loader.conf" cfg.settings;
 
   configurationLimit = if cfg.configurationLimit == null then 0 else cfg.configurationLimit;
+
+  profileConfigurationLimitArgs = lib.concatStringsSep " " (lib.mapAttrsToList
+    (profile: limit: "--profile-configuration-limit ${lib.escapeShellArg "${profile}=${toString limit}"}")
+    cfg.profileConfigurationLimits);
+
+  efiToolArgs = lib.concatStringsSep " " (lib.mapAttrsToList
+    (name: path: "--efi-tool ${lib.escapeShellArg "${name}=${path}"}")
+    cfg.efiTools);
+
+  extraInitrdArgs = lib.concatMapStringsSep " "
+    (initrd: "--extra-initrd ${lib.escapeShellArg initrd}")
+    cfg.extraInitrds;
+
+  initrdSecretsCredentialArgs = optionalString (cfg.initrdSecretsCredential != null)
+    "--initrd-secrets-credential ${cfg.initrdSecretsCredential}";
+
+  titleTemplateArgs = optionalString (cfg.titleTemplate != null)
+    "--title-template ${lib.escapeShellArg cfg.titleTemplate}";
+
+  pinArgs = lib.concatMapStringsSep " "
+    (pin: "--pin ${lib.escapeShellArg (toString pin)}")
+    cfg.pinnedGenerations;
+
+  pinFileArgs = optionalString (cfg.pinFile != null)
+    "--pin-file ${lib.escapeShellArg cfg.pinFile}";
+
+  quarantineInstallsArgs = optionalString (cfg.quarantineInstalls != null)
+    "--quarantine-installs ${toString cfg.quarantineInstalls}";
+
+  retentionArgs = lib.concatStringsSep " " [
+    (optionalString (cfg.keepDays != null) "--keep-days ${toString cfg.keepDays}")
+    (optionalString (cfg.maxImages != null) "--max-images ${toString cfg.maxImages}")
+    (optionalString (cfg.specialisationsLimit != null)
+      "--specialisations-limit ${toString cfg.specialisationsLimit}")
+  ];
+
+  systemdBootArgs = lib.concatStringsSep " " ([
+    (optionalString (cfg.systemdBootVersion != null)
+      "--systemd-boot-version ${lib.escapeShellArg cfg.systemdBootVersion}")
+    (optionalString cfg.allowSystemdBootDowngrade "--allow-systemd-boot-downgrade")
+  ] ++ map (version: "--deny-systemd-boot-version ${lib.escapeShellArg version}")
+    cfg.deniedSystemdBootVersions);
+
+  rescueArgs = optionalString (cfg.rescueGeneration != null)
+    "--rescue ${lib.escapeShellArg cfg.rescueGeneration}";
 in
 {
   options.boot.lanzaboote = {
@@ -35,6 +80,19 @@ in
       '';
     };
 
+    profileConfigurationLimits = mkOption {
+      type = types.attrsOf types.int;
+      default = { };
+      example = { my-server = 5; };
+      description = lib.mdDoc ''
+        Maximum number of latest generations in the boot menu for specific
+        profiles in `/nix/var/nix/profiles/system-profiles`.
+
+        Profiles that are not listed here use `configurationLimit`. `0` means
+        no limit.
+      '';
+    };
+
     pkiBundle = mkOption {
       type = types.nullOr types.path;
       description = "PKI bundle containing db, PK, KEK";
@@ -52,6 +110,301 @@ in
       description = "Private key to sign your boot files";
     };
 
+    efiTools = mkOption {
+      type = types.attrsOf types.path;
+      default = { };
+      example = literalExpression ''
+        {
+          memtest86 = "''${pkgs.memtest86plus}/memtest.efi";
+          shell = "''${pkgs.edk2-uefi-shell}/shell.efi";
+        }
+      '';
+      description = lib.mdDoc ''
+        Extra EFI binaries that are signed and added to the boot menu.
+
+        The attribute name is used as the title of the boot menu entry. The
+        binaries are installed to `EFI/nixos/tools` on the ESP.
+      '';
+    };
+
+    extraInitrds = mkOption {
+      type = types.listOf types.path;
+      default = [ ];
+      example = literalExpression ''[ "''${pkgs.microcodeIntel}/intel-ucode.img" ]'';
+      description = lib.mdDoc ''
+        Additional initrds that are loaded before the initrd of each
+        generation, e.g. CPU microcode.
+
+        The initrds are installed to the ESP separately and concatenated by the
+        stub in the given order. Changing them does not require rebuilding the
+        initrd of the generation.
+      '';
+    };
+
+    initrdSecretsCredential = mkOption {
+      type = types.nullOr (types.enum [ "tpm2" ]);
+      default = null;
+      example = "tpm2";
+      description = lib.mdDoc ''
+        Ship initrd secrets (`boot.initrd.secrets`) as a systemd credential
+        encrypted with the given key instead of appending them to the initrd.
+
+        The credential is installed next to the image of each generation and
+        picked up by the stub at boot. This keeps the secrets off the ESP in
+        plain text and the initrd on the ESP identical to the one in the store.
+
+        Only `tpm2` is supported. The credential is decrypted in the initrd,
+        before the host key in /var/lib/systemd/credential.secret on the root
+        file system is available.
+
+        `null` appends the secrets to a copy of the initrd.
+      '';
+    };
+
+    titleTemplate = mkOption {
+      type = types.nullOr types.str;
+      default = null;
+      example = "{label} - Generation {generation}{specialisation}";
+      description = lib.mdDoc ''
+        Template for the titles of the boot menu entries.
+
+        Supported placeholders are `{label}`, `{name}`, `{nixos_version}`,
+        `{kernel_version}`, `{generation}`, `{build_time}`, `{profile}`,
+        `{specialisation}` and `{pinned}`. `{build_time}` is the time the
+        generation was added to the Nix store of this machine, which is not
+        the time it was built if it was substituted or copied. `null` uses the
+        default template of lzbt.
+      '';
+    };
+
+    quarantineInstalls = mkOption {
+      type = types.nullOr types.ints.positive;
+      default = null;
+      example = 3;
+      description = lib.mdDoc ''
+        Move unknown files in `EFI/nixos` on the ESP to `EFI/nixos/.quarantine`
+        instead of deleting them during garbage collection. Quarantined files
+        are deleted after this number of successful installs. Files installed
+        by lanzaboote itself are still deleted right away.
+
+        `null` deletes unknown files right away.
+      '';
+    };
+
+    keepDays = mkOption {
+      type = types.nullOr types.ints.unsigned;
+      default = null;
+      example = 14;
+      description = lib.mdDoc ''
+        Keep generations built within this number of days even if they exceed
+        the configuration limit.
+
+        `null` only keeps the generations within the configuration limit.
+      '';
+    };
+
+    maxImages = mkOption {
+      type = types.nullOr types.ints.positive;
+      default = null;
+      example = 20;
+      description = lib.mdDoc ''
+        Maximum number of images installed to the ESP. Each specialisation
+        counts as an image. The oldest generations are dropped first. Pinned
+        generations, the booted generation and the latest generation of each
+        profile are always installed, even if this exceeds the maximum.
+
+        `null` does not limit the number of images.
+      '';
+    };
+
+    specialisationsLimit = mkOption {
+      type = types.nullOr types.ints.unsigned;
+      default = null;
+      example = 1;
+      description = lib.mdDoc ''
+        Only install the specialisations of this number of the newest
+        generations of each profile.
+
+        `null` installs the specialisations of all generations.
+      '';
+    };
+
+    fallbackPolicy = mkOption {
+      type = types.enum [ "take-over" "leave" "backup" ];
+      default = "backup";
+      description = lib.mdDoc ''
+        What to do with a fallback boot loader (`EFI/BOOT/BOOTX64.EFI` on the
+        ESP) that is not systemd-boot, e.g. the boot loader of Windows or of
+        another distribution on a dual-boot machine.
+
+        - `take-over` replaces it with systemd-boot.
+        - `leave` leaves it alone. systemd-boot is not installed as the
+          fallback boot loader.
+        - `backup` backs it up to `EFI/nixos/fallback-backup` and replaces it
+          with systemd-boot. `lzbt uninstall` restores the backup unless
+          `--no-restore-fallback` is passed.
+
+        `backup` is the default. Earlier versions of lanzaboote always
+        replaced the fallback boot loader without a backup. Set this to
+        `take-over` to keep that behaviour.
+      '';
+    };
+
+    rescueGeneration = mkOption {
+      type = types.nullOr types.str;
+      default = null;
+      example = "/nix/var/nix/profiles/system-42-link";
+      description = lib.mdDoc ''
+        A generation link or a toplevel to build a rescue image from. The
+        rescue image is installed as `EFI/Linux/nixos-rescue.efi` on the ESP
+        and listed as a separate boot menu entry after the regular
+        generations. It is never pruned by the configuration limit or the
+        garbage collection.
+
+        The installed rescue image is kept if this option is unset. It is
+        only replaced by a newer generation. Run `lzbt install` with
+        `--replace-rescue` to replace it with an older one.
+      '';
+    };
+
+    legacyEntryPolicy = mkOption {
+      type = types.enum [ "warn" "remove" ];
+      default = "remove";
+      description = lib.mdDoc ''
+        What to do with Type #1 loader entries in `loader/entries` on the ESP
+        that boot NixOS without lanzaboote, e.g. the entries left over from
+        booting with plain systemd-boot. systemd-boot still offers them and
+        they can be used to bypass Secure Boot.
+
+        - `warn` only reports them.
+        - `remove` reports and removes them.
+      '';
+    };
+
+    systemdBootVersion = mkOption {
+      type = types.nullOr types.str;
+      default = null;
+      example = "254.6";
+      description = lib.mdDoc ''
+        Only install exactly this version of systemd-boot. Other versions are
+        refused and the installed systemd-boot is kept. The pinned version is
+        installed even if it is older than the installed one.
+
+        `null` installs the systemd-boot of {option}`systemd.package`.
+      '';
+    };
+
+    allowSystemdBootDowngrade = mkOption {
+      type = types.bool;
+      default = false;
+      description = lib.mdDoc ''
+        Whether to install systemd-boot even if it is older than the installed
+        one. By default, systemd-boot is only ever updated.
+      '';
+    };
+
+    deniedSystemdBootVersions = mkOption {
+      type = types.listOf types.str;
+      default = [ ];
+      example = [ "254-rc1" ];
+      description = lib.mdDoc ''
+        Versions of systemd-boot that are never installed, in addition to the
+        versions lanzaboote refuses to install because they are known to be
+        broken. The installed systemd-boot is kept instead.
+      '';
+    };
+
+    pinnedGenerations = mkOption {
+      type = types.listOf (types.either types.ints.unsigned types.str);
+      default = [ ];
+      example = [ 42 "my-server=12" ];
+      description = lib.mdDoc ''
+        Generations that are always installed regardless of the configuration
+        limit and thus never garbage collected. Generations of other profiles
+        than the default system profile are given as `profile=version`.
+
+        Pinned generations are marked in the title of their boot menu entries.
+      '';
+    };
+
+    pinFile = mkOption {
+      type = types.nullOr types.str;
+      default = null;
+      example = "/var/lib/lanzaboote/pins";
+      description = lib.mdDoc ''
+        Path to a file with one pinned generation per line in the format of
+        {option}`boot.lanzaboote.pinnedGenerations`. Unlike the option, the
+        file can be changed without rebuilding the system. Empty lines and
+        lines starting with `#` are ignored.
+      '';
+    };
+
+    strict = mkOption {
+      type = types.bool;
+      default = false;
+      description = lib.mdDoc ''
+        Whether to refuse synthesizing a bootspec for generations without a
+        readable bootspec document. Such generations are treated as malformed
+        and are not installed, but their files on the ESP are kept.
+      '';
+    };
+
+    bootspecExtension = {
+      osRelease = mkOption {
+        type = types.nullOr types.path;
+        default = null;
+        description = lib.mdDoc ''
+          os-release file that is used verbatim for the boot menu entry instead
+          of the one generated by lzbt.
+        '';
+      };
+
+      kernelParams = mkOption {
+        type = types.listOf types.str;
+        default = [ ];
+        description = lib.mdDoc ''
+          Kernel parameters that are only passed when booting via Lanzaboote.
+        '';
+      };
+
+      initrds = mkOption {
+        type = types.listOf types.path;
+        default = [ ];
+        description = lib.mdDoc ''
+          Additional initrds for this generation. They are loaded after
+          `extraInitrds` and before the initrd of the generation.
+        '';
+      };
+
+      sections = mkOption {
+        type = types.attrsOf types.path;
+        default = { };
+        example = literalExpression ''{ ".dtb" = "''${config.hardware.deviceTree.package}/board.dtb"; }'';
+        description = lib.mdDoc ''
+          Extra PE sections of the boot image. Only `.dtb` is supported.
+          `.splash` is ignored because the stub does not display splash
+          images.
+        '';
+      };
+
+      stub = mkOption {
+        type = types.enum [ "thin" "fat" ];
+        default = "thin";
+        description = lib.mdDoc ''
+          Whether the kernel and initrd are installed next to the boot image
+          (`thin`) or embedded into it (`fat`).
+        '';
+      };
+
+      exclude = mkOption {
+        type = types.bool;
+        default = false;
+        description = lib.mdDoc ''
+          Do not add this generation to the boot menu.
+        '';
+      };
+    };
+
     package = mkOption {
       type = types.package;
       default = pkgs.lzbt;
@@ -90,8 +443,46 @@ in
   };
 
   config = mkIf cfg.enable {
+    assertions = [
+      {
+        assertion = cfg.initrdSecretsCredential != null -> config.boot.initrd.systemd.enable;
+        message = "boot.lanzaboote.initrdSecretsCredential requires boot.initrd.systemd.enable.";
+      }
+    ];
+
+    boot.initrd.systemd = mkIf (cfg.initrdSecretsCredential != null) {
+      extraBin = {
+        bsdtar = "${pkgs.libarchive}/bin/bsdtar";
+        systemd-creds = "${config.boot.initrd.systemd.package}/bin/systemd-creds";
+      };
+
+      # The stub places the encrypted initrd secrets in /.extra/credentials.
+      # Decrypt and unpack them before anything that might need them runs.
+      services.lanzaboote-initrd-secrets = {
+        description = "Unpack initrd secrets from credential";
+        wantedBy = [ "initrd.target" ];
+        before = [ "cryptsetup-pre.target" "initrd-fs.target" ];
+        unitConfig = {
+          DefaultDependencies = false;
+          ConditionPathExists = "/.extra/credentials/initrd-secrets.cred";
+        };
+        serviceConfig = {
+          Type = "oneshot";
+          RemainAfterExit = true;
+        };
+        script = ''
+          systemd-creds decrypt --name=initrd-secrets \
+            /.extra/credentials/initrd-secrets.cred - | bsdtar -xpf - -C /
+        '';
+      };
+    };
+
     boot.bootspec = {
       enable = true;
+      # These settings are part of the bootspec so that they can differ between
+      # generations and specialisations.
+      extensions."org.nixos-community.lanzaboote" =
+        filterAttrs (_: value: value != null) cfg.bootspecExtension;
     };
     boot.loader.supportsInitrdSecrets = true;
     boot.loader.external = {
@@ -103,14 +494,33 @@ in
           ${sbctlWithPki}/bin/sbctl enroll-keys --yes-this-might-brick-my-machine
         ''}
 
+        # Profiles in system-profiles are optional.
+        shopt -s nullglob
+
         ${cfg.package}/bin/lzbt install \
           --systemd ${config.systemd.package} \
+          --nix ${config.nix.package} \
           --systemd-boot-loader-config ${loaderConfigFile} \
           --public-key ${cfg.publicKeyFile} \
           --private-key ${cfg.privateKeyFile} \
           --configuration-limit ${toString configurationLimit} \
+          ${profileConfigurationLimitArgs} \
+          ${efiToolArgs} \
+          ${extraInitrdArgs} \
+          ${initrdSecretsCredentialArgs} \
+          ${titleTemplateArgs} \
+          ${quarantineInstallsArgs} \
+          ${retentionArgs} \
+          ${systemdBootArgs} \
+          --fallback-policy ${cfg.fallbackPolicy} \
+          --legacy-entry-policy ${cfg.legacyEntryPolicy} \
+          ${rescueArgs} \
+          ${pinArgs} \
+          ${pinFileArgs} \
+          ${optionalString cfg.strict "--strict"} \
           ${config.boot.loader.efi.efiSysMountPoint} \
-          /nix/var/nix/profiles/system-*-link
+          /nix/var/nix/profiles/system-*-link \
+          /nix/var/nix/profiles/system-profiles/*-link
       '';
     };
 
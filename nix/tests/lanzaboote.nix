@@ -432,7 +432,8 @@ in
 
       # TODO: the other variables are not yet supported.
       expected_variables = [
-        "StubPcrKernelImage"
+        "StubPcrKernelImage",
+        "StubPcrKernelParameters"
       ]
 
       # Debug all systemd loader specification GUID EFI variables loaded by the current environment.
@@ -443,6 +444,44 @@ in
 
       # "Static" parts of the UKI is measured in PCR11
       assert_variable_uint("StubPcrKernelImage", 11)
+      # The kernel command line is measured in PCR12
+      assert_variable_uint("StubPcrKernelParameters", 12)
+    '';
+  };
+
+  # The stub measures the expected hash of the kernel (the `.kernelh`
+  # section) into PCR 11 before it verifies the kernel. Booting a kernel
+  # that does not match it without Secure Boot must not leave PCR 11 at the
+  # value of the unmodified system.
+  tpm2-hash-mismatch-changes-pcr11 = mkSecureBootTest {
+    name = "lanzaboote-tpm2-hash-mismatch-changes-pcr11";
+    useSecureBoot = false;
+    useTPM2 = true;
+    testScript = ''
+      import json
+      import os.path
+
+      def convert_to_esp(store_file_path):
+          store_dir = os.path.basename(os.path.dirname(store_file_path))
+          filename = os.path.basename(store_file_path)
+          return f'/boot/EFI/nixos/{store_dir}-{filename}.efi'
+
+      def read_pcr11():
+          machine.wait_for_unit("multi-user.target")
+          return machine.succeed("cat /sys/class/tpm/tpm0/pcr-sha256/11").strip()
+
+      machine.start()
+      expected_pcr11 = read_pcr11()
+
+      bootspec = json.loads(machine.succeed("cat /run/current-system/boot.json")).get('org.nixos.bootspec.v1')
+      assert bootspec is not None, "Unsupported bootspec version!"
+      kernel = convert_to_esp(bootspec.get('kernel'))
+      machine.succeed(f"echo Foo >> {kernel}")
+      machine.succeed("sync")
+      machine.crash()
+      machine.start()
+
+      assert read_pcr11() != expected_pcr11, "PCR 11 does not record the hash mismatch"
     '';
   };
 
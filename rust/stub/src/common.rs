@@ -1,7 +1,9 @@
 use alloc::vec::Vec;
+use log::warn;
 use uefi::{prelude::*, CStr16, CString16, Result};
 
 use crate::linux_loader::InitrdLoader;
+use crate::measure::measure_kernel_cmdline;
 use crate::pe_loader::Image;
 use crate::pe_section::pe_section_as_string;
 
@@ -26,10 +28,39 @@ pub fn boot_linux_unchecked(
     let kernel =
         Image::load(system_table.boot_services(), &kernel_data).expect("Failed to load the kernel");
 
-    let mut initrd_loader = InitrdLoader::new(system_table.boot_services(), handle, initrd_data)?;
+    let mut initrd_loader =
+        install_initrd_loader(system_table.boot_services(), handle, initrd_data)?;
 
+    measure_kernel_cmdline_or_warn(&system_table, kernel_cmdline);
     let status = unsafe { kernel.start(handle, &system_table, kernel_cmdline) };
 
-    initrd_loader.uninstall(system_table.boot_services())?;
+    if let Some(initrd_loader) = &mut initrd_loader {
+        initrd_loader.uninstall(system_table.boot_services())?;
+    }
     status.to_result()
 }
+
+/// Measure the command line that is passed to the kernel.
+///
+/// Like the measurement of the image, failures do not prevent booting.
+pub fn measure_kernel_cmdline_or_warn(system_table: &SystemTable<Boot>, kernel_cmdline: &CStr16) {
+    if let Err(err) = measure_kernel_cmdline(system_table, kernel_cmdline) {
+        warn!("Failed to measure the kernel command line: {err:?}");
+    }
+}
+
+/// Make the initrd available to the kernel.
+///
+/// Returns `None` without an initrd. The kernel then boots without
+/// one instead of failing to unpack an empty initrd.
+pub fn install_initrd_loader(
+    boot_services: &BootServices,
+    handle: Handle,
+    initrd_data: Vec<u8>,
+) -> uefi::Result<Option<InitrdLoader>> {
+    if initrd_data.is_empty() {
+        return Ok(None);
+    }
+
+    InitrdLoader::new(boot_services, handle, initrd_data).map(Some)
+}
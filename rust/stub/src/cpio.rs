@@ -0,0 +1,101 @@
+//! A minimal writer for cpio archives in the "new ASCII" (newc)
+//! format.
+//!
+//! This is the format the Linux kernel expects for initrds. Archives
+//! can be concatenated with other initrds and the kernel unpacks
+//! all of them into the same root file system.
+
+use alloc::{format, vec::Vec};
+
+/// The magic number of the newc format.
+const CPIO_MAGIC: &[u8] = b"070701";
+
+/// The name of the entry that terminates an archive.
+const CPIO_TRAILER: &str = "TRAILER!!!";
+
+/// File type bits of a directory.
+const S_IFDIR: u32 = 0o040000;
+
+/// File type bits of a regular file.
+const S_IFREG: u32 = 0o100000;
+
+/// A cpio archive that is built in memory.
+pub struct Cpio {
+    buffer: Vec<u8>,
+    next_inode: u32,
+}
+
+impl Cpio {
+    pub fn new() -> Self {
+        Self {
+            buffer: Vec::new(),
+            // Start at an arbitrary high inode number to stay clear of
+            // the inodes used by the archives we are concatenated with.
+            next_inode: 0x4c5a0000,
+        }
+    }
+
+    /// Add a directory with the given permission bits.
+    ///
+    /// Parent directories are not created implicitly and need to be
+    /// added before.
+    pub fn add_directory(&mut self, path: &str, permissions: u32) {
+        self.add_entry(path, S_IFDIR | permissions, 2, &[]);
+    }
+
+    /// Add a regular file with the given permission bits.
+    pub fn add_file(&mut self, path: &str, permissions: u32, data: &[u8]) {
+        self.add_entry(path, S_IFREG | permissions, 1, data);
+    }
+
+    /// Terminate the archive and return its bytes.
+    pub fn finish(mut self) -> Vec<u8> {
+        self.add_header(CPIO_TRAILER, 0, 0, 1, 0);
+        self.buffer
+    }
+
+    fn add_entry(&mut self, path: &str, mode: u32, nlink: u32, data: &[u8]) {
+        let inode = self.next_inode;
+        self.next_inode += 1;
+
+        // cpio file sizes are 32-bit. Everything we put into an
+        // archive is much smaller than that.
+        let size = u32::try_from(data.len()).expect("File too large for a cpio archive");
+
+        self.add_header(path, inode, mode, nlink, size);
+        self.buffer.extend_from_slice(data);
+        self.pad();
+    }
+
+    fn add_header(&mut self, path: &str, inode: u32, mode: u32, nlink: u32, size: u32) {
+        // The name size includes the terminating NUL byte.
+        let name_size = u32::try_from(path.len() + 1).expect("Path too long for a cpio archive");
+
+        let fields = [
+            inode, mode, 0, // uid
+            0, // gid
+            nlink, 0, // mtime
+            size, 0, // devmajor
+            0, // devminor
+            0, // rdevmajor
+            0, // rdevminor
+            name_size, 0, // check
+        ];
+
+        self.buffer.extend_from_slice(CPIO_MAGIC);
+        for field in fields {
+            self.buffer
+                .extend_from_slice(format!("{:08x}", field).as_bytes());
+        }
+
+        self.buffer.extend_from_slice(path.as_bytes());
+        self.buffer.push(0);
+        self.pad();
+    }
+
+    /// Pad the archive to a multiple of 4 bytes.
+    fn pad(&mut self) {
+        let padding = (4 - self.buffer.len() % 4) % 4;
+        self.buffer.resize(self.buffer.len() + padding, 0);
+    }
+}
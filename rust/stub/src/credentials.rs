@@ -0,0 +1,157 @@
+//! Pick up systemd credentials from the ESP and hand them to the
+//! initrd.
+//!
+//! This follows systemd-stub: credentials are read from the
+//! `<image>.extra.d` directory next to the booted image and from
+//! `\loader\credentials` and are packed into a cpio archive that is
+//! appended to the initrd. In the initrd, they show up in
+//! `/.extra/credentials` and `/.extra/global_credentials`
+//! respectively.
+//!
+//! Credentials are not verified by the stub. They are expected to
+//! be encrypted and authenticated (e.g. with `systemd-creds encrypt`)
+//! and are only usable once they have been decrypted in the initrd.
+//! Like systemd-stub, the stub measures each credential into TPM PCR
+//! 12, so that a TPM policy bound to it covers the credentials too.
+
+use alloc::{format, string::String, string::ToString, vec::Vec};
+use log::{info, warn};
+use uefi::{
+    cstr16,
+    fs::FileSystem,
+    prelude::BootServices,
+    proto::{
+        device_path::{media::FilePath, DeviceSubType, DeviceType},
+        loaded_image::LoadedImage,
+    },
+    CStr16, CString16, Handle,
+};
+
+use crate::cpio::Cpio;
+use crate::measure::measure_credential;
+
+/// A directory on the ESP from which credentials are picked up.
+struct CredentialDirectory {
+    /// The directory on the ESP.
+    source: String,
+    /// The directory in the initrd.
+    target: &'static str,
+    /// The description of the TPM event a credential is measured with.
+    tpm_description: &'static CStr16,
+}
+
+/// Return the path of the booted image on its file system.
+fn booted_image_path(boot_services: &BootServices, handle: Handle) -> Option<String> {
+    let loaded_image = boot_services
+        .open_protocol_exclusive::<LoadedImage>(handle)
+        .ok()?;
+
+    // The path of the image can be split across multiple file path
+    // nodes. Their concatenation is the full path.
+    let mut path = String::new();
+    for node in loaded_image.file_path()?.node_iter() {
+        if node.device_type() != DeviceType::MEDIA
+            || node.sub_type() != DeviceSubType::MEDIA_FILE_PATH
+        {
+            continue;
+        }
+
+        if let Ok(file_path) = <&FilePath>::try_from(node) {
+            let path_name = CString16::try_from(&file_path.path_name()).ok()?;
+            path.push_str(&path_name.to_string());
+        }
+    }
+
+    (!path.is_empty()).then_some(path)
+}
+
+/// Read all credentials (`*.cred` files) from a directory.
+///
+/// Returns the file names together with their contents. A missing
+/// directory is not an error and results in no credentials.
+fn read_credentials(file_system: &mut FileSystem, directory: &str) -> Vec<(String, Vec<u8>)> {
+    let Ok(directory_path) = CString16::try_from(directory) else {
+        return Vec::new();
+    };
+    let Ok(entries) = file_system.read_dir(&*directory_path) else {
+        return Vec::new();
+    };
+
+    let mut credentials = Vec::new();
+    for entry in entries.flatten() {
+        let name = entry.file_name().to_string();
+
+        if !entry.is_regular_file() || !name.to_lowercase().ends_with(".cred") {
+            continue;
+        }
+
+        let Ok(path) = CString16::try_from(format!("{directory}\\{name}").as_str()) else {
+            continue;
+        };
+
+        match file_system.read(&*path) {
+            Ok(data) => credentials.push((name, data)),
+            Err(_) => warn!("Failed to read credential {}", path),
+        }
+    }
+
+    credentials
+}
+
+/// Pick up the credentials for the booted image and pack them into a
+/// cpio archive.
+///
+/// Returns `None` if there are no credentials.
+pub fn pick_up_credentials(boot_services: &BootServices, handle: Handle) -> Option<Vec<u8>> {
+    let mut file_system = boot_services.get_image_file_system(handle).ok()?;
+
+    let mut directories = Vec::new();
+    if let Some(image_path) = booted_image_path(boot_services, handle) {
+        directories.push(CredentialDirectory {
+            source: format!("{image_path}.extra.d"),
+            target: ".extra/credentials",
+            tpm_description: cstr16!("Credentials initrd"),
+        });
+    }
+    directories.push(CredentialDirectory {
+        source: String::from("\\loader\\credentials"),
+        target: ".extra/global_credentials",
+        tpm_description: cstr16!("Global credentials initrd"),
+    });
+
+    let mut cpio = Cpio::new();
+    let mut found_credentials = false;
+
+    for directory in directories {
+        let credentials = read_credentials(&mut file_system, &directory.source);
+        if credentials.is_empty() {
+            continue;
+        }
+
+        if !found_credentials {
+            cpio.add_directory(".extra", 0o555);
+            found_credentials = true;
+        }
+
+        cpio.add_directory(directory.target, 0o500);
+        for (name, data) in credentials {
+            info!("Picking up credential `{}`...", name);
+            if let Err(err) = measure_credential(boot_services, &data, directory.tpm_description) {
+                warn!("Failed to measure credential `{}`: {:?}", name, err);
+            }
+            cpio.add_file(&format!("{}/{}", directory.target, name), 0o400, &data);
+        }
+    }
+
+    found_credentials.then(|| cpio.finish())
+}
+
+/// Append the credentials for the booted image to an initrd.
+pub fn append_credentials(boot_services: &BootServices, handle: Handle, initrd_data: &mut Vec<u8>) {
+    if let Some(credentials) = pick_up_credentials(boot_services, handle) {
+        // Each cpio archive needs to start at a 4 byte aligned offset.
+        let padding = (4 - initrd_data.len() % 4) % 4;
+        initrd_data.resize(initrd_data.len() + padding, 0);
+        initrd_data.extend_from_slice(&credentials);
+    }
+}
@@ -0,0 +1,53 @@
+//! Install the devicetree that is embedded in the `.dtb` section.
+//!
+//! Like systemd-stub, the devicetree is handed to the kernel as a
+//! UEFI configuration table. The `.dtb` section is part of the
+//! signed image, so it needs no further verification.
+
+use core::ffi::c_void;
+
+use log::{info, warn};
+use uefi::{guid, prelude::BootServices, table::boot::MemoryType, Guid};
+
+use crate::pe_section::pe_section;
+
+/// The GUID of the configuration table that contains the flattened
+/// devicetree.
+const DTB_TABLE_GUID: Guid = guid!("b1b621d5-f19c-41a5-830b-d9152c69aae0");
+
+/// Install the devicetree from the `.dtb` section of the image, if
+/// there is one.
+pub fn install_devicetree(boot_services: &BootServices, pe_data: &[u8]) {
+    let Some(devicetree) = pe_section(pe_data, ".dtb") else {
+        return;
+    };
+
+    // The configuration table has to outlive the stub. Memory of type
+    // ACPI_RECLAIM is preserved for the kernel.
+    let buffer = match boot_services.allocate_pool(MemoryType::ACPI_RECLAIM, devicetree.len()) {
+        Ok(buffer) => buffer,
+        Err(_) => {
+            warn!("Failed to allocate memory for the devicetree");
+            return;
+        }
+    };
+
+    // SAFETY: The buffer was just allocated with the size of the
+    // devicetree and does not overlap with the image.
+    unsafe {
+        buffer.copy_from_nonoverlapping(devicetree.as_ptr(), devicetree.len());
+    }
+
+    // SAFETY: The buffer contains the complete devicetree and is only
+    // freed if it could not be installed.
+    match unsafe {
+        boot_services.install_configuration_table(&DTB_TABLE_GUID, buffer as *const c_void)
+    } {
+        Ok(()) => info!("Installed devicetree from .dtb section."),
+        Err(_) => {
+            warn!("Failed to install the devicetree");
+            // The buffer is not referenced by a configuration table.
+            let _ = boot_services.free_pool(buffer);
+        }
+    }
+}
@@ -150,7 +150,10 @@ pub fn export_efi_variables(system_table: &SystemTable<Boot>) -> Result<()> {
     let boot_services = system_table.boot_services();
     let runtime_services = system_table.runtime_services();
 
-    let stub_features: EfiStubFeatures = EfiStubFeatures::ReportBootPartition;
+    let stub_features: EfiStubFeatures = EfiStubFeatures::ReportBootPartition
+        | EfiStubFeatures::PickUpCredentials
+        | EfiStubFeatures::ThreePcrs
+        | EfiStubFeatures::RandomSeed;
 
     let loaded_image =
         boot_services.open_protocol_exclusive::<LoadedImage>(boot_services.image_handle())?;
@@ -2,6 +2,7 @@ use alloc::vec::Vec;
 use uefi::{prelude::*, CString16, Result};
 
 use crate::common::{boot_linux_unchecked, extract_string};
+use crate::credentials::append_credentials;
 use crate::pe_section::pe_section;
 use crate::uefi_helpers::booted_image_file;
 
@@ -26,7 +27,8 @@ struct EmbeddedConfiguration {
     /// The kernel as raw bytes.
     kernel: Vec<u8>,
 
-    /// The initrd as raw bytes.
+    /// The initrd as raw bytes. Empty if the image does not contain an
+    /// initrd.
     initrd: Vec<u8>,
 }
 
@@ -34,7 +36,9 @@ impl EmbeddedConfiguration {
     fn new(file_data: &[u8]) -> Result<Self> {
         Ok(Self {
             kernel: extract_bytes(file_data, ".linux")?,
-            initrd: extract_bytes(file_data, ".initrd")?,
+            initrd: pe_section(file_data, ".initrd")
+                .map(<[u8]>::to_vec)
+                .unwrap_or_default(),
             cmdline: extract_string(file_data, ".cmdline")?,
         })
     }
@@ -47,7 +51,7 @@ pub fn boot_linux(handle: Handle, mut system_table: SystemTable<Boot>) -> Status
     // image and then parse the PE data structures from it. This is
     // safe, because we don't touch any data in the data sections that
     // might conceivably change while we look at the slice.
-    let config = unsafe {
+    let mut config = unsafe {
         EmbeddedConfiguration::new(
             booted_image_file(system_table.boot_services())
                 .unwrap()
@@ -56,6 +60,8 @@ pub fn boot_linux(handle: Handle, mut system_table: SystemTable<Boot>) -> Status
         .expect("Failed to extract configuration from binary.")
     };
 
+    append_credentials(system_table.boot_services(), handle, &mut config.initrd);
+
     boot_linux_unchecked(
         handle,
         system_table,
@@ -5,11 +5,15 @@
 extern crate alloc;
 
 mod common;
+mod cpio;
+mod credentials;
+mod devicetree;
 mod efivars;
 mod linux_loader;
 mod measure;
 mod pe_loader;
 mod pe_section;
+mod random_seed;
 mod tpm;
 mod uefi_helpers;
 mod unified_sections;
@@ -23,8 +27,9 @@ mod thin;
 #[cfg(all(feature = "fat", feature = "thin"))]
 compile_error!("A thin and fat stub cannot be produced at the same time, disable either `thin` or `fat` feature");
 
-use efivars::{export_efi_variables, get_loader_features, EfiLoaderFeatures};
-use log::info;
+use devicetree::install_devicetree;
+use efivars::export_efi_variables;
+use log::{info, warn};
 use measure::measure_image;
 use tpm::tpm_available;
 use uefi::prelude::*;
@@ -63,19 +68,28 @@ fn main(handle: Handle, mut system_table: SystemTable<Boot>) -> Status {
                 &system_table,
                 booted_image_file(system_table.boot_services()).unwrap(),
             );
-            // TODO: Measure kernel parameters
+            // The kernel parameters are measured right before the
+            // kernel is started.
             // TODO: Measure sysexts
         }
     }
 
-    if let Ok(features) = get_loader_features(system_table.runtime_services()) {
-        if !features.contains(EfiLoaderFeatures::RandomSeed) {
-            // FIXME: process random seed then on the disk.
-            info!("Random seed is available, but lanzaboote does not support it yet.");
-        }
+    if let Err(err) = random_seed::process_random_seed(&system_table, handle) {
+        warn!("Failed to pass a random seed to the kernel: {err:?}");
     }
     export_efi_variables(&system_table).expect("Failed to export stub EFI variables");
 
+    // SAFETY: See the comment in `boot_linux` of the thin and fat
+    // stubs. The data of the image is only read.
+    unsafe {
+        install_devicetree(
+            system_table.boot_services(),
+            booted_image_file(system_table.boot_services())
+                .unwrap()
+                .as_slice(),
+        );
+    }
+
     let status;
 
     #[cfg(feature = "fat")]
@@ -1,16 +1,32 @@
 use log::info;
 use uefi::{
     cstr16,
+    prelude::BootServices,
     proto::tcg::PcrIndex,
     table::{runtime::VariableAttributes, Boot, SystemTable},
+    CStr16,
 };
 
 use crate::{
-    efivars::BOOT_LOADER_VENDOR_UUID, pe_section::pe_section_data, tpm::tpm_log_event_ascii,
-    uefi_helpers::PeInMemory, unified_sections::UnifiedSection,
+    efivars::{cstr16_to_bytes, BOOT_LOADER_VENDOR_UUID},
+    pe_section::pe_section_data,
+    tpm::{tpm_available, tpm_log_event, tpm_log_event_ascii, tpm_log_tagged_event},
+    uefi_helpers::PeInMemory,
+    unified_sections::{UnifiedSection, THIN_SECTIONS},
 };
 
 const TPM_PCR_INDEX_KERNEL_IMAGE: PcrIndex = PcrIndex(11);
+const TPM_PCR_INDEX_KERNEL_PARAMETERS: PcrIndex = PcrIndex(12);
+
+/// The data and the description of the event that records a hash
+/// mismatch of the kernel or an initrd.
+#[cfg(feature = "thin")]
+const VERIFICATION_FAILED_EVENT: &str = "lanzaboote: hash verification failed";
+
+/// The tag of the event that measures the kernel command line.
+///
+/// This is `LOAD_OPTIONS_EVENT_TAG_ID` of systemd-stub.
+const LOAD_OPTIONS_EVENT_TAG_ID: u32 = 0x8F3B22EC;
 
 pub unsafe fn measure_image(
     system_table: &SystemTable<Boot>,
@@ -28,14 +44,14 @@ pub unsafe fn measure_image(
     let pe = goblin::pe::PE::parse(pe_binary).map_err(|_err| uefi::Status::LOAD_ERROR)?;
 
     let mut measurements = 0;
-    for section in pe.sections {
+    for section in &pe.sections {
         let section_name = section.name().map_err(|_err| uefi::Status::UNSUPPORTED)?;
         if let Ok(unified_section) = UnifiedSection::try_from(section_name) {
             // UNSTABLE: && in the previous if is an unstable feature
             // https://github.com/rust-lang/rust/issues/53667
             if unified_section.should_be_measured() {
                 // Here, perform the TPM log event in ASCII.
-                if let Some(data) = pe_section_data(pe_binary, &section) {
+                if let Some(data) = pe_section_data(pe_binary, section) {
                     info!("Measuring section `{}`...", section_name);
                     if tpm_log_event_ascii(
                         boot_services,
@@ -50,6 +66,35 @@ pub unsafe fn measure_image(
         }
     }
 
+    // The sections that reference the kernel and the initrds of thin
+    // images are measured after the unified sections in a fixed order,
+    // independent of their order in the image.
+    //
+    // These are the expected hashes. The files are only verified
+    // later. If they do not match, the thin stub extends PCR 11 with a
+    // verification failure event (see
+    // `measure_verification_failure`).
+    for section_name in THIN_SECTIONS {
+        let Some(section) = pe
+            .sections
+            .iter()
+            .find(|section| section.name().ok() == Some(section_name))
+        else {
+            continue;
+        };
+        if let Some(data) = pe_section_data(pe_binary, section) {
+            info!("Measuring section `{}`...", section_name);
+            if tpm_log_event_ascii(
+                boot_services,
+                TPM_PCR_INDEX_KERNEL_IMAGE,
+                data,
+                section_name,
+            )? {
+                measurements += 1;
+            }
+        }
+    }
+
     if measurements > 0 {
         // If we did some measurements, expose a variable encoding the PCR where
         // we have done the measurements.
@@ -63,3 +108,87 @@ pub unsafe fn measure_image(
 
     Ok(measurements)
 }
+
+/// Measure that the kernel or an initrd does not match its expected hash.
+///
+/// PCR 11 is extended with the expected hashes before the files are
+/// verified. Booting the unverified files without Secure Boot must not
+/// leave PCR 11 with the value predicted for the image. Thus, a
+/// distinct event is logged before falling back to booting them.
+#[cfg(feature = "thin")]
+pub fn measure_verification_failure(boot_services: &BootServices) -> uefi::Result<bool> {
+    if !tpm_available(boot_services) {
+        return Ok(false);
+    }
+
+    info!("Measuring hash verification failure...");
+    tpm_log_event_ascii(
+        boot_services,
+        TPM_PCR_INDEX_KERNEL_IMAGE,
+        VERIFICATION_FAILED_EVENT.as_bytes(),
+        VERIFICATION_FAILED_EVENT,
+    )
+}
+
+/// Measure a credential that is passed to the initrd.
+///
+/// Like systemd-stub, credentials are measured into the same PCR as
+/// the kernel command line because they configure the booted system
+/// as well. The event is described by the kind of the credential as a
+/// UTF-16 string.
+pub fn measure_credential(
+    boot_services: &BootServices,
+    data: &[u8],
+    description: &CStr16,
+) -> uefi::Result<bool> {
+    if !tpm_available(boot_services) {
+        return Ok(false);
+    }
+
+    tpm_log_event(
+        boot_services,
+        TPM_PCR_INDEX_KERNEL_PARAMETERS,
+        data,
+        cstr16_to_bytes(description),
+    )
+}
+
+/// Measure the command line that is passed to the kernel.
+///
+/// Like systemd-stub v254 and later, the command line is measured as a
+/// UTF-16 string including the terminating NUL. It is logged as a
+/// tagged event with the load options tag and the command line as its
+/// description. This is the command line the kernel actually
+/// receives, not just the embedded `.cmdline` section that is part of
+/// the image measurement.
+pub fn measure_kernel_cmdline(
+    system_table: &SystemTable<Boot>,
+    kernel_cmdline: &CStr16,
+) -> uefi::Result<bool> {
+    let boot_services = system_table.boot_services();
+    if !tpm_available(boot_services) {
+        return Ok(false);
+    }
+
+    let cmdline_bytes = cstr16_to_bytes(kernel_cmdline);
+    info!("Measuring kernel command line...");
+    if !tpm_log_tagged_event(
+        boot_services,
+        TPM_PCR_INDEX_KERNEL_PARAMETERS,
+        cmdline_bytes,
+        LOAD_OPTIONS_EVENT_TAG_ID,
+        cmdline_bytes,
+    )? {
+        return Ok(false);
+    }
+
+    // Expose the PCR where the kernel command line has been measured.
+    system_table.runtime_services().set_variable(
+        cstr16!("StubPcrKernelParameters"),
+        &BOOT_LOADER_VENDOR_UUID,
+        VariableAttributes::BOOTSERVICE_ACCESS | VariableAttributes::RUNTIME_ACCESS,
+        &TPM_PCR_INDEX_KERNEL_PARAMETERS.0.to_le_bytes(),
+    )?;
+
+    Ok(true)
+}
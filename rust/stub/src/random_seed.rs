@@ -0,0 +1,268 @@
+//! Pass a random seed to the kernel.
+//!
+//! This follows systemd-stub: the kernel picks up a random seed from
+//! the `LINUX_EFI_RANDOM_SEED` configuration table early during boot.
+//! systemd-boot fills this table from the random seed on the ESP. The
+//! stub hashes the seed in the table together with its own entropy
+//! and replaces the table, so that the kernel never receives the seed
+//! systemd-boot handed out before.
+//!
+//! When the stub is not booted by a systemd-boot that supports random
+//! seeds, it derives the seed from `\loader\random-seed` itself. Like
+//! systemd-boot, it only does so if the system token is set and
+//! refreshes the seed file before it uses it, so that the same seed
+//! is never used twice.
+
+use alloc::vec::Vec;
+use log::{info, warn};
+use sha2::{Digest, Sha256};
+use uefi::{
+    cstr16, guid,
+    prelude::{BootServices, RuntimeServices},
+    proto::rng::Rng,
+    table::{boot::MemoryType, Boot, SystemTable},
+    CStr16, Guid, Handle,
+};
+
+use crate::efivars::{get_loader_features, EfiLoaderFeatures, BOOT_LOADER_VENDOR_UUID};
+
+/// The GUID of the configuration table the kernel reads the random seed from.
+const LINUX_EFI_RANDOM_SEED_TABLE_GUID: Guid = guid!("1ce1e5bc-7ceb-42f2-81e5-8aadf180f57b");
+
+/// The size of the random seed passed to the kernel in bytes.
+const RANDOM_SEED_SIZE: usize = 32;
+
+/// The maximum size of a random seed the stub accepts in bytes.
+///
+/// This is the limit systemd-boot applies to the seed file on the ESP.
+const RANDOM_SEED_SIZE_MAX: usize = 32 * 1024;
+
+/// The random seed file on the ESP.
+const RANDOM_SEED_PATH: &CStr16 = cstr16!("\\loader\\random-seed");
+
+/// The header of the `LINUX_EFI_RANDOM_SEED` configuration table.
+///
+/// The seed directly follows the header.
+#[repr(C)]
+struct LinuxEfiRandomSeed {
+    size: u32,
+}
+
+/// Pass a random seed to the kernel.
+///
+/// Returns whether a seed was passed. Without any source of entropy,
+/// no seed is passed.
+pub fn process_random_seed(system_table: &SystemTable<Boot>, handle: Handle) -> uefi::Result<bool> {
+    let boot_services = system_table.boot_services();
+    let runtime_services = system_table.runtime_services();
+
+    let mut hasher = Sha256::new();
+    hasher.update(b"lanzaboote stub random seed label v1\0");
+    let mut has_entropy = false;
+
+    let previous_table = previous_seed_table(system_table);
+    if let Some(previous_seed) = previous_table {
+        hasher.update((previous_seed.len() as u64).to_le_bytes());
+        hasher.update(previous_seed);
+        has_entropy = true;
+    } else if !loader_passes_random_seed(runtime_services) {
+        if let Some(esp_seed) = take_esp_random_seed(boot_services, runtime_services, handle) {
+            hasher.update(esp_seed);
+            has_entropy = true;
+        }
+    }
+
+    if let Some(firmware_seed) = firmware_random_bytes(boot_services) {
+        hasher.update(firmware_seed);
+        has_entropy = true;
+    }
+
+    if !has_entropy {
+        info!("No random seed available to pass to the kernel.");
+        return Ok(false);
+    }
+
+    // The time alone is no entropy, but it keeps the seed from being
+    // reused if the firmware hands out the same table again.
+    if let Ok(time) = runtime_services.get_time() {
+        hasher.update(time.year().to_le_bytes());
+        hasher.update([
+            time.month(),
+            time.day(),
+            time.hour(),
+            time.minute(),
+            time.second(),
+        ]);
+        hasher.update(time.nanosecond().to_le_bytes());
+    }
+    let seed = hasher.finalize();
+
+    install_seed_table(boot_services, &seed)?;
+
+    if let Some(previous_seed) = previous_table {
+        // SAFETY: The previous table is not referenced by the
+        // configuration table anymore. It was allocated from pool
+        // memory by the boot loader, which handed it over to us.
+        unsafe {
+            let header = previous_seed
+                .as_ptr()
+                .sub(core::mem::size_of::<LinuxEfiRandomSeed>())
+                .cast_mut();
+            wipe(core::slice::from_raw_parts_mut(
+                header,
+                core::mem::size_of::<LinuxEfiRandomSeed>() + previous_seed.len(),
+            ));
+            boot_services.free_pool(header).ok();
+        }
+    }
+
+    Ok(true)
+}
+
+/// Return the seed in the `LINUX_EFI_RANDOM_SEED` configuration table
+/// if a boot loader installed one.
+fn previous_seed_table(system_table: &SystemTable<Boot>) -> Option<&'static [u8]> {
+    let entry = system_table
+        .config_table()
+        .iter()
+        .find(|entry| entry.guid == LINUX_EFI_RANDOM_SEED_TABLE_GUID)?;
+    if entry.address.is_null() {
+        return None;
+    }
+
+    // SAFETY: The configuration table is a `LinuxEfiRandomSeed`
+    // header followed by `size` bytes of seed. The size is bounded
+    // to rule out garbage.
+    unsafe {
+        let header = entry.address as *const LinuxEfiRandomSeed;
+        let size = usize::try_from((*header).size).ok()?;
+        if size == 0 || size > RANDOM_SEED_SIZE_MAX {
+            return None;
+        }
+        Some(core::slice::from_raw_parts(
+            header.add(1).cast::<u8>(),
+            size,
+        ))
+    }
+}
+
+/// Return whether the boot loader already passes the random seed on
+/// the ESP to the kernel.
+///
+/// In this case, the seed file must not be consumed again.
+fn loader_passes_random_seed(runtime_services: &RuntimeServices) -> bool {
+    get_loader_features(runtime_services)
+        .map(|features| features.contains(EfiLoaderFeatures::RandomSeed))
+        .unwrap_or(false)
+}
+
+/// Derive a seed from the random seed on the ESP and refresh the file.
+///
+/// The seed is only used if the system token is set and the refreshed
+/// seed could be written back. Otherwise, the next boot would pass the
+/// same seed again.
+fn take_esp_random_seed(
+    boot_services: &BootServices,
+    runtime_services: &RuntimeServices,
+    handle: Handle,
+) -> Option<[u8; RANDOM_SEED_SIZE]> {
+    let token = read_system_token(runtime_services)?;
+
+    let mut file_system = boot_services.get_image_file_system(handle).ok()?;
+    let mut file_seed = file_system.read(RANDOM_SEED_PATH).ok()?;
+    if file_seed.len() < RANDOM_SEED_SIZE || file_seed.len() > RANDOM_SEED_SIZE_MAX {
+        warn!(
+            "Ignoring random seed on the ESP with invalid size {}.",
+            file_seed.len()
+        );
+        return None;
+    }
+
+    let derive = |label: &[u8]| -> [u8; RANDOM_SEED_SIZE] {
+        Sha256::new()
+            .chain_update(label)
+            .chain_update((file_seed.len() as u64).to_le_bytes())
+            .chain_update(&file_seed)
+            .chain_update(&token)
+            .finalize()
+            .into()
+    };
+    let mut next_file_seed = derive(b"lanzaboote stub random seed file label v1\0");
+    let kernel_seed = derive(b"lanzaboote stub random seed kernel label v1\0");
+    wipe(&mut file_seed);
+
+    let written = file_system.write(RANDOM_SEED_PATH, next_file_seed);
+    wipe(&mut next_file_seed);
+    if let Err(err) = written {
+        warn!("Failed to refresh the random seed on the ESP, not using it: {err:?}");
+        return None;
+    }
+
+    Some(kernel_seed)
+}
+
+/// Read the `LoaderSystemToken` EFI variable.
+///
+/// The token distinguishes installations that were cloned from the
+/// same image and thus share a seed file.
+fn read_system_token(runtime_services: &RuntimeServices) -> Option<Vec<u8>> {
+    let name = cstr16!("LoaderSystemToken");
+    let size = runtime_services
+        .get_variable_size(name, &BOOT_LOADER_VENDOR_UUID)
+        .ok()?;
+    let mut token = alloc::vec![0; size];
+    runtime_services
+        .get_variable(name, &BOOT_LOADER_VENDOR_UUID, &mut token)
+        .ok()?;
+    (!token.is_empty()).then_some(token)
+}
+
+/// Read random bytes from the firmware if it provides an RNG.
+fn firmware_random_bytes(boot_services: &BootServices) -> Option<[u8; RANDOM_SEED_SIZE]> {
+    let rng_handle = boot_services.get_handle_for_protocol::<Rng>().ok()?;
+    let mut rng = boot_services
+        .open_protocol_exclusive::<Rng>(rng_handle)
+        .ok()?;
+    let mut bytes = [0; RANDOM_SEED_SIZE];
+    rng.get_rng(None, &mut bytes).ok()?;
+    Some(bytes)
+}
+
+/// Install a seed as the `LINUX_EFI_RANDOM_SEED` configuration table.
+fn install_seed_table(boot_services: &BootServices, seed: &[u8]) -> uefi::Result {
+    let header_size = core::mem::size_of::<LinuxEfiRandomSeed>();
+    // Memory of this type is kept by the kernel after boot services are exited.
+    let table = boot_services.allocate_pool(MemoryType::ACPI_RECLAIM, header_size + seed.len())?;
+
+    // SAFETY: The allocation is large enough for the header and the
+    // seed. Pool allocations are 8-byte aligned.
+    unsafe {
+        table
+            .cast::<LinuxEfiRandomSeed>()
+            .write(LinuxEfiRandomSeed {
+                size: seed.len() as u32,
+            });
+        core::ptr::copy_nonoverlapping(seed.as_ptr(), table.add(header_size), seed.len());
+
+        boot_services
+            .install_configuration_table(&LINUX_EFI_RANDOM_SEED_TABLE_GUID, table.cast())
+            .map_err(|err| {
+                wipe(core::slice::from_raw_parts_mut(
+                    table,
+                    header_size + seed.len(),
+                ));
+                boot_services.free_pool(table).ok();
+                err
+            })
+    }
+}
+
+/// Overwrite secret data with zeroes.
+///
+/// Volatile writes keep the compiler from eliding the wipe.
+fn wipe(data: &mut [u8]) {
+    for byte in data {
+        // SAFETY: The pointer is derived from a mutable reference.
+        unsafe { core::ptr::write_volatile(byte, 0) };
+    }
+}
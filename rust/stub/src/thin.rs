@@ -3,9 +3,13 @@ use log::warn;
 use sha2::{Digest, Sha256};
 use uefi::{prelude::*, proto::loaded_image::LoadedImage, CStr16, CString16, Result};
 
-use crate::common::{boot_linux_unchecked, extract_string};
-use crate::pe_section::pe_section;
-use crate::{linux_loader::InitrdLoader, uefi_helpers::booted_image_file};
+use crate::common::{
+    boot_linux_unchecked, extract_string, install_initrd_loader, measure_kernel_cmdline_or_warn,
+};
+use crate::credentials::append_credentials;
+use crate::measure::measure_verification_failure;
+use crate::pe_section::{pe_section, pe_section_as_string};
+use crate::uefi_helpers::booted_image_file;
 
 type Hash = sha2::digest::Output<Sha256>;
 
@@ -22,13 +26,17 @@ struct EmbeddedConfiguration {
     /// The cryptographic hash of the kernel.
     kernel_hash: Hash,
 
-    /// The filename of the initrd to be passed to the kernel. See
+    /// The filenames of the initrds to be passed to the kernel. See
     /// `kernel_filename` for how to interpret these filenames.
-    initrd_filename: CString16,
+    ///
+    /// The initrds are concatenated in this order before they are
+    /// handed to the kernel.
+    initrd_filenames: Vec<CString16>,
 
-    /// The cryptographic hash of the initrd. This hash is computed
-    /// over the whole PE binary, not only the embedded initrd.
-    initrd_hash: Hash,
+    /// The cryptographic hashes of the initrds in the same order as
+    /// `initrd_filenames`. Each hash is computed over the whole PE
+    /// binary, not only the embedded initrd.
+    initrd_hashes: Vec<Hash>,
 
     /// The kernel command-line.
     cmdline: CString16,
@@ -44,14 +52,70 @@ fn extract_hash(pe_data: &[u8], section: &str) -> Result<Hash> {
     Ok(array.into())
 }
 
+/// Extract a list of SHA256 hashes from a PE section.
+///
+/// The section contains the concatenation of the hashes.
+fn extract_hashes(pe_data: &[u8], section: &str) -> Result<Vec<Hash>> {
+    let data = pe_section(pe_data, section).ok_or(Status::INVALID_PARAMETER)?;
+    let hashes = data.chunks_exact(Sha256::output_size());
+
+    if !hashes.remainder().is_empty() {
+        return Err(Status::INVALID_PARAMETER.into());
+    }
+
+    Ok(hashes.map(Hash::clone_from_slice).collect())
+}
+
+/// Extract a list of strings, stored as UTF-8 and separated by newlines, from a PE section.
+fn extract_strings(pe_data: &[u8], section: &str) -> Result<Vec<CString16>> {
+    let string = pe_section_as_string(pe_data, section).ok_or(Status::INVALID_PARAMETER)?;
+
+    string
+        .lines()
+        .map(|line| CString16::try_from(line).map_err(|_| Status::INVALID_PARAMETER.into()))
+        .collect()
+}
+
+/// Concatenate initrds.
+///
+/// Each initrd is padded to a multiple of 4 bytes, because the
+/// kernel expects each cpio archive to start at a 4 byte aligned
+/// offset. The kernel skips the zero padding between archives.
+fn concatenate_initrds(initrds: Vec<Vec<u8>>) -> Vec<u8> {
+    let mut initrd_data = Vec::new();
+
+    for initrd in initrds {
+        initrd_data.extend_from_slice(&initrd);
+        let padding = (4 - initrd_data.len() % 4) % 4;
+        initrd_data.resize(initrd_data.len() + padding, 0);
+    }
+
+    initrd_data
+}
+
 impl EmbeddedConfiguration {
     fn new(file_data: &[u8]) -> Result<Self> {
+        // Generations without an initrd do not have the initrd
+        // sections at all.
+        let (initrd_filenames, initrd_hashes) = if pe_section(file_data, ".initrdp").is_some() {
+            (
+                extract_strings(file_data, ".initrdp")?,
+                extract_hashes(file_data, ".initrdh")?,
+            )
+        } else {
+            (Vec::new(), Vec::new())
+        };
+
+        if initrd_filenames.len() != initrd_hashes.len() {
+            return Err(Status::INVALID_PARAMETER.into());
+        }
+
         Ok(Self {
             kernel_filename: extract_string(file_data, ".kernelp")?,
             kernel_hash: extract_hash(file_data, ".kernelh")?,
 
-            initrd_filename: extract_string(file_data, ".initrdp")?,
-            initrd_hash: extract_hash(file_data, ".initrdh")?,
+            initrd_filenames,
+            initrd_hashes,
 
             cmdline: extract_string(file_data, ".cmdline")?,
         })
@@ -93,14 +157,18 @@ fn boot_linux_uefi(
         );
     }
 
-    let mut initrd_loader = InitrdLoader::new(system_table.boot_services(), handle, initrd_data)?;
+    let mut initrd_loader =
+        install_initrd_loader(system_table.boot_services(), handle, initrd_data)?;
 
+    measure_kernel_cmdline_or_warn(&system_table, kernel_cmdline);
     let status = system_table
         .boot_services()
         .start_image(kernel_handle)
         .status();
 
-    initrd_loader.uninstall(system_table.boot_services())?;
+    if let Some(initrd_loader) = &mut initrd_loader {
+        initrd_loader.uninstall(system_table.boot_services())?;
+    }
     status.to_result()
 }
 
@@ -121,7 +189,7 @@ pub fn boot_linux(handle: Handle, mut system_table: SystemTable<Boot>) -> Status
     };
 
     let kernel_data;
-    let initrd_data;
+    let initrds;
 
     {
         let mut file_system = system_table
@@ -132,22 +200,38 @@ pub fn boot_linux(handle: Handle, mut system_table: SystemTable<Boot>) -> Status
         kernel_data = file_system
             .read(&*config.kernel_filename)
             .expect("Failed to read kernel file into memory");
-        initrd_data = file_system
-            .read(&*config.initrd_filename)
-            .expect("Failed to read initrd file into memory");
+        initrds = config
+            .initrd_filenames
+            .iter()
+            .map(|initrd_filename| {
+                file_system
+                    .read(&**initrd_filename)
+                    .expect("Failed to read initrd file into memory")
+            })
+            .collect::<Vec<Vec<u8>>>();
     }
 
     let is_kernel_hash_correct = Sha256::digest(&kernel_data) == config.kernel_hash;
-    let is_initrd_hash_correct = Sha256::digest(&initrd_data) == config.initrd_hash;
+    let mut is_initrd_hash_correct = true;
 
     if !is_kernel_hash_correct {
         warn!("Hash mismatch for kernel!");
     }
 
-    if !is_initrd_hash_correct {
-        warn!("Hash mismatch for initrd!");
+    for ((initrd_data, initrd_hash), initrd_filename) in initrds
+        .iter()
+        .zip(&config.initrd_hashes)
+        .zip(&config.initrd_filenames)
+    {
+        if Sha256::digest(initrd_data) != *initrd_hash {
+            warn!("Hash mismatch for initrd {}!", initrd_filename);
+            is_initrd_hash_correct = false;
+        }
     }
 
+    let mut initrd_data = concatenate_initrds(initrds);
+    append_credentials(system_table.boot_services(), handle, &mut initrd_data);
+
     if is_kernel_hash_correct && is_initrd_hash_correct {
         boot_linux_unchecked(
             handle,
@@ -181,6 +265,11 @@ pub fn boot_linux(handle: Handle, mut system_table: SystemTable<Boot>) -> Status
 
         warn!("Trying to continue as non-Secure Boot. This will fail when Secure Boot is enabled.");
 
+        // Like the measurement of the image, failures do not prevent booting.
+        if let Err(err) = measure_verification_failure(system_table.boot_services()) {
+            warn!("Failed to measure the hash verification failure: {err:?}");
+        }
+
         boot_linux_uefi(
             handle,
             system_table,
@@ -1,4 +1,4 @@
-use alloc::vec;
+use alloc::{vec, vec::Vec};
 use core::mem::{self, MaybeUninit};
 use log::warn;
 use uefi::{
@@ -59,6 +59,63 @@ pub fn tpm_log_event_ascii(
     pcr_index: PcrIndex,
     buffer: &[u8],
     description: &str,
+) -> uefi::Result<bool> {
+    tpm_log_event(boot_services, pcr_index, buffer, description.as_bytes())
+}
+
+/// Log an event in the TPM with `buffer` as data and an arbitrarily encoded description.
+/// Returns a boolean whether the measurement has been done or not in case of success.
+pub fn tpm_log_event(
+    boot_services: &BootServices,
+    pcr_index: PcrIndex,
+    buffer: &[u8],
+    description: &[u8],
+) -> uefi::Result<bool> {
+    log_event(
+        boot_services,
+        pcr_index,
+        buffer,
+        EventType::IPL,
+        description,
+    )
+}
+
+/// Log a tagged event (`EV_EVENT_TAG`) in the TPM with `buffer` as data.
+///
+/// The event data is a `TCG_PCClientTaggedEvent`: the tag, the size of
+/// the description and the description itself. This is how
+/// systemd-stub v254 and later logs events that tools identify by
+/// their tag rather than by their description.
+/// Returns a boolean whether the measurement has been done or not in case of success.
+pub fn tpm_log_tagged_event(
+    boot_services: &BootServices,
+    pcr_index: PcrIndex,
+    buffer: &[u8],
+    tag: u32,
+    description: &[u8],
+) -> uefi::Result<bool> {
+    let description_size =
+        u32::try_from(description.len()).map_err(|_| uefi::Status::INVALID_PARAMETER)?;
+    let mut tagged_event = Vec::with_capacity(2 * mem::size_of::<u32>() + description.len());
+    tagged_event.extend_from_slice(&tag.to_le_bytes());
+    tagged_event.extend_from_slice(&description_size.to_le_bytes());
+    tagged_event.extend_from_slice(description);
+
+    log_event(
+        boot_services,
+        pcr_index,
+        buffer,
+        EventType::EVENT_TAG,
+        &tagged_event,
+    )
+}
+
+fn log_event(
+    boot_services: &BootServices,
+    pcr_index: PcrIndex,
+    buffer: &[u8],
+    event_type: EventType,
+    description: &[u8],
 ) -> uefi::Result<bool> {
     if pcr_index.0 == u32::MAX {
         return Ok(false);
@@ -73,8 +130,8 @@ pub fn tpm_log_event_ascii(
         let event = v2::PcrEventInputs::new_in_buffer(
             event_buffer.as_mut_slice(),
             pcr_index,
-            EventType::IPL,
-            description.as_bytes(),
+            event_type,
+            description,
         )?;
         // FIXME: what do we want as flags here?
         tpm2.hash_log_extend_event(Default::default(), buffer, event)?;
@@ -89,14 +146,14 @@ pub fn tpm_log_event_ascii(
 
         // Compute sha1 of the event data
         let mut m = sha1_smol::Sha1::new();
-        m.update(description.as_bytes());
+        m.update(description);
 
         let event = v1::PcrEvent::new_in_buffer(
             event_buffer.as_mut_slice(),
             pcr_index,
-            EventType::IPL,
+            event_type,
             m.digest().bytes(),
-            description.as_bytes(),
+            description,
         )?;
 
         tpm1.hash_log_extend_event(event, Some(buffer))?;
@@ -32,6 +32,14 @@ impl TryFrom<&str> for UnifiedSection {
     }
 }
 
+/// List of PE sections of thin images that reference the kernel and
+/// the initrds on the ESP. They are not part of the UKI specification.
+/// They are measured into TPM PCR 11 after the unified sections in
+/// this order, so that PCR 11 reflects the kernel and initrds that
+/// the stub verifies and boots.
+/// !!! DO NOT REORDER !!!
+pub const THIN_SECTIONS: [&str; 4] = [".kernelp", ".kernelh", ".initrdp", ".initrdh"];
+
 impl UnifiedSection {
     /// Whether this section should be measured into TPM.
     pub fn should_be_measured(&self) -> bool {
@@ -1,10 +1,14 @@
+use std::num::NonZeroUsize;
 use std::path::PathBuf;
 
 use anyhow::{Context, Result};
 use clap::{Parser, Subcommand};
 
-use crate::install;
-use crate::signature::KeyPair;
+use lanzaboote_tool::{
+    CredentialKey, EfiTool, FallbackPolicy, Installer, KeyPair, LegacyEntryPolicy, Pin,
+    RetentionPolicy, SystemdBootPolicy, SystemdVersion, TitleTemplate, Uninstaller,
+    DEFAULT_EFIVARFS,
+};
 
 /// The default log level.
 ///
@@ -25,7 +29,8 @@ pub struct Cli {
 
 #[derive(Subcommand)]
 enum Commands {
-    Install(InstallCommand),
+    Install(Box<InstallCommand>),
+    Uninstall(UninstallCommand),
 }
 
 #[derive(Parser)]
@@ -34,6 +39,13 @@ struct InstallCommand {
     #[arg(long)]
     systemd: PathBuf,
 
+    /// Nix path
+    ///
+    /// Used to read the build times of the generations from the Nix store. Without it, the build
+    /// times are approximated from the modification times of the generation links.
+    #[arg(long)]
+    nix: Option<PathBuf>,
+
     /// Systemd-boot loader config
     #[arg(long)]
     systemd_boot_loader_config: PathBuf,
@@ -50,6 +62,131 @@ struct InstallCommand {
     #[arg(long, default_value_t = 1)]
     configuration_limit: usize,
 
+    /// Keep generations built within the last N days even if they exceed the configuration limit
+    #[arg(long, value_name = "N")]
+    keep_days: Option<u64>,
+
+    /// Install at most M images, counting each specialisation as an image
+    ///
+    /// The oldest generations are dropped first. Pinned generations, the booted generation and the
+    /// latest generation of each profile are always installed.
+    #[arg(long, value_name = "M")]
+    max_images: Option<NonZeroUsize>,
+
+    /// Only install the specialisations of the newest K generations of each profile
+    #[arg(long, value_name = "K")]
+    specialisations_limit: Option<usize>,
+
+    /// Configuration limit for a specific profile (e.g. my-server=5)
+    ///
+    /// Profiles without a specific limit use the configuration limit.
+    #[arg(
+        long = "profile-configuration-limit",
+        value_name = "PROFILE=LIMIT",
+        value_parser = parse_profile_configuration_limit
+    )]
+    profile_configuration_limits: Vec<(String, usize)>,
+
+    /// Extra EFI binary to sign and add to the boot menu (e.g. memtest86=/path/to/memtest.efi)
+    #[arg(long = "efi-tool", value_name = "NAME=PATH")]
+    efi_tools: Vec<EfiTool>,
+
+    /// Extra initrd that is loaded before the initrd of each generation (e.g. CPU microcode)
+    ///
+    /// Can be specified multiple times. The initrds are passed to the kernel in the order in which
+    /// they are specified.
+    #[arg(long = "extra-initrd", value_name = "PATH")]
+    extra_initrds: Vec<PathBuf>,
+
+    /// Ship initrd secrets as a credential encrypted with KEY instead of appending them to the
+    /// initrd
+    ///
+    /// The stub picks up the credential at boot and passes it to the initrd. It has to be
+    /// decrypted there with systemd-creds.
+    #[arg(long, value_name = "KEY")]
+    initrd_secrets_credential: Option<CredentialKey>,
+
+    /// Template for the title of the boot menu entries
+    ///
+    /// Supported placeholders: {label}, {name}, {nixos_version}, {kernel_version}, {generation},
+    /// {build_time}, {profile}, {specialisation} and {pinned}.
+    #[arg(long)]
+    title_template: Option<TitleTemplate>,
+
+    /// Move unknown files in esp/EFI/nixos to esp/EFI/nixos/.quarantine instead of deleting them
+    ///
+    /// Quarantined files are deleted after N successful installs. Files lzbt installed itself are
+    /// still deleted right away.
+    #[arg(long, value_name = "N")]
+    quarantine_installs: Option<NonZeroUsize>,
+
+    /// Do not synthesize a bootspec for generations without a readable bootspec
+    ///
+    /// Such generations are treated as malformed and are not installed.
+    #[arg(long)]
+    strict: bool,
+
+    /// Generation that is always installed regardless of the configuration limit (e.g. 42 or
+    /// my-server=12)
+    ///
+    /// Can be specified multiple times.
+    #[arg(long = "pin", value_name = "[PROFILE=]VERSION")]
+    pins: Vec<Pin>,
+
+    /// File with one pinned generation per line in the format of --pin
+    ///
+    /// Empty lines and lines starting with # are ignored. A missing file does not pin any
+    /// generations.
+    #[arg(long)]
+    pin_file: Option<PathBuf>,
+
+    /// Only install exactly this version of systemd-boot (e.g. 254.6)
+    ///
+    /// Other versions are refused. The pinned version is installed even if it is older than the
+    /// installed one.
+    #[arg(long, value_name = "VERSION")]
+    systemd_boot_version: Option<SystemdVersion>,
+
+    /// Install systemd-boot even if it is older than the installed one
+    #[arg(long)]
+    allow_systemd_boot_downgrade: bool,
+
+    /// Never install this version of systemd-boot (e.g. 254-rc1)
+    ///
+    /// Can be specified multiple times. This is in addition to the versions lzbt refuses to
+    /// install because they are known to be broken.
+    #[arg(long = "deny-systemd-boot-version", value_name = "VERSION")]
+    denied_systemd_boot_versions: Vec<SystemdVersion>,
+
+    /// What to do with a fallback boot loader (esp/EFI/BOOT/BOOTX64.EFI) that is not systemd-boot
+    ///
+    /// The default is to back it up. Earlier versions of lzbt always replaced it without a backup,
+    /// which is `take-over` now.
+    #[arg(long, value_enum, default_value_t)]
+    fallback_policy: FallbackPolicy,
+
+    /// What to do with Type #1 loader entries (esp/loader/entries) that boot NixOS without
+    /// lanzaboote, e.g. the entries left over from plain systemd-boot
+    #[arg(long, value_enum, default_value_t)]
+    legacy_entry_policy: LegacyEntryPolicy,
+
+    /// Build a rescue image from this generation link or toplevel
+    ///
+    /// The rescue image is installed as esp/EFI/Linux/nixos-rescue.efi. It is never pruned and
+    /// only replaced by a newer generation.
+    #[arg(long, value_name = "GENERATION")]
+    rescue: Option<PathBuf>,
+
+    /// Replace the installed rescue image even if the rescue generation is not newer
+    #[arg(long)]
+    replace_rescue: bool,
+
+    /// Mountpoint of efivarfs
+    ///
+    /// The generation the system is booted from is read from here and always kept installed.
+    #[arg(long, default_value = DEFAULT_EFIVARFS)]
+    efivarfs: PathBuf,
+
     /// EFI system partition mountpoint (e.g. efiSysMountPoint)
     esp: PathBuf,
 
@@ -57,10 +194,33 @@ struct InstallCommand {
     generations: Vec<PathBuf>,
 }
 
+/// Remove everything lzbt installed from the ESP
+#[derive(Parser)]
+struct UninstallCommand {
+    /// Only print what would be removed or restored
+    #[arg(long)]
+    dry_run: bool,
+
+    /// Keep the backup of the fallback boot loader that lzbt took over instead of restoring it
+    ///
+    /// By default, the backup is restored to esp/EFI/BOOT/BOOTX64.EFI.
+    #[arg(long)]
+    no_restore_fallback: bool,
+
+    /// Replace the signed systemd-boot with the unsigned one from this systemd path
+    ///
+    /// Without it, systemd-boot and its loader config are removed.
+    #[arg(long, value_name = "SYSTEMD")]
+    restore_systemd_boot: Option<PathBuf>,
+
+    /// EFI system partition mountpoint (e.g. efiSysMountPoint)
+    esp: PathBuf,
+}
+
 impl Cli {
-    pub fn call(self, module: &str) {
+    pub fn call(self, modules: &[&str]) {
         stderrlog::new()
-            .module(module)
+            .modules(modules.iter().copied())
             .show_level(false)
             .quiet(self.quiet)
             .verbosity(DEFAULT_LOG_LEVEL + usize::from(self.verbose))
@@ -77,7 +237,8 @@ impl Cli {
 impl Commands {
     pub fn call(self) -> Result<()> {
         match self {
-            Commands::Install(args) => install(args),
+            Commands::Install(args) => install(*args),
+            Commands::Uninstall(args) => uninstall(args),
         }
     }
 }
@@ -88,14 +249,82 @@ fn install(args: InstallCommand) -> Result<()> {
 
     let key_pair = KeyPair::new(&args.public_key, &args.private_key);
 
-    install::Installer::new(
-        PathBuf::from(lanzaboote_stub),
-        args.systemd,
-        args.systemd_boot_loader_config,
-        key_pair,
-        args.configuration_limit,
-        args.esp,
-        args.generations,
-    )
-    .install()
+    let mut builder = Installer::builder()
+        .lanzaboote_stub(lanzaboote_stub)
+        .systemd(args.systemd)
+        .systemd_boot_loader_config(args.systemd_boot_loader_config)
+        .key_pair(key_pair)
+        .configuration_limit(args.configuration_limit)
+        .esp(args.esp)
+        .generation_links(args.generations)
+        .efi_tools(args.efi_tools)
+        .extra_initrds(args.extra_initrds)
+        .strict(args.strict)
+        .pins(args.pins)
+        .efivarfs(args.efivarfs)
+        .retention(RetentionPolicy {
+            keep_days: args.keep_days,
+            max_images: args.max_images,
+            specialisations_limit: args.specialisations_limit,
+        })
+        .fallback_policy(args.fallback_policy)
+        .legacy_entry_policy(args.legacy_entry_policy)
+        .replace_rescue(args.replace_rescue)
+        .systemd_boot_policy(SystemdBootPolicy {
+            pinned_version: args.systemd_boot_version,
+            allow_downgrade: args.allow_systemd_boot_downgrade,
+            denied_versions: args.denied_systemd_boot_versions,
+        });
+
+    // The fat stub is only needed for generations that request it.
+    if let Some(lanzaboote_fat_stub) = std::env::var_os("LANZABOOTE_FAT_STUB") {
+        builder = builder.lanzaboote_fat_stub(lanzaboote_fat_stub);
+    }
+    if let Some(nix) = args.nix {
+        builder = builder.nix(nix);
+    }
+    for (profile, limit) in args.profile_configuration_limits {
+        builder = builder.profile_configuration_limit(profile, limit);
+    }
+    if let Some(key) = args.initrd_secrets_credential {
+        builder = builder.initrd_secrets_credential(key);
+    }
+    if let Some(title_template) = args.title_template {
+        builder = builder.title_template(title_template);
+    }
+    if let Some(installs) = args.quarantine_installs {
+        builder = builder.quarantine_installs(installs);
+    }
+    if let Some(pin_file) = args.pin_file {
+        builder = builder.pin_file(pin_file);
+    }
+    if let Some(rescue) = args.rescue {
+        builder = builder.rescue(rescue);
+    }
+
+    builder.build()?.install()?;
+    Ok(())
+}
+
+fn uninstall(args: UninstallCommand) -> Result<()> {
+    let mut uninstaller = Uninstaller::new(args.esp)
+        .restore_fallback(!args.no_restore_fallback)
+        .dry_run(args.dry_run);
+    if let Some(systemd) = args.restore_systemd_boot {
+        uninstaller = uninstaller.restore_systemd_boot(systemd);
+    }
+
+    uninstaller.uninstall()?;
+    Ok(())
+}
+
+/// Parse a configuration limit for a profile from the format `PROFILE=LIMIT`.
+fn parse_profile_configuration_limit(s: &str) -> Result<(String, usize)> {
+    let (profile, limit) = s
+        .split_once('=')
+        .with_context(|| format!("Expected PROFILE=LIMIT, got: {s}"))?;
+    let limit = limit
+        .parse::<usize>()
+        .with_context(|| format!("Failed to parse configuration limit: {limit}"))?;
+    Ok((profile.to_string(), limit))
 }
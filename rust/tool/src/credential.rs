@@ -0,0 +1,65 @@
+use std::ffi::OsString;
+use std::path::Path;
+use std::process::Command;
+
+use anyhow::{Context, Result};
+use clap::ValueEnum;
+
+/// The name of the credential that contains the initrd secrets.
+///
+/// The stub hands it to the initrd as `/.extra/credentials/initrd-secrets.cred`.
+pub const INITRD_SECRETS_CREDENTIAL_NAME: &str = "initrd-secrets";
+
+/// The key a credential is encrypted with.
+///
+/// This mirrors the `--with-key` option of `systemd-creds encrypt`. Only keys that are available in
+/// the initrd are supported. The host key in /var/lib/systemd/credential.secret lives on the root
+/// file system, which is not mounted yet when the initrd decrypts the credential.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, ValueEnum)]
+pub enum CredentialKey {
+    /// Bind to the TPM2.
+    Tpm2,
+}
+
+impl CredentialKey {
+    fn as_str(&self) -> &'static str {
+        match self {
+            Self::Tpm2 => "tpm2",
+        }
+    }
+}
+
+/// Encrypt a file into a systemd credential with `systemd-creds`.
+///
+/// The name is embedded into the credential and checked when it is decrypted. This prevents a
+/// credential from being used under a different name.
+pub fn encrypt(
+    systemd: &Path,
+    key: CredentialKey,
+    name: &str,
+    from: &Path,
+    to: &Path,
+) -> Result<()> {
+    let systemd_creds = systemd.join("bin/systemd-creds");
+
+    let args: Vec<OsString> = vec![
+        OsString::from("encrypt"),
+        format!("--name={name}").into(),
+        format!("--with-key={}", key.as_str()).into(),
+        from.as_os_str().to_owned(),
+        to.as_os_str().to_owned(),
+    ];
+
+    let status = Command::new(&systemd_creds)
+        .args(&args)
+        .status()
+        .with_context(|| format!("Failed to run {systemd_creds:?}."))?;
+    if !status.success() {
+        return Err(anyhow::anyhow!(
+            "Failed to encrypt credential with args `{:?}`",
+            &args
+        ));
+    }
+
+    Ok(())
+}
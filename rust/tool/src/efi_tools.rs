@@ -0,0 +1,136 @@
+use std::fmt::Write;
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
+
+use anyhow::{Context, Result};
+
+use crate::error::Error;
+
+/// The prefix of the systemd-boot loader entries that lzbt creates for EFI tools.
+///
+/// Only entries starting with this prefix are garbage collected from `loader/entries`.
+pub const LOADER_ENTRY_PREFIX: &str = "nixos-tool-";
+
+/// An extra EFI binary (e.g. memtest86+ or the UEFI shell) that is signed and added to the boot
+/// menu.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct EfiTool {
+    /// The name of the tool. It is used as the title of the boot menu entry and to derive the file
+    /// names on the ESP.
+    pub name: String,
+    /// The path to the unsigned EFI binary.
+    pub path: PathBuf,
+}
+
+impl EfiTool {
+    /// The file name of the signed binary in the managed tools directory.
+    pub fn filename(&self) -> PathBuf {
+        PathBuf::from(format!("{}.efi", self.name))
+    }
+
+    /// The file name of the systemd-boot loader entry.
+    pub fn loader_entry_filename(&self) -> PathBuf {
+        PathBuf::from(format!("{}{}.conf", LOADER_ENTRY_PREFIX, self.name))
+    }
+
+    /// Render the systemd-boot loader entry (Type #1) for this tool.
+    ///
+    /// `installed_path` is the location of the signed binary on the ESP. It has to be inside `esp`.
+    pub(crate) fn loader_entry(&self, esp: &Path, installed_path: &Path) -> Result<String> {
+        let relative_path = installed_path
+            .strip_prefix(esp)
+            .with_context(|| {
+                format!("Failed to strip esp prefix: {esp:?} from: {installed_path:?}")
+            })?
+            .to_str()
+            .with_context(|| format!("Failed to convert {installed_path:?} to a string"))?;
+
+        let mut entry = String::new();
+        writeln!(entry, "title {}", self.name)?;
+        writeln!(entry, "efi /{}", relative_path)?;
+        writeln!(entry, "sort-key {}{}", LOADER_ENTRY_PREFIX, self.name)?;
+        Ok(entry)
+    }
+}
+
+/// Parse an EFI tool from the format `NAME=PATH`.
+impl FromStr for EfiTool {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let (name, path) = s.split_once('=').ok_or_else(|| {
+            Error::InvalidArgument(format!(
+                "Expected EFI tool in the format NAME=PATH, got: {s}"
+            ))
+        })?;
+
+        if name.is_empty() {
+            return Err(Error::InvalidArgument(format!(
+                "The name of the EFI tool {s} is empty."
+            )));
+        }
+
+        // The name ends up in file names on the ESP. Keep it to a character set that is safe on
+        // vfat and does not allow escaping the tools directory.
+        if !name
+            .chars()
+            .all(|c| c.is_ascii_alphanumeric() || matches!(c, '-' | '_' | '.'))
+        {
+            return Err(Error::InvalidArgument(format!(
+                "The name of the EFI tool {name} may only contain ASCII letters, digits, '-', '_' and '.'."
+            )));
+        }
+
+        if path.is_empty() {
+            return Err(Error::InvalidArgument(format!(
+                "The path of the EFI tool {name} is empty."
+            )));
+        }
+
+        Ok(Self {
+            name: name.to_string(),
+            path: PathBuf::from(path),
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parse_efi_tool_correctly() -> Result<()> {
+        let tool = EfiTool::from_str("memtest86=/nix/store/xxx-memtest86plus/memtest.efi")?;
+
+        assert_eq!(tool.name, "memtest86");
+        assert_eq!(
+            tool.path,
+            PathBuf::from("/nix/store/xxx-memtest86plus/memtest.efi")
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn fail_to_parse_efi_tool() {
+        assert!(EfiTool::from_str("memtest86").is_err());
+        assert!(EfiTool::from_str("=/memtest.efi").is_err());
+        assert!(EfiTool::from_str("memtest86=").is_err());
+        assert!(EfiTool::from_str("../memtest86=/memtest.efi").is_err());
+        assert!(EfiTool::from_str("mem test=/memtest.efi").is_err());
+    }
+
+    #[test]
+    fn render_loader_entry_correctly() -> Result<()> {
+        let tool = EfiTool::from_str("shell=/nix/store/xxx-edk2-uefi-shell/shell.efi")?;
+        let esp = Path::new("/boot");
+        let installed_path = Path::new("/boot/EFI/nixos/tools/shell.efi");
+
+        let entry = tool.loader_entry(esp, installed_path)?;
+
+        assert_eq!(
+            entry,
+            "title shell\nefi /EFI/nixos/tools/shell.efi\nsort-key nixos-tool-shell\n"
+        );
+        Ok(())
+    }
+}
@@ -0,0 +1,245 @@
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+
+use anyhow::{anyhow, Context, Result};
+
+use crate::generation::DEFAULT_PROFILE;
+
+/// The default mountpoint of efivarfs.
+pub const DEFAULT_EFIVARFS: &str = "/sys/firmware/efi/efivars";
+
+/// The vendor UUID of the variables of the Boot Loader Interface.
+const BOOT_LOADER_VENDOR_UUID: &str = "4a67b082-0a4c-41cf-b6c7-440b29bb8c4f";
+
+/// The generation the system was booted from.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct BootedGeneration {
+    /// Name of the profile or `None` for the default system profile
+    pub profile: Option<String>,
+    pub version: u64,
+}
+
+impl BootedGeneration {
+    /// Read the booted generation from the Boot Loader Interface variables in efivarfs.
+    ///
+    /// The `LoaderImageIdentifier` exported by the stub is preferred because it names the image
+    /// that was actually booted. Otherwise, the `LoaderEntrySelected` exported by systemd-boot is
+    /// used. Returns `None` if neither variable exists or names a NixOS generation, e.g. because
+    /// the system was not booted via lanzaboote.
+    pub fn from_efivarfs(efivarfs: &Path) -> Result<Option<Self>> {
+        for name in ["LoaderImageIdentifier", "LoaderEntrySelected"] {
+            let Some(value) = read_loader_variable(efivarfs, name)? else {
+                continue;
+            };
+
+            match Self::from_entry(&value) {
+                Some(booted_generation) => return Ok(Some(booted_generation)),
+                None => log::debug!("{name} {value:?} does not refer to a NixOS generation."),
+            }
+        }
+
+        Ok(None)
+    }
+
+    /// Parse the booted generation from an image path or a boot loader entry.
+    ///
+    /// Accepts image paths (e.g. `\EFI\Linux\nixos-generation-40.efi`) and boot loader entry IDs
+    /// (e.g. `nixos-my-server-generation-12-specialisation-foo.efi` or
+    /// `nixos-generation-40.conf`). Specialisations are mapped to their generation.
+    fn from_entry(entry: &str) -> Option<Self> {
+        let file_name = entry.rsplit(['\\', '/']).next()?;
+        let lowercase_file_name = file_name.to_ascii_lowercase();
+        let name = [".efi", ".conf"]
+            .iter()
+            .find_map(|suffix| {
+                lowercase_file_name
+                    .ends_with(suffix)
+                    .then(|| &file_name[..file_name.len() - suffix.len()])
+            })
+            .unwrap_or(file_name);
+
+        let (profile, rest) = match name.strip_prefix("nixos-generation-") {
+            Some(rest) => (None, rest),
+            None => {
+                let (profile, rest) = name.strip_prefix("nixos-")?.split_once("-generation-")?;
+                (Some(profile), rest)
+            }
+        };
+        let version = match rest.split_once('-') {
+            Some((version, specialisation)) => {
+                specialisation.strip_prefix("specialisation-")?;
+                version
+            }
+            None => rest,
+        };
+
+        Some(Self {
+            profile: profile
+                .filter(|profile| *profile != DEFAULT_PROFILE)
+                .map(String::from),
+            version: version.parse().ok()?,
+        })
+    }
+}
+
+/// Read a string variable of the Boot Loader Interface from efivarfs.
+///
+/// Returns `None` if the variable does not exist.
+fn read_loader_variable(efivarfs: &Path, name: &str) -> Result<Option<String>> {
+    let path = loader_variable_path(efivarfs, name);
+    let Some(data) = read_variable_file(&path)? else {
+        return Ok(None);
+    };
+
+    decode_utf16_variable(&data)
+        .with_context(|| format!("Failed to decode EFI variable {path:?}"))
+        .map(Some)
+}
+
+/// Read the raw value of a variable of the Boot Loader Interface from efivarfs.
+///
+/// The attributes are stripped from the value. Returns `None` if the variable does not exist.
+pub fn read_loader_variable_data(efivarfs: &Path, name: &str) -> Result<Option<Vec<u8>>> {
+    let path = loader_variable_path(efivarfs, name);
+    let Some(mut data) = read_variable_file(&path)? else {
+        return Ok(None);
+    };
+    if data.len() < 4 {
+        return Err(anyhow!(
+            "EFI variable {path:?} is shorter than its attributes"
+        ));
+    }
+
+    Ok(Some(data.split_off(4)))
+}
+
+/// Create a variable of the Boot Loader Interface in efivarfs.
+///
+/// efivarfs expects the value to be prefixed with its 4 byte attributes.
+pub fn write_loader_variable_data(
+    efivarfs: &Path,
+    name: &str,
+    attributes: u32,
+    value: &[u8],
+) -> Result<()> {
+    let path = loader_variable_path(efivarfs, name);
+    let data = attributes
+        .to_le_bytes()
+        .into_iter()
+        .chain(value.iter().copied())
+        .collect::<Vec<u8>>();
+
+    // efivarfs requires the whole variable to be written at once.
+    fs::write(&path, data).with_context(|| format!("Failed to write EFI variable {path:?}"))
+}
+
+fn loader_variable_path(efivarfs: &Path, name: &str) -> PathBuf {
+    efivarfs.join(format!("{name}-{BOOT_LOADER_VENDOR_UUID}"))
+}
+
+/// Read a variable file from efivarfs. Returns `None` if the variable does not exist.
+fn read_variable_file(path: &Path) -> Result<Option<Vec<u8>>> {
+    match fs::read(path) {
+        Ok(data) => Ok(Some(data)),
+        Err(e) if e.kind() == io::ErrorKind::NotFound => Ok(None),
+        Err(e) => Err(e).with_context(|| format!("Failed to read EFI variable {path:?}")),
+    }
+}
+
+/// Decode the contents of a string variable read from efivarfs.
+///
+/// efivarfs prefixes the value with its 4 byte attributes. The value is a UTF-16LE string that is
+/// usually terminated by a NUL character.
+fn decode_utf16_variable(data: &[u8]) -> Result<String> {
+    let value = data
+        .get(4..)
+        .ok_or_else(|| anyhow!("Variable is shorter than its attributes"))?;
+    let code_units = value
+        .chunks_exact(2)
+        .map(|c| u16::from_le_bytes([c[0], c[1]]))
+        .take_while(|c| *c != 0)
+        .collect::<Vec<u16>>();
+
+    String::from_utf16(&code_units).context("Variable is not valid UTF-16")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn encode_utf16_variable(value: &str) -> Vec<u8> {
+        [0x06, 0x00, 0x00, 0x00]
+            .into_iter()
+            .chain(value.encode_utf16().chain([0]).flat_map(u16::to_le_bytes))
+            .collect()
+    }
+
+    #[test]
+    fn decode_variable() -> Result<()> {
+        let data = encode_utf16_variable("nixos-generation-40.efi");
+        assert_eq!(decode_utf16_variable(&data)?, "nixos-generation-40.efi");
+        assert!(decode_utf16_variable(&[0x06]).is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn parse_booted_generation() {
+        let generation = |profile: Option<&str>, version| {
+            Some(BootedGeneration {
+                profile: profile.map(String::from),
+                version,
+            })
+        };
+
+        assert_eq!(
+            BootedGeneration::from_entry(r"\EFI\Linux\nixos-generation-40.efi"),
+            generation(None, 40)
+        );
+        assert_eq!(
+            BootedGeneration::from_entry("nixos-generation-40-specialisation-foo-bar.efi"),
+            generation(None, 40)
+        );
+        assert_eq!(
+            BootedGeneration::from_entry("nixos-my-server-generation-12.EFI"),
+            generation(Some("my-server"), 12)
+        );
+        assert_eq!(
+            BootedGeneration::from_entry("nixos-generation-7.conf"),
+            generation(None, 7)
+        );
+        assert_eq!(BootedGeneration::from_entry("ubuntu.efi"), None);
+        assert_eq!(
+            BootedGeneration::from_entry("nixos-generation-40-foo.efi"),
+            None
+        );
+    }
+
+    #[test]
+    fn prefer_image_identifier() -> Result<()> {
+        let efivarfs = tempfile::tempdir()?;
+        assert_eq!(BootedGeneration::from_efivarfs(efivarfs.path())?, None);
+
+        fs::write(
+            efivarfs
+                .path()
+                .join(format!("LoaderEntrySelected-{BOOT_LOADER_VENDOR_UUID}")),
+            encode_utf16_variable("nixos-generation-41.efi"),
+        )?;
+        fs::write(
+            efivarfs
+                .path()
+                .join(format!("LoaderImageIdentifier-{BOOT_LOADER_VENDOR_UUID}")),
+            encode_utf16_variable(r"\EFI\Linux\nixos-generation-40.efi"),
+        )?;
+
+        assert_eq!(
+            BootedGeneration::from_efivarfs(efivarfs.path())?,
+            Some(BootedGeneration {
+                profile: None,
+                version: 40
+            })
+        );
+        Ok(())
+    }
+}
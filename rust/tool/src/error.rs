@@ -0,0 +1,141 @@
+use std::path::PathBuf;
+
+/// A boxed error from the internals of lanzaboote_tool.
+///
+/// It keeps the whole chain of causes, which can be walked with `std::error::Error::source`.
+pub type BoxError = Box<dyn std::error::Error + Send + Sync + 'static>;
+
+/// The result type of the public API of lanzaboote_tool.
+pub type Result<T, E = Error> = std::result::Result<T, E>;
+
+/// The errors of the public API of lanzaboote_tool.
+///
+/// Each variant describes which step failed. The underlying cause is available as the source of
+/// the error.
+#[derive(Debug, thiserror::Error)]
+#[non_exhaustive]
+pub enum Error {
+    /// A setting the installer cannot work without was not provided to the builder.
+    #[error("Missing required installer setting: {0}")]
+    MissingSetting(&'static str),
+
+    /// A value (e.g. a pin or a title template) could not be parsed.
+    #[error("{0}")]
+    InvalidArgument(String),
+
+    /// The pin file could not be read.
+    #[error("Failed to read pin file {path:?}")]
+    PinFile {
+        /// The pin file.
+        path: PathBuf,
+        /// The underlying cause.
+        #[source]
+        source: BoxError,
+    },
+
+    /// The generation links could not be discovered.
+    #[error("Failed to discover generation links in {path:?}")]
+    Discovery {
+        /// The directory that was searched.
+        path: PathBuf,
+        /// The underlying cause.
+        #[source]
+        source: BoxError,
+    },
+
+    /// A generation could not be read.
+    #[error("Failed to read generation {path:?}")]
+    Generation {
+        /// The generation link.
+        path: PathBuf,
+        /// The underlying cause.
+        #[source]
+        source: BoxError,
+    },
+
+    /// None of the generations can be installed.
+    #[error("No bootable generations found! Aborting to avoid unbootable system. Please check for Lanzaboote updates!")]
+    NoBootableGenerations,
+
+    /// The kernel, the initrds or the command line of a generation cannot be booted by the stub.
+    #[error("Pre-flight validation of {generation} failed")]
+    Preflight {
+        /// The generation that was checked.
+        generation: String,
+        /// The underlying cause.
+        #[source]
+        source: BoxError,
+    },
+
+    /// The lanzaboote image of a generation could not be assembled.
+    #[error("Failed to assemble lanzaboote image for {generation}")]
+    Image {
+        /// The generation whose image was assembled.
+        generation: String,
+        /// The underlying cause.
+        #[source]
+        source: BoxError,
+    },
+
+    /// An installed binary is not signed with the public key of the installer.
+    #[error("{path:?} is not signed with the public key of the installer")]
+    InvalidSignature {
+        /// The binary on the ESP.
+        path: PathBuf,
+    },
+
+    /// A binary could not be signed.
+    #[error("Failed to sign {path:?}")]
+    Signing {
+        /// The destination of the signed binary.
+        path: PathBuf,
+        /// The underlying cause.
+        #[source]
+        source: BoxError,
+    },
+
+    /// systemd-boot could not be installed to the ESP.
+    #[error("Failed to install systemd-boot")]
+    SystemdBoot(#[source] BoxError),
+
+    /// A file could not be written to the ESP.
+    #[error("Failed to write {path:?} to the ESP")]
+    EspWrite {
+        /// The file on the ESP.
+        path: PathBuf,
+        /// The underlying cause.
+        #[source]
+        source: BoxError,
+    },
+
+    /// A file or directory on the ESP could not be accessed.
+    #[error("Failed to access {path:?}")]
+    Io {
+        /// The file or directory on the ESP.
+        path: PathBuf,
+        /// The underlying cause.
+        #[source]
+        source: std::io::Error,
+    },
+
+    /// Installing to the ESP failed.
+    #[error("Failed to install lanzaboote")]
+    Install(#[source] BoxError),
+
+    /// Removing lanzaboote from the ESP failed.
+    #[error("Failed to uninstall lanzaboote")]
+    Uninstall(#[source] BoxError),
+}
+
+impl Error {
+    /// Convert an error from the internals of the installer.
+    ///
+    /// Errors of this type that were passed through the internals (e.g. a failure to sign a
+    /// binary) are returned unchanged. Everything else is reported as a failed install.
+    pub(crate) fn from_install(error: anyhow::Error) -> Self {
+        match error.downcast::<Self>() {
+            Ok(error) => error,
+            Err(error) => Self::Install(error.into()),
+        }
+    }
+}
@@ -1,51 +1,109 @@
 use std::array::IntoIter;
+use std::fs;
 use std::path::{Path, PathBuf};
 
 use anyhow::{Context, Result};
+use walkdir::WalkDir;
 
+use crate::credential;
 use crate::generation::Generation;
+use crate::pe;
+
+/// The name of the directory in esp/EFI/nixos that contains the EFI tools.
+pub const TOOLS_DIR: &str = "tools";
+
+/// The file name of the rescue image in esp/EFI/Linux.
+const RESCUE_IMAGE: &str = "nixos-rescue.efi";
+
+/// The file name of the record of the generation the rescue image was built from in esp/EFI/nixos.
+const RESCUE_STATE: &str = "rescue.json";
 
 /// Paths to the boot files that are not specific to a generation.
+///
+/// Further paths may be added in future versions. Thus, this struct can only be created with
+/// [`EspPaths::new`].
+#[non_exhaustive]
 pub struct EspPaths {
+    /// The mountpoint of the ESP.
     pub esp: PathBuf,
+    /// esp/EFI.
     pub efi: PathBuf,
+    /// esp/EFI/nixos, which contains the kernels and initrds of NixOS.
     pub nixos: PathBuf,
+    /// esp/EFI/nixos/tools, which contains the EFI tools.
+    pub tools: PathBuf,
+    /// esp/EFI/Linux, which contains the lanzaboote images.
     pub linux: PathBuf,
+    /// esp/EFI/BOOT.
     pub efi_fallback_dir: PathBuf,
+    /// The fallback boot loader esp/EFI/BOOT/BOOTX64.EFI.
     pub efi_fallback: PathBuf,
+    /// The directory of the backup of a foreign fallback boot loader.
+    pub efi_fallback_backup_dir: PathBuf,
+    /// The backup of a foreign fallback boot loader.
+    pub efi_fallback_backup: PathBuf,
+    /// esp/EFI/systemd.
     pub systemd: PathBuf,
+    /// The systemd-boot binary esp/EFI/systemd/systemd-bootx64.efi.
     pub systemd_boot: PathBuf,
+    /// esp/loader.
     pub loader: PathBuf,
+    /// The systemd-boot loader config esp/loader/loader.conf.
     pub systemd_boot_loader_config: PathBuf,
+    /// esp/loader/entries, which contains the Type #1 loader entries.
+    pub loader_entries: PathBuf,
+    /// The random seed systemd-boot passes to the OS.
+    pub random_seed: PathBuf,
+    /// The rescue image.
+    pub rescue_image: PathBuf,
+    /// The record of the generation the rescue image was built from.
+    pub rescue_state: PathBuf,
 }
 
 impl EspPaths {
+    /// Compute the paths for the ESP mounted at `esp`.
+    ///
+    /// Nothing is read from or written to the ESP.
     pub fn new(esp: impl AsRef<Path>) -> Self {
         let esp = esp.as_ref();
         let efi = esp.join("EFI");
         let efi_nixos = efi.join("nixos");
+        let efi_nixos_tools = efi_nixos.join(TOOLS_DIR);
         let efi_linux = efi.join("Linux");
         let efi_systemd = efi.join("systemd");
         let efi_efi_fallback_dir = efi.join("BOOT");
+        let efi_fallback_backup_dir = efi_nixos.join("fallback-backup");
         let loader = esp.join("loader");
         let systemd_boot_loader_config = loader.join("loader.conf");
+        let loader_entries = loader.join("entries");
 
         Self {
             esp: esp.to_path_buf(),
             efi,
-            nixos: efi_nixos,
-            linux: efi_linux,
+            nixos: efi_nixos.clone(),
+            tools: efi_nixos_tools,
+            linux: efi_linux.clone(),
             efi_fallback_dir: efi_efi_fallback_dir.clone(),
             efi_fallback: efi_efi_fallback_dir.join("BOOTX64.EFI"),
+            efi_fallback_backup: efi_fallback_backup_dir.join("BOOTX64.EFI"),
+            efi_fallback_backup_dir,
             systemd: efi_systemd.clone(),
             systemd_boot: efi_systemd.join("systemd-bootx64.efi"),
-            loader,
+            loader: loader.clone(),
             systemd_boot_loader_config,
+            loader_entries,
+            random_seed: loader.join("random-seed"),
+            rescue_image: efi_linux.join(RESCUE_IMAGE),
+            rescue_state: efi_nixos.join(RESCUE_STATE),
         }
     }
 
     /// Return the used file paths to store as garbage collection roots.
-    pub fn to_iter(&self) -> IntoIter<&PathBuf, 10> {
+    ///
+    /// The backup of a foreign fallback boot loader is included so that it is never garbage
+    /// collected, regardless of the fallback policy. The same holds for the rescue image. The
+    /// files it references are added by the installer.
+    pub fn to_iter(&self) -> IntoIter<&PathBuf, 15> {
         [
             &self.esp,
             &self.efi,
@@ -53,10 +111,15 @@ impl EspPaths {
             &self.linux,
             &self.efi_fallback_dir,
             &self.efi_fallback,
+            &self.efi_fallback_backup_dir,
+            &self.efi_fallback_backup,
             &self.systemd,
             &self.systemd_boot,
             &self.loader,
             &self.systemd_boot_loader_config,
+            &self.random_seed,
+            &self.rescue_image,
+            &self.rescue_state,
         ]
         .into_iter()
     }
@@ -65,35 +128,87 @@ impl EspPaths {
 /// Paths to the boot files of a specific generation.
 pub struct EspGenerationPaths {
     pub kernel: PathBuf,
-    pub initrd: PathBuf,
+    /// Additional initrds that are loaded before the initrd of the generation (e.g. CPU
+    /// microcode).
+    pub extra_initrds: Vec<PathBuf>,
+    /// The initrd of the generation. Generations do not necessarily have an initrd.
+    pub initrd: Option<PathBuf>,
     pub lanzaboote_image: PathBuf,
+    /// The directory next to the lanzaboote image from which the stub picks up credentials.
+    pub credentials: PathBuf,
+    /// The encrypted credential that contains the initrd secrets.
+    pub initrd_secrets_credential: PathBuf,
 }
 
 impl EspGenerationPaths {
-    pub fn new(esp_paths: &EspPaths, generation: &Generation) -> Result<Self> {
+    pub fn new(
+        esp_paths: &EspPaths,
+        generation: &Generation,
+        extra_initrds: &[PathBuf],
+    ) -> Result<Self> {
         let bootspec = &generation.spec.bootspec.bootspec;
+        let lanzaboote_image = esp_paths.linux.join(generation_path(generation));
+        let credentials = credentials_path(&lanzaboote_image);
 
         Ok(Self {
             kernel: esp_paths
                 .nixos
                 .join(nixos_path(&bootspec.kernel, "bzImage")?),
-            initrd: esp_paths.nixos.join(nixos_path(
-                bootspec
-                    .initrd
-                    .as_ref()
-                    .context("Lanzaboote does not support missing initrd yet")?,
-                "initrd",
-            )?),
-            lanzaboote_image: esp_paths.linux.join(generation_path(generation)),
+            extra_initrds: extra_initrds
+                .iter()
+                .map(|initrd| extra_initrd_path(esp_paths, initrd))
+                .collect::<Result<Vec<PathBuf>>>()?,
+            initrd: bootspec
+                .initrd
+                .as_ref()
+                .map(|initrd| {
+                    Ok::<_, anyhow::Error>(esp_paths.nixos.join(nixos_path(initrd, "initrd")?))
+                })
+                .transpose()?,
+            lanzaboote_image,
+            initrd_secrets_credential: credentials.join(format!(
+                "{}.cred",
+                credential::INITRD_SECRETS_CREDENTIAL_NAME
+            )),
+            credentials,
         })
     }
 
+    /// Return all initrds in the order in which they are concatenated and passed to the kernel.
+    pub fn initrds(&self) -> impl Iterator<Item = &PathBuf> {
+        self.extra_initrds.iter().chain(&self.initrd)
+    }
+
     /// Return the used file paths to store as garbage collection roots.
-    pub fn to_iter(&self) -> IntoIter<&PathBuf, 3> {
-        [&self.kernel, &self.initrd, &self.lanzaboote_image].into_iter()
+    pub fn to_iter(&self) -> impl Iterator<Item = &PathBuf> {
+        [&self.kernel, &self.lanzaboote_image]
+            .into_iter()
+            .chain(self.initrds())
     }
 }
 
+/// Compute the path of an extra initrd on the ESP.
+///
+/// Multiple extra initrds can come from the same directory. Thus, unlike for the initrd of a
+/// generation, the file name is part of the path on the ESP.
+fn extra_initrd_path(esp_paths: &EspPaths, initrd: &Path) -> Result<PathBuf> {
+    let file_name = initrd
+        .file_name()
+        .and_then(|x| x.to_str())
+        .with_context(|| format!("Failed to extract file name from: {initrd:?}"))?;
+
+    Ok(esp_paths.nixos.join(nixos_path(initrd, file_name)?))
+}
+
+/// Compute the directory from which the stub picks up credentials for an image.
+///
+/// Like systemd-stub, the stub looks for credentials in `<image>.extra.d`.
+pub fn credentials_path(image: &Path) -> PathBuf {
+    let mut path = image.as_os_str().to_owned();
+    path.push(".extra.d");
+    PathBuf::from(path)
+}
+
 fn nixos_path(path: impl AsRef<Path>, name: &str) -> Result<PathBuf> {
     let resolved = path
         .as_ref()
@@ -112,14 +227,122 @@ fn nixos_path(path: impl AsRef<Path>, name: &str) -> Result<PathBuf> {
 }
 
 fn generation_path(generation: &Generation) -> PathBuf {
+    if generation.is_rescue() {
+        return PathBuf::from(RESCUE_IMAGE);
+    }
+
+    let prefix = generation_prefix(generation.profile(), generation.version());
+
     if let Some(specialisation_name) = generation.is_specialised() {
         PathBuf::from(format!(
-            "nixos-generation-{}-specialisation-{}.efi",
-            generation, specialisation_name
+            "{}-specialisation-{}.efi",
+            prefix, specialisation_name
         ))
     } else {
-        PathBuf::from(format!("nixos-generation-{}.efi", generation))
+        PathBuf::from(format!("{}.efi", prefix))
+    }
+}
+
+/// Compute the common prefix of the file names of the images of a generation and its
+/// specialisations (e.g. "nixos-generation-2" or "nixos-my-server-generation-12").
+fn generation_prefix(profile: Option<&str>, version: u64) -> String {
+    match profile {
+        Some(profile) => format!("nixos-{profile}-generation-{version}"),
+        None => format!("nixos-generation-{version}"),
+    }
+}
+
+/// Return the files on the ESP that an installed image references in its `.kernelp` and
+/// `.initrdp` sections.
+///
+/// Fat images do not reference a kernel or initrds.
+pub fn referenced_paths(esp: &Path, image: &Path) -> Result<Vec<PathBuf>> {
+    let file_data = fs::read(image).with_context(|| format!("Failed to read image {image:?}"))?;
+
+    let mut paths = Vec::new();
+    for section in [".kernelp", ".initrdp"] {
+        let Some(data) = pe::read_section_data(&file_data, section) else {
+            continue;
+        };
+        let uefi_paths = std::str::from_utf8(data)
+            .with_context(|| format!("Failed to read {section} section of {image:?}"))?;
+        paths.extend(
+            uefi_paths
+                .lines()
+                .map(|p| p.trim_end_matches('\0'))
+                .filter(|p| !p.is_empty())
+                .map(|p| esp.join(p.trim_start_matches('\\').replace('\\', "/"))),
+        );
+    }
+    Ok(paths)
+}
+
+/// Return the paths on the ESP that plausibly belong to a generation whose bootspec cannot be
+/// read.
+///
+/// The images (and their credentials) are found by the profile and version of the generation.
+/// The kernels and initrds are the ones the installed images reference. If the toplevel still
+/// exists, the kernels and initrds found via the `kernel` and `initrd` symlinks of the toplevel
+/// and its specialisations are kept as well. Only paths that exist are returned.
+pub fn broken_generation_paths(
+    esp_paths: &EspPaths,
+    profile: Option<&str>,
+    version: u64,
+    toplevel: &Path,
+) -> Vec<PathBuf> {
+    let prefix = generation_prefix(profile, version);
+    let image = format!("{prefix}.efi");
+    let specialisation_prefix = format!("{prefix}-specialisation-");
+
+    let is_image = |name: &str| {
+        let name = name.strip_suffix(".extra.d").unwrap_or(name);
+        name == image || (name.starts_with(&specialisation_prefix) && name.ends_with(".efi"))
+    };
+
+    let mut paths = WalkDir::new(&esp_paths.linux)
+        .min_depth(1)
+        .max_depth(1)
+        .into_iter()
+        .filter_map(|entry| entry.ok())
+        .filter(|entry| entry.file_name().to_str().is_some_and(is_image))
+        .flat_map(|entry| WalkDir::new(entry.path()).into_iter())
+        .filter_map(|entry| entry.ok())
+        .map(|entry| entry.into_path())
+        .collect::<Vec<PathBuf>>();
+
+    let images = paths
+        .iter()
+        .filter(|path| path.is_file() && path.extension().is_some_and(|ext| ext == "efi"))
+        .cloned()
+        .collect::<Vec<_>>();
+    for image in images {
+        match referenced_paths(&esp_paths.esp, &image) {
+            Ok(referenced) => paths.extend(referenced),
+            Err(e) => log::warn!("Failed to read the files referenced by {image:?}: {e:#}"),
+        }
     }
+
+    let specialisations = toplevel
+        .join("specialisation")
+        .read_dir()
+        .into_iter()
+        .flatten()
+        .filter_map(|entry| entry.ok())
+        .map(|entry| entry.path());
+    for toplevel in [toplevel.to_path_buf()].into_iter().chain(specialisations) {
+        for (link, name) in [("kernel", "bzImage"), ("initrd", "initrd")] {
+            let path = toplevel.join(link);
+            if !path.exists() {
+                continue;
+            }
+            if let Ok(file_name) = nixos_path(&path, name) {
+                paths.push(esp_paths.nixos.join(file_name));
+            }
+        }
+    }
+
+    paths.retain(|path| path.exists());
+    paths
 }
 
 #[cfg(test)]
@@ -139,4 +362,75 @@ mod tests {
         assert_eq!(generated_filename, expected_filename);
         Ok(())
     }
+
+    #[test]
+    fn extra_initrd_path_contains_file_name() -> Result<()> {
+        let esp_paths = EspPaths::new("esp");
+        let path = Path::new(
+            "/nix/store/xqplddjjjy1lhzyzbcv4dza11ccpcfds-microcode-intel/intel-ucode.img",
+        );
+
+        let generated_path = extra_initrd_path(&esp_paths, path)?;
+
+        let expected_path = PathBuf::from(
+            "esp/EFI/nixos/xqplddjjjy1lhzyzbcv4dza11ccpcfds-microcode-intel-intel-ucode.img.efi",
+        );
+
+        assert_eq!(generated_path, expected_path);
+        Ok(())
+    }
+
+    #[test]
+    fn credentials_path_is_next_to_image() {
+        let image = Path::new("esp/EFI/Linux/nixos-generation-1.efi");
+
+        assert_eq!(
+            credentials_path(image),
+            PathBuf::from("esp/EFI/Linux/nixos-generation-1.efi.extra.d")
+        );
+    }
+
+    #[test]
+    fn find_paths_of_broken_generation() -> Result<()> {
+        let tmpdir = tempfile::tempdir()?;
+        let esp_paths = EspPaths::new(tmpdir.path().join("esp"));
+        std::fs::create_dir_all(&esp_paths.linux)?;
+        std::fs::create_dir_all(&esp_paths.nixos)?;
+
+        let image = esp_paths.linux.join("nixos-generation-1.efi");
+        let credentials = esp_paths.linux.join("nixos-generation-1.efi.extra.d");
+        let credential = credentials.join("initrd-secrets.cred");
+        let specialisation = esp_paths
+            .linux
+            .join("nixos-generation-1-specialisation-foo.efi");
+        let other_image = esp_paths.linux.join("nixos-generation-12.efi");
+        let kernel = esp_paths.nixos.join("xxx-linux-6.1.1-bzImage.efi");
+        let other_kernel = esp_paths.nixos.join("yyy-linux-6.1.1-bzImage.efi");
+        std::fs::create_dir(&credentials)?;
+        for path in [
+            &image,
+            &credential,
+            &specialisation,
+            &other_image,
+            &kernel,
+            &other_kernel,
+        ] {
+            std::fs::File::create(path)?;
+        }
+
+        let store_kernel = tmpdir.path().join("xxx-linux-6.1.1/bzImage");
+        std::fs::create_dir_all(store_kernel.parent().unwrap())?;
+        std::fs::File::create(&store_kernel)?;
+        let toplevel = tmpdir.path().join("toplevel");
+        std::fs::create_dir(&toplevel)?;
+        std::os::unix::fs::symlink(&store_kernel, toplevel.join("kernel"))?;
+
+        let mut paths = broken_generation_paths(&esp_paths, None, 1, &toplevel);
+        paths.sort();
+
+        let mut expected_paths = vec![image, credentials, credential, specialisation, kernel];
+        expected_paths.sort();
+        assert_eq!(paths, expected_paths);
+        Ok(())
+    }
 }
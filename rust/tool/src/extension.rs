@@ -0,0 +1,170 @@
+use std::collections::BTreeMap;
+use std::path::PathBuf;
+
+use anyhow::{anyhow, Context, Result};
+use serde::Deserialize;
+use serde_json::Value;
+
+/// The name of the bootspec extension of lanzaboote.
+pub const LANZABOOTE_EXTENSION: &str = "org.nixos-community.lanzaboote";
+
+/// The PE sections that can be added to a lanzaboote image via the bootspec extension.
+///
+/// All other sections have a meaning for the stub and thus cannot be overridden.
+const EXTRA_SECTIONS: [&str; 1] = [".dtb"];
+
+/// The PE sections that are valid in a UKI but not supported by the stub.
+///
+/// They are dropped with a warning instead of being embedded. The stub does not display splash
+/// images.
+const IGNORED_SECTIONS: [&str; 1] = [".splash"];
+
+/// The kind of stub a generation is installed with.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum StubKind {
+    /// The stub only references the kernel and initrd on the ESP and verifies them via their
+    /// hashes.
+    #[default]
+    Thin,
+    /// The kernel and initrd are embedded into the stub.
+    Fat,
+}
+
+/// The lanzaboote bootspec extension (`org.nixos-community.lanzaboote`).
+///
+/// Every generation and every specialisation can carry its own extension. All fields are
+/// optional.
+#[derive(Debug, Clone, Default, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct LanzabooteExtension {
+    /// An os-release file that is used verbatim for the `.osrel` section instead of the generated
+    /// one.
+    pub os_release: Option<PathBuf>,
+    /// Kernel parameters that are appended to the kernel parameters of the bootspec.
+    #[serde(default)]
+    pub kernel_params: Vec<String>,
+    /// Initrds that are loaded before the initrd of the bootspec.
+    #[serde(default)]
+    pub initrds: Vec<PathBuf>,
+    /// Extra PE sections by name (e.g. `.dtb`) and the files with their contents.
+    #[serde(default)]
+    pub sections: BTreeMap<String, PathBuf>,
+    /// The kind of stub to install the generation with.
+    #[serde(default)]
+    pub stub: StubKind,
+    /// Do not install the generation, i.e. exclude it from the boot menu.
+    #[serde(default)]
+    pub exclude: bool,
+    /// All keys that lzbt does not know about.
+    #[serde(flatten)]
+    unknown: BTreeMap<String, Value>,
+}
+
+impl LanzabooteExtension {
+    /// Parse the extension from the JSON value of the extension namespace.
+    ///
+    /// Unknown keys are not an error, so that an older lzbt can still install generations built
+    /// for a newer one. They are logged instead.
+    pub fn from_json(value: &Value) -> Result<Self> {
+        let mut extension = Self::deserialize(value)
+            .with_context(|| format!("Failed to parse the {LANZABOOTE_EXTENSION} extension"))?;
+
+        for key in extension.unknown.keys() {
+            log::warn!("Ignoring unknown key {key:?} in the {LANZABOOTE_EXTENSION} extension.");
+        }
+
+        extension.sections.retain(|section, _| {
+            let ignored = IGNORED_SECTIONS.contains(&section.as_str());
+            if ignored {
+                log::warn!(
+                    "Ignoring PE section {section:?} in the {LANZABOOTE_EXTENSION} extension because the stub does not support it."
+                );
+            }
+            !ignored
+        });
+        for section in extension.sections.keys() {
+            if !EXTRA_SECTIONS.contains(&section.as_str()) {
+                return Err(anyhow!(
+                    "Unsupported PE section {section:?} in the {LANZABOOTE_EXTENSION} extension. Supported sections: {}",
+                    EXTRA_SECTIONS.join(", ")
+                ));
+            }
+        }
+
+        Ok(extension)
+    }
+
+    /// Parse the extension from the bootspec extensions of a generation, e.g. the `extensions`
+    /// of a `BootJson` or a specialisation.
+    ///
+    /// A missing extension results in the default extension.
+    pub fn from_extensions<'a>(
+        mut extensions: impl Iterator<Item = (&'a String, &'a Value)>,
+    ) -> Result<Self> {
+        extensions
+            .find(|(key, _)| *key == LANZABOOTE_EXTENSION)
+            .map(|(_, value)| Self::from_json(value))
+            .transpose()
+            .map(Option::unwrap_or_default)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use serde_json::json;
+
+    #[test]
+    fn parse_extension_correctly() -> Result<()> {
+        let extension = LanzabooteExtension::from_json(&json!({
+            "osRelease": "/nix/store/xxx-os-release",
+            "kernelParams": ["quiet"],
+            "initrds": ["/nix/store/xxx-microcode/intel-ucode.img"],
+            "sections": { ".dtb": "/nix/store/xxx-dtb/board.dtb" },
+            "stub": "fat",
+            "exclude": true,
+            "someFutureKey": 42,
+        }))?;
+
+        assert_eq!(
+            extension.os_release,
+            Some(PathBuf::from("/nix/store/xxx-os-release"))
+        );
+        assert_eq!(extension.kernel_params, vec![String::from("quiet")]);
+        assert_eq!(extension.initrds.len(), 1);
+        assert_eq!(
+            extension.sections.get(".dtb"),
+            Some(&PathBuf::from("/nix/store/xxx-dtb/board.dtb"))
+        );
+        assert_eq!(extension.stub, StubKind::Fat);
+        assert!(extension.exclude);
+        assert!(extension.unknown.contains_key("someFutureKey"));
+        Ok(())
+    }
+
+    #[test]
+    fn empty_extension_is_default() -> Result<()> {
+        let extension = LanzabooteExtension::from_json(&json!({}))?;
+        assert_eq!(extension, LanzabooteExtension::default());
+        Ok(())
+    }
+
+    #[test]
+    fn reject_unsupported_sections() {
+        assert!(LanzabooteExtension::from_json(&json!({
+            "sections": { ".cmdline": "/nix/store/xxx-cmdline" }
+        }))
+        .is_err());
+    }
+
+    #[test]
+    fn ignore_splash_section() -> Result<()> {
+        let extension = LanzabooteExtension::from_json(&json!({
+            "sections": { ".splash": "/nix/store/xxx-splash.bmp" }
+        }))?;
+        assert!(extension.sections.is_empty());
+        Ok(())
+    }
+}
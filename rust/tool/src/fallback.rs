@@ -0,0 +1,78 @@
+use std::fs;
+use std::path::Path;
+
+use clap::ValueEnum;
+
+use crate::pe;
+
+/// What to do with a fallback boot loader (esp/EFI/BOOT/BOOTX64.EFI) that is not systemd-boot.
+///
+/// The fallback boot loader is shared by all operating systems on the ESP. On dual-boot machines,
+/// it might belong to Windows or another distribution.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, ValueEnum)]
+pub enum FallbackPolicy {
+    /// Replace it with systemd-boot.
+    TakeOver,
+    /// Leave it alone and do not install systemd-boot as the fallback boot loader.
+    Leave,
+    /// Back it up to esp/EFI/nixos/fallback-backup and replace it with systemd-boot.
+    ///
+    /// The backup is restored when lanzaboote is uninstalled.
+    #[default]
+    Backup,
+}
+
+/// Return whether a boot loader is a foreign, i.e. not systemd-boot, EFI binary.
+///
+/// Missing files and files that are not PE binaries are not foreign boot loaders. They are
+/// replaced like a malformed systemd-boot.
+pub fn is_foreign_boot_loader(path: &Path) -> bool {
+    let Ok(file_data) = fs::read(path) else {
+        return false;
+    };
+    if goblin::pe::PE::parse(&file_data).is_err() {
+        return false;
+    }
+
+    !is_systemd_boot(&file_data)
+}
+
+/// Return whether a file is a systemd-boot binary.
+///
+/// Missing files and files that are not PE binaries are not systemd-boot.
+pub fn is_systemd_boot_binary(path: &Path) -> bool {
+    let Ok(file_data) = fs::read(path) else {
+        return false;
+    };
+    goblin::pe::PE::parse(&file_data).is_ok() && is_systemd_boot(&file_data)
+}
+
+/// Return whether a PE binary is systemd-boot.
+///
+/// systemd-boot identifies itself with "#### LoaderInfo: systemd-boot <version> ####" in its
+/// `.sdmagic` section. Older versions without this section are recognized by the ID in their
+/// `.osrel` section.
+fn is_systemd_boot(file_data: &[u8]) -> bool {
+    let contains = |section_name, needle: &[u8]| {
+        pe::read_section_data(file_data, section_name)
+            .is_some_and(|data| data.windows(needle.len()).any(|window| window == needle))
+    };
+
+    contains(".sdmagic", b"LoaderInfo: systemd-boot ") || contains(".osrel", b"ID=systemd-boot")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn ignore_missing_and_malformed_boot_loaders() -> anyhow::Result<()> {
+        let tmpdir = tempfile::tempdir()?;
+        let boot_loader = tmpdir.path().join("BOOTX64.EFI");
+        assert!(!is_foreign_boot_loader(&boot_loader));
+
+        fs::write(&boot_loader, b"not a PE binary")?;
+        assert!(!is_foreign_boot_loader(&boot_loader));
+        Ok(())
+    }
+}
@@ -5,6 +5,8 @@ use std::path::{Path, PathBuf};
 use anyhow::{Context, Result};
 use walkdir::{DirEntry, WalkDir};
 
+use crate::quarantine::Quarantine;
+
 /// Keeps track of the garbage collection roots.
 ///
 /// The internal HashSet contains all the paths still in use. These paths
@@ -78,6 +80,56 @@ impl Roots {
 
         Ok(())
     }
+
+    /// Collect garbage, but quarantine unknown paths instead of deleting them.
+    ///
+    /// Unused paths that lzbt produced during a previous install are deleted right away. All
+    /// other unused paths are moved to the quarantine and are only deleted after the configured
+    /// number of successful installs. The quarantine itself is never garbage collected.
+    pub fn collect_garbage_with_quarantine(
+        &self,
+        directory: impl AsRef<Path>,
+        mut quarantine: Quarantine,
+    ) -> Result<()> {
+        let directory = directory.as_ref();
+        quarantine.expire()?;
+
+        let quarantine_directory = quarantine.path().to_path_buf();
+        let mut produced_directories = Vec::new();
+
+        for e in WalkDir::new(directory)
+            .into_iter()
+            .filter_entry(|e| e.path() != quarantine_directory)
+            .filter(|e| !self.in_use(e.as_ref().ok()))
+        {
+            // Entries below a path that was already removed or quarantined cannot be read.
+            let Ok(entry) = e else {
+                continue;
+            };
+            let path = entry.path();
+
+            if !quarantine.is_produced(path) {
+                log::debug!("Quarantining {path:?}...");
+                quarantine.quarantine(path)?;
+            } else if path.is_dir() {
+                // A directory produced by lzbt might still contain unknown paths. They are
+                // quarantined separately and the directory is removed afterwards if it is empty.
+                produced_directories.push(path.to_path_buf());
+            } else {
+                log::debug!("Garbage collecting {path:?}...");
+                fs::remove_file(path)
+                    .with_context(|| format!("Failed to remove file: {:?}", path))?;
+            }
+        }
+
+        // Remove the deepest directories first so that their parents can become empty.
+        for path in produced_directories.iter().rev() {
+            log::debug!("Garbage collecting {path:?}...");
+            fs::remove_dir(path).ok();
+        }
+
+        quarantine.close(self.0.iter())
+    }
 }
 
 #[cfg(test)]
@@ -189,6 +241,32 @@ mod tests {
         Ok(())
     }
 
+    #[test]
+    fn quarantine_unknown_and_delete_produced_files() -> Result<()> {
+        let tmpdir = tempfile::tempdir()?;
+        let rootdir = create_dir(tmpdir.path().join("root"))?;
+        let installs = std::num::NonZeroUsize::new(1).unwrap();
+
+        let produced_file = create_file(rootdir.join("produced_file"))?;
+        let mut roots = Roots::new();
+        roots.extend(vec![&rootdir, &produced_file]);
+        roots.collect_garbage_with_quarantine(&rootdir, Quarantine::open(&rootdir, installs)?)?;
+
+        let unknown_file = create_file(rootdir.join("unknown_file"))?;
+        let quarantined_file = rootdir.join(".quarantine/unknown_file");
+        let mut roots = Roots::new();
+        roots.extend(vec![&rootdir]);
+        roots.collect_garbage_with_quarantine(&rootdir, Quarantine::open(&rootdir, installs)?)?;
+
+        assert!(!produced_file.exists());
+        assert!(!unknown_file.exists());
+        assert!(quarantined_file.exists());
+
+        roots.collect_garbage_with_quarantine(&rootdir, Quarantine::open(&rootdir, installs)?)?;
+        assert!(!quarantined_file.exists());
+        Ok(())
+    }
+
     fn create_file(path: PathBuf) -> Result<PathBuf> {
         fs::File::create(&path)?;
         Ok(path)
@@ -1,5 +1,7 @@
+use std::collections::HashMap;
 use std::fmt;
 use std::fs;
+use std::io;
 use std::os::unix::fs::MetadataExt;
 use std::path::{Path, PathBuf};
 
@@ -7,15 +9,27 @@ use anyhow::{Context, Result};
 use bootspec::BootJson;
 use bootspec::BootSpec;
 use bootspec::SpecialisationName;
-use time::Date;
+use serde::Deserialize;
+use serde_json::Value;
+use time::{OffsetDateTime, UtcOffset};
 
-/// (Possibly) extended Bootspec.
-///
-/// This struct currently does not have any extensions. We keep it around so that extension becomes
-/// easy if/when we have to do it.
+use crate::error::Error;
+use crate::extension::LanzabooteExtension;
+
+/// The key of the specialisations in a bootspec document.
+const SPECIALISATIONS_KEY: &str = "org.nixos.specialisation.v1";
+
+/// Bootspec extended with the lanzaboote bootspec extension.
 #[derive(Debug, Clone)]
 pub struct ExtendedBootJson {
     pub bootspec: BootSpec,
+    /// The lanzaboote extension of the generation.
+    pub lanzaboote: LanzabooteExtension,
+    /// The lanzaboote extensions of the specialisations of the generation.
+    ///
+    /// The bootspec crate does not keep the extensions of specialisations. Thus, they are stored
+    /// separately.
+    pub specialisations: HashMap<SpecialisationName, LanzabooteExtension>,
 }
 
 /// A system configuration.
@@ -30,8 +44,16 @@ pub struct ExtendedBootJson {
 pub struct Generation {
     /// Profile symlink index
     version: u64,
+    /// Name of the profile or `None` for the default system profile
+    profile: Option<String>,
     /// Build time
-    build_time: Option<Date>,
+    build_time: Option<BuildTime>,
+    /// Whether the generation is pinned
+    pinned: bool,
+    /// Whether the system is currently booted from the generation
+    booted: bool,
+    /// Whether the generation is installed as the rescue image
+    rescue: bool,
     /// Top-level specialisation name
     specialisation_name: Option<SpecialisationName>,
     /// Top-level extended boot specification
@@ -39,52 +61,174 @@ pub struct Generation {
 }
 
 impl Generation {
-    pub fn from_link(link: &GenerationLink) -> Result<Self> {
+    /// Read a generation from the path of a generation link (e.g.
+    /// /nix/var/nix/profiles/system-42-link).
+    ///
+    /// If the bootspec of the generation cannot be read, a replacement is synthesized from the
+    /// toplevel. In strict mode, this fallback is disabled and the generation cannot be read.
+    pub fn read(path: impl AsRef<Path>, strict: bool) -> crate::Result<Self> {
+        let path = path.as_ref();
+        GenerationLink::from_path(path)
+            .and_then(|link| Self::from_link(&link, strict))
+            .map_err(|e| Error::Generation {
+                path: path.to_path_buf(),
+                source: e.into(),
+            })
+    }
+
+    /// Read a generation from a link.
+    ///
+    /// If the bootspec of the generation cannot be read, a replacement is synthesized from the
+    /// toplevel. In strict mode, this fallback is disabled and the generation cannot be read.
+    pub(crate) fn from_link(link: &GenerationLink, strict: bool) -> Result<Self> {
         let bootspec_path = link.path.join("boot.json");
-        let boot_json: BootJson = fs::read(bootspec_path)
+        // The document is parsed once. The bootspec and the extensions of the specialisations,
+        // which `BootJson` does not keep, are both read from it.
+        let raw_boot_json: Option<Value> = fs::read(&bootspec_path)
             .context("Failed to read bootspec file")
             .and_then(|raw| serde_json::from_slice(&raw).context("Failed to read bootspec JSON"))
-            .or_else(|_err| BootJson::synthesize_latest(&link.path)
-                    .context("Failed to read a bootspec (missing bootspec?) and failed to synthesize a valid replacement bootspec."))?;
+            .ok();
+        let boot_json: Result<BootJson> = raw_boot_json
+            .as_ref()
+            .context("Failed to read bootspec")
+            .and_then(|raw| BootJson::deserialize(raw).context("Failed to read bootspec JSON"));
+        let boot_json = match boot_json {
+            Ok(boot_json) => boot_json,
+            Err(e) if strict => {
+                return Err(e.context(
+                    "Failed to read a bootspec (missing bootspec?) and synthesizing a replacement bootspec is disabled in strict mode.",
+                ))
+            }
+            Err(_) => BootJson::synthesize_latest(&link.path)
+                    .context("Failed to read a bootspec (missing bootspec?) and failed to synthesize a valid replacement bootspec.")?,
+        };
 
         let bootspec: BootSpec = boot_json.generation.try_into()?;
+        let lanzaboote = LanzabooteExtension::from_extensions(boot_json.extensions.iter())?;
+        let specialisations = raw_boot_json
+            .as_ref()
+            .map(specialisation_extensions)
+            .transpose()?
+            .unwrap_or_default();
 
         Ok(Self {
             version: link.version,
+            profile: link.profile.clone(),
             build_time: link.build_time,
+            pinned: link.pinned,
+            booted: link.booted,
+            rescue: false,
             specialisation_name: None,
-            spec: ExtendedBootJson { bootspec },
+            spec: ExtendedBootJson {
+                bootspec,
+                lanzaboote,
+                specialisations,
+            },
         })
     }
 
-    pub fn specialise(&self, name: &SpecialisationName, bootspec: &BootSpec) -> Result<Self> {
+    pub(crate) fn specialise(
+        &self,
+        name: &SpecialisationName,
+        bootspec: &BootSpec,
+    ) -> Result<Self> {
         Ok(Self {
             version: self.version,
+            profile: self.profile.clone(),
             build_time: self.build_time,
+            pinned: self.pinned,
+            booted: self.booted,
+            rescue: self.rescue,
             specialisation_name: Some(name.clone()),
             spec: ExtendedBootJson {
                 bootspec: bootspec.clone(),
+                lanzaboote: self
+                    .spec
+                    .specialisations
+                    .get(name)
+                    .cloned()
+                    .unwrap_or_default(),
+                specialisations: HashMap::new(),
             },
         })
     }
 
+    /// Return the name of the specialisation or `None` if the generation is not a
+    /// specialisation.
     pub fn is_specialised(&self) -> Option<SpecialisationName> {
         self.specialisation_name.clone()
     }
 
-    /// Describe the generation in a single line.
-    ///
-    /// Emulates how NixOS's current systemd-boot-builder.py describes generations so that the user
-    /// interface remains similar.
+    /// Return the name of the profile or `None` if the generation belongs to the default system
+    /// profile.
+    pub fn profile(&self) -> Option<&str> {
+        self.profile.as_deref()
+    }
+
+    /// Return the version of the generation, i.e. the index of the profile symlink.
+    pub fn version(&self) -> u64 {
+        self.version
+    }
+
+    /// Return whether the generation is pinned, i.e. always installed regardless of the
+    /// configuration limit.
+    pub fn is_pinned(&self) -> bool {
+        self.pinned
+    }
+
+    /// Return whether the system is currently booted from the generation.
+    pub fn is_booted(&self) -> bool {
+        self.booted
+    }
+
+    /// Install the generation as the rescue image instead of as a regular generation.
+    pub(crate) fn into_rescue(self) -> Self {
+        Self {
+            rescue: true,
+            ..self
+        }
+    }
+
+    /// Return whether the generation is installed as the rescue image.
+    pub fn is_rescue(&self) -> bool {
+        self.rescue
+    }
+
+    /// Return when the generation was built.
+    pub fn build_time(&self) -> Option<BuildTime> {
+        self.build_time
+    }
+
+    /// Describe when the generation was built.
+    pub fn describe_build_time(&self) -> String {
+        self.build_time
+            .map(|x| x.to_string())
+            .unwrap_or_else(|| String::from("Unknown"))
+    }
+
+    /// Return the NixOS version of the generation (e.g. "23.05.20230101.abcdef").
     ///
-    /// This is currently implemented by poking around the filesystem to find the necessary data.
+    /// This is currently implemented by poking around the toplevel to find the necessary data.
     /// Ideally, the needed data should be included in the bootspec.
-    pub fn describe(&self) -> String {
-        let build_time = self
-            .build_time
-            .map(|x| x.to_string())
-            .unwrap_or_else(|| String::from("Unknown"));
-        format!("Generation {}, Built on {}", self.version, build_time)
+    pub fn nixos_version(&self) -> Option<String> {
+        let toplevel = &self.spec.bootspec.bootspec.toplevel.0;
+        fs::read_to_string(toplevel.join("nixos-version"))
+            .ok()
+            .map(|version| version.trim().to_string())
+    }
+
+    /// Return the version of the kernel of the generation (e.g. "6.1.1").
+    ///
+    /// This is read from the name of the kernel modules directory in the toplevel. If there are
+    /// several, the first in sort order is used so that the result does not depend on the order in
+    /// which the file system lists them.
+    pub fn kernel_version(&self) -> Option<String> {
+        let toplevel = &self.spec.bootspec.bootspec.toplevel.0;
+        fs::read_dir(toplevel.join("kernel-modules/lib/modules"))
+            .ok()?
+            .filter_map(|entry| entry.ok())
+            .filter_map(|entry| entry.file_name().into_string().ok())
+            .min()
     }
 }
 
@@ -94,46 +238,229 @@ impl fmt::Display for Generation {
     }
 }
 
-fn read_build_time(path: &Path) -> Result<Date> {
-    let build_time =
-        time::OffsetDateTime::from_unix_timestamp(fs::symlink_metadata(path)?.mtime())?.date();
-    Ok(build_time)
+/// Read the lanzaboote extensions of all specialisations from a raw bootspec document.
+fn specialisation_extensions(
+    boot_json: &Value,
+) -> Result<HashMap<SpecialisationName, LanzabooteExtension>> {
+    let Some(specialisations) = boot_json
+        .get(SPECIALISATIONS_KEY)
+        .and_then(Value::as_object)
+    else {
+        return Ok(HashMap::new());
+    };
+
+    specialisations
+        .iter()
+        .filter_map(|(name, specialisation)| {
+            specialisation.as_object().map(|extensions| {
+                LanzabooteExtension::from_extensions(extensions.iter())
+                    .with_context(|| format!("Failed to parse extensions of specialisation {name}"))
+                    .map(|extension| (SpecialisationName(name.clone()), extension))
+            })
+        })
+        .collect()
+}
+
+/// The time a generation was added to this machine.
+///
+/// Nix does not record when a path was built. This is the best approximation that is available
+/// for generations that were built elsewhere and substituted or copied, too.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum BuildTime {
+    /// The time the toplevel of the generation was registered in the Nix store, i.e. when it was
+    /// built on or copied to this machine.
+    Registered(OffsetDateTime),
+    /// The modification time of the generation link.
+    ///
+    /// This is only an approximation. It changes, for example, when a profile is copied.
+    LinkModified(OffsetDateTime),
+}
+
+impl BuildTime {
+    /// Return the build time regardless of how it was determined.
+    pub fn time(&self) -> OffsetDateTime {
+        match self {
+            Self::Registered(time) | Self::LinkModified(time) => *time,
+        }
+    }
+}
+
+/// Display the build time in UTC with second resolution.
+///
+/// A build time that is only approximated from the modification time of the generation link is
+/// marked as such.
+impl fmt::Display for BuildTime {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let (time, marker) = match self {
+            Self::Registered(time) => (time, ""),
+            Self::LinkModified(time) => (time, " (link mtime)"),
+        };
+        let time = time.to_offset(UtcOffset::UTC);
+
+        write!(
+            f,
+            "{} {:02}:{:02}:{:02} UTC{}",
+            time.date(),
+            time.hour(),
+            time.minute(),
+            time.second(),
+            marker
+        )
+    }
+}
+
+fn read_link_modification_time(path: &Path) -> Result<BuildTime> {
+    let mtime = OffsetDateTime::from_unix_timestamp(fs::symlink_metadata(path)?.mtime())?;
+    Ok(BuildTime::LinkModified(mtime))
 }
 
 /// A link pointing to a generation.
 ///
 /// Can be built from a symlink in /nix/var/nix/profiles/ alone because the name of the
-/// symlink encodes the version number.
-#[derive(Debug)]
+/// symlink encodes the profile name and the version number.
+#[derive(Debug, Clone)]
 pub struct GenerationLink {
     pub version: u64,
+    /// Name of the profile or `None` for the default system profile
+    pub profile: Option<String>,
     pub path: PathBuf,
-    pub build_time: Option<Date>,
+    pub build_time: Option<BuildTime>,
+    /// Whether the generation is pinned, i.e. always installed regardless of the configuration
+    /// limit
+    pub pinned: bool,
+    /// Whether the system is currently booted from this generation
+    pub booted: bool,
 }
 
 impl GenerationLink {
     pub fn from_path(path: impl AsRef<Path>) -> Result<Self> {
+        let (profile, version) =
+            parse_link_name(&path).context("Failed to parse generation link name")?;
+
         Ok(Self {
-            version: parse_version(&path).context("Failed to parse version")?,
+            version,
+            profile,
             path: PathBuf::from(path.as_ref()),
-            build_time: read_build_time(path.as_ref()).ok(),
+            build_time: read_link_modification_time(path.as_ref()).ok(),
+            pinned: false,
+            booted: false,
         })
     }
+
+    /// Return the path of the profile this generation belongs to.
+    ///
+    /// This is the path that needs to be passed to `nix-env --profile`.
+    pub fn profile_path(&self) -> PathBuf {
+        let profile_name = self.profile.as_deref().unwrap_or(DEFAULT_PROFILE);
+        self.path
+            .parent()
+            .map(|parent| parent.join(profile_name))
+            .unwrap_or_else(|| PathBuf::from(profile_name))
+    }
+}
+
+/// The name of the default system profile (i.e. /nix/var/nix/profiles/system).
+pub const DEFAULT_PROFILE: &str = "system";
+
+/// The name of the directory next to the default system profile that contains the other profiles.
+const SYSTEM_PROFILES_DIR: &str = "system-profiles";
+
+/// Find the generation links in a profiles directory (usually /nix/var/nix/profiles).
+///
+/// Returns the links of the default system profile and of all profiles in `system-profiles`
+/// sorted by path. These are the links the NixOS module passes to lzbt.
+pub fn discover_generation_links(profiles: impl AsRef<Path>) -> crate::Result<Vec<PathBuf>> {
+    let profiles = profiles.as_ref();
+    let system_profiles = profiles.join(SYSTEM_PROFILES_DIR);
+
+    let mut links = find_generation_links(profiles)
+        .map_err(|e| Error::Discovery {
+            path: profiles.to_path_buf(),
+            source: e.into(),
+        })?
+        .into_iter()
+        .filter(|(profile, _)| profile.is_none())
+        .collect::<Vec<(Option<String>, PathBuf)>>();
+    match find_generation_links(&system_profiles) {
+        Ok(profile_links) => links.extend(profile_links),
+        // Profiles in system-profiles are optional.
+        Err(e) if e.kind() == io::ErrorKind::NotFound => (),
+        Err(e) => {
+            return Err(Error::Discovery {
+                path: system_profiles,
+                source: e.into(),
+            })
+        }
+    }
+
+    let mut links = links
+        .into_iter()
+        .map(|(_, path)| path)
+        .collect::<Vec<PathBuf>>();
+    links.sort();
+    Ok(links)
+}
+
+/// Find the paths in a directory that are named like generation links together with the profile
+/// they belong to.
+fn find_generation_links(directory: &Path) -> io::Result<Vec<(Option<String>, PathBuf)>> {
+    let mut links = Vec::new();
+    for entry in fs::read_dir(directory)? {
+        let path = entry?.path();
+        match parse_link_name(&path) {
+            Ok((profile, _)) => links.push((profile, path)),
+            Err(e) if path.to_str().is_some_and(|p| p.ends_with("-link")) => {
+                log::warn!("Ignoring generation link: {e:#}");
+            }
+            Err(_) => (),
+        }
+    }
+    Ok(links)
 }
 
-/// Parse version number from a path.
+/// Parse the profile name and the version number from a path.
+///
+/// Expects a path in the format of "{profile}-{version}-link" (e.g. "system-2-link" or
+/// "system-profiles/my-server-12-link"). Because profile names can contain dashes, the version
+/// number is read from the end. The default system profile is returned as `None`.
 ///
-/// Expects a path in the format of "system-{version}-link".
-fn parse_version(path: impl AsRef<Path>) -> Result<u64> {
-    let generation_version = path
-        .as_ref()
+/// A profile in `system-profiles` cannot be named like the default system profile. Its images
+/// would overwrite the images of the default system profile.
+fn parse_link_name(path: impl AsRef<Path>) -> Result<(Option<String>, u64)> {
+    let path = path.as_ref();
+
+    let (profile, version) = path
         .file_name()
         .and_then(|x| x.to_str())
-        .and_then(|x| x.split('-').nth(1))
-        .and_then(|x| x.parse::<u64>().ok())
-        .with_context(|| format!("Failed to extract version from: {:?}", path.as_ref()))?;
+        .and_then(|x| x.strip_suffix("-link"))
+        .and_then(|x| x.rsplit_once('-'))
+        .with_context(|| {
+            format!("Expected a link in the format {{profile}}-{{version}}-link, got: {path:?}")
+        })?;
 
-    Ok(generation_version)
+    let version = version
+        .parse::<u64>()
+        .with_context(|| format!("Failed to extract version from: {path:?}"))?;
+
+    let profile = match profile {
+        "" => return Err(anyhow::anyhow!("Failed to extract profile from: {path:?}")),
+        DEFAULT_PROFILE if is_in_system_profiles(path) => {
+            return Err(anyhow::anyhow!(
+                "The profile name {DEFAULT_PROFILE:?} is reserved for the default system profile. Rename the profile of {path:?}."
+            ))
+        }
+        DEFAULT_PROFILE => None,
+        profile => Some(String::from(profile)),
+    };
+
+    Ok((profile, version))
+}
+
+/// Return whether a generation link is in the `system-profiles` directory.
+fn is_in_system_profiles(path: &Path) -> bool {
+    path.parent()
+        .and_then(|parent| parent.file_name())
+        .is_some_and(|name| name == SYSTEM_PROFILES_DIR)
 }
 
 #[cfg(test)]
@@ -143,7 +470,90 @@ mod tests {
     #[test]
     fn parse_version_correctly() {
         let path = Path::new("system-2-link");
-        let parsed_version = parse_version(path).unwrap();
+        let (profile, parsed_version) = parse_link_name(path).unwrap();
+        assert_eq!(profile, None);
         assert_eq!(parsed_version, 2,);
     }
+
+    #[test]
+    fn parse_profile_with_dashes_correctly() {
+        let path = Path::new("/nix/var/nix/profiles/system-profiles/my-server-12-link");
+        let (profile, parsed_version) = parse_link_name(path).unwrap();
+        assert_eq!(profile.as_deref(), Some("my-server"));
+        assert_eq!(parsed_version, 12);
+    }
+
+    #[test]
+    fn fail_to_parse_malformed_link_names() {
+        assert!(parse_link_name("system-link").is_err());
+        assert!(parse_link_name("system-2").is_err());
+        assert!(parse_link_name("system-two-link").is_err());
+        assert!(parse_link_name("-2-link").is_err());
+    }
+
+    #[test]
+    fn reject_profile_named_like_default_profile() {
+        assert!(parse_link_name("/nix/var/nix/profiles/system-profiles/system-2-link").is_err());
+        assert!(parse_link_name("/nix/var/nix/profiles/system-2-link").is_ok());
+    }
+
+    #[test]
+    fn display_build_time_with_marker() {
+        let time = OffsetDateTime::from_unix_timestamp(1672576496).unwrap();
+
+        assert_eq!(
+            BuildTime::Registered(time).to_string(),
+            "2023-01-01 12:34:56 UTC"
+        );
+        assert_eq!(
+            BuildTime::LinkModified(time).to_string(),
+            "2023-01-01 12:34:56 UTC (link mtime)"
+        );
+    }
+
+    #[test]
+    fn profile_path_is_next_to_link() -> Result<()> {
+        let default = GenerationLink::from_path("/nix/var/nix/profiles/system-2-link")?;
+        assert_eq!(
+            default.profile_path(),
+            PathBuf::from("/nix/var/nix/profiles/system")
+        );
+
+        let profile =
+            GenerationLink::from_path("/nix/var/nix/profiles/system-profiles/my-server-12-link")?;
+        assert_eq!(
+            profile.profile_path(),
+            PathBuf::from("/nix/var/nix/profiles/system-profiles/my-server")
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn discover_links_of_system_profiles() -> Result<()> {
+        let profiles = tempfile::tempdir()?;
+        let system_profiles = profiles.path().join(SYSTEM_PROFILES_DIR);
+        fs::create_dir(&system_profiles)?;
+        for path in [
+            profiles.path().join("system-1-link"),
+            profiles.path().join("system-2-link"),
+            profiles.path().join("default-1-link"),
+            profiles.path().join("system"),
+            system_profiles.join("my-server-3-link"),
+        ] {
+            fs::File::create(path)?;
+        }
+
+        assert_eq!(
+            discover_generation_links(profiles.path())?,
+            [
+                profiles.path().join("system-1-link"),
+                profiles.path().join("system-2-link"),
+                system_profiles.join("my-server-3-link"),
+            ]
+        );
+
+        fs::remove_dir_all(&system_profiles)?;
+        assert_eq!(discover_generation_links(profiles.path())?.len(), 2);
+        Ok(())
+    }
 }
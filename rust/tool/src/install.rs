@@ -1,128 +1,315 @@
-use std::collections::{BTreeMap, BTreeSet};
+use std::collections::{BTreeMap, HashMap};
+use std::fmt;
 use std::fs;
+use std::num::NonZeroUsize;
 use std::os::unix::prelude::PermissionsExt;
 use std::path::{Path, PathBuf};
 use std::process::Command;
 use std::string::ToString;
+use std::time::Instant;
 
 use anyhow::{anyhow, Context, Result};
+use bootspec::v1::BootSpecV1;
 use nix::unistd::sync;
 use tempfile::TempDir;
-
-use crate::esp::{EspGenerationPaths, EspPaths};
+use time::OffsetDateTime;
+
+use crate::credential::{self, CredentialKey};
+use crate::efi_tools::{self, EfiTool};
+use crate::efivars::{self, BootedGeneration};
+use crate::error::Error;
+use crate::esp::{self, EspGenerationPaths, EspPaths};
+use crate::extension::StubKind;
+use crate::fallback::{self, FallbackPolicy};
 use crate::gc::Roots;
-use crate::generation::{Generation, GenerationLink};
-use crate::os_release::OsRelease;
+use crate::generation::{BuildTime, Generation, GenerationLink};
+use crate::legacy_entries::{self, LegacyEntryPolicy};
+use crate::os_release::{OsRelease, TitleTemplate};
 use crate::pe;
+use crate::pin::{self, Pin};
+use crate::preflight;
+use crate::quarantine::Quarantine;
+use crate::random_seed;
+use crate::rescue::{self, RescueState};
+use crate::retention::{self, ImageCandidate, RetentionPolicy};
 use crate::signature::KeyPair;
-use crate::systemd::SystemdVersion;
-use crate::utils::{file_hash, SecureTempDirExt};
+use crate::store;
+use crate::systemd::{SystemdBootAction, SystemdBootPolicy, SystemdVersion};
+use crate::utils::{file_hash, parallel_map, tmpname, SecureTempDirExt};
 
+/// Installs lanzaboote to an ESP.
+///
+/// Use [`Installer::builder`] to create an installer.
 pub struct Installer {
-    broken_gens: BTreeSet<u64>,
+    /// Malformed generations that could not be read.
+    broken_gens: Vec<BrokenGeneration>,
     gc_roots: Roots,
     lanzaboote_stub: PathBuf,
+    lanzaboote_fat_stub: Option<PathBuf>,
+    /// The machine types of the stubs, read once per install.
+    stub_machines: HashMap<PathBuf, u16>,
     systemd: PathBuf,
+    nix: Option<PathBuf>,
     systemd_boot_loader_config: PathBuf,
     key_pair: KeyPair,
     configuration_limit: usize,
+    profile_configuration_limits: HashMap<String, usize>,
     esp_paths: EspPaths,
     generation_links: Vec<PathBuf>,
+    efi_tools: Vec<EfiTool>,
+    extra_initrds: Vec<PathBuf>,
+    initrd_secrets_credential_key: Option<CredentialKey>,
+    title_template: TitleTemplate,
+    quarantine_installs: Option<NonZeroUsize>,
+    strict: bool,
+    pins: Vec<Pin>,
+    efivarfs: PathBuf,
+    retention: RetentionPolicy,
+    systemd_boot_policy: SystemdBootPolicy,
+    fallback_policy: FallbackPolicy,
+    legacy_entry_policy: LegacyEntryPolicy,
+    rescue: Option<PathBuf>,
+    replace_rescue: bool,
 }
 
 impl Installer {
-    pub fn new(
-        lanzaboote_stub: PathBuf,
-        systemd: PathBuf,
-        systemd_boot_loader_config: PathBuf,
-        key_pair: KeyPair,
-        configuration_limit: usize,
-        esp: PathBuf,
-        generation_links: Vec<PathBuf>,
-    ) -> Self {
-        let mut gc_roots = Roots::new();
-        let esp_paths = EspPaths::new(esp);
-        gc_roots.extend(esp_paths.to_iter());
+    /// Create a builder for an installer.
+    pub fn builder() -> InstallerBuilder {
+        InstallerBuilder::default()
+    }
 
-        Self {
-            broken_gens: BTreeSet::new(),
-            gc_roots,
-            lanzaboote_stub,
-            systemd,
-            systemd_boot_loader_config,
-            key_pair,
-            configuration_limit,
-            esp_paths,
-            generation_links,
+    /// Install lanzaboote and the generations to the ESP and collect the garbage there.
+    pub fn install(&mut self) -> crate::Result<()> {
+        self.install_to_esp().map_err(Error::from_install)
+    }
+
+    /// Assemble and sign the lanzaboote image of a generation and write it to `output`.
+    ///
+    /// Unlike [`Installer::install`], this does not touch the ESP. A thin image expects the
+    /// kernel and the initrds at the locations on the ESP they are installed to by
+    /// [`Installer::install`].
+    pub fn assemble_image(&self, generation: &Generation, output: &Path) -> crate::Result<()> {
+        self.assemble_signed_image(generation, output)
+            .map_err(|e| Error::Image {
+                generation: generation.to_string(),
+                source: e.into(),
+            })
+    }
+
+    /// Verify that the installed binaries are signed with the public key of the installer.
+    ///
+    /// Checks systemd-boot, the fallback boot loader (unless it is a foreign boot loader that the
+    /// `leave` fallback policy leaves alone), the lanzaboote images of NixOS in
+    /// esp/EFI/Linux and the EFI tools. Returns an error for the first binary that is missing or
+    /// not signed with the public key.
+    pub fn verify(&self) -> crate::Result<()> {
+        let mut binaries = vec![self.esp_paths.systemd_boot.clone()];
+        // A foreign fallback boot loader that is left alone is not signed by lzbt.
+        let efi_fallback = &self.esp_paths.efi_fallback;
+        if !(self.fallback_policy == FallbackPolicy::Leave
+            && fallback::is_foreign_boot_loader(efi_fallback))
+        {
+            binaries.push(efi_fallback.clone());
+        }
+        binaries.extend(efi_binaries_in(&self.esp_paths.linux, "nixos-")?);
+        binaries.extend(efi_binaries_in(&self.esp_paths.tools, "")?);
+
+        for binary in binaries {
+            log::debug!("Verifying {binary:?}...");
+            if !binary.exists() {
+                return Err(Error::Io {
+                    path: binary,
+                    source: std::io::ErrorKind::NotFound.into(),
+                });
+            }
+            if !self.key_pair.verify(&binary) {
+                return Err(Error::InvalidSignature { path: binary });
+            }
         }
+
+        Ok(())
     }
 
-    pub fn install(&mut self) -> Result<()> {
+    fn install_to_esp(&mut self) -> Result<()> {
         log::info!("Installing Lanzaboote to {:?}...", self.esp_paths.esp);
 
-        let mut links = self
-            .generation_links
-            .iter()
-            .map(GenerationLink::from_path)
-            .collect::<Result<Vec<GenerationLink>>>()?;
-
-        // Sort the links by version. The links need to always be sorted to ensure the secrets of
-        // the latest generation are appended to the initrd when multiple generations point to the
-        // same initrd.
-        links.sort_by_key(|l| l.version);
-
-        // A configuration limit of 0 means there is no limit.
-        if self.configuration_limit > 0 {
-            // Only install the number of generations configured. Reverse the list to only take the
-            // latest generations and then, after taking them, reverse the list again so that the
-            // generations are installed from oldest to newest, i.e. from smallest to largest
-            // generation version.
-            links = links
-                .into_iter()
-                .rev()
-                .take(self.configuration_limit)
-                .rev()
-                .collect()
-        };
-        self.install_generations_from_links(&links)?;
-
-        self.install_systemd_boot()?;
-
-        if self.broken_gens.is_empty() {
-            log::info!("Collecting garbage...");
-            // Only collect garbage in these two directories. This way, no files that do not belong to
-            // the NixOS installation are deleted. Lanzatool takes full control over the esp/EFI/nixos
-            // directory and deletes ALL files that it doesn't know about. Dual- or multiboot setups
-            // that need files in this directory will NOT work.
-            self.gc_roots.collect_garbage(&self.esp_paths.nixos)?;
-            // The esp/EFI/Linux directory is assumed to be potentially shared with other distros.
-            // Thus, only files that start with "nixos-" are garbage collected (i.e. potentially
-            // deleted).
-            self.gc_roots
-                .collect_garbage_with_filter(&self.esp_paths.linux, |p| {
-                    p.file_name()
-                        .and_then(|n| n.to_str())
-                        .map_or(false, |n| n.starts_with("nixos-"))
-                })?;
-        } else {
-            // This might produce a ridiculous message if you have a lot of malformed generations.
-            let warning = indoc::formatdoc! {"
-                Garbage collection is disabled because you have malformed NixOS generations that do
-                not contain a readable bootspec document.
-
-                Remove the malformed generations to re-enable garbage collection with 
-                `nix-env --delete-generations {}` 
-            ", self.broken_gens.iter().map(ToString::to_string).collect::<Vec<String>>().join(" ")};
-            log::warn!("{warning}");
-        };
+        self.read_stub_machines();
+
+        let booted_generation = self.read_booted_generation();
+
+        let mut all_links = Vec::new();
+        for link in self.generation_links.iter().map(GenerationLink::from_path) {
+            let mut link = link?;
+            link.pinned = self.pins.iter().any(|pin| pin.matches(&link));
+            link.booted = booted_generation.as_ref().is_some_and(|booted| {
+                booted.profile == link.profile && booted.version == link.version
+            });
+            all_links.push(link);
+        }
+
+        for pin in &self.pins {
+            if !all_links.iter().any(|link| pin.matches(link)) {
+                log::warn!("Pinned {pin} does not exist and cannot be installed.");
+            }
+        }
+        if let Some(booted_generation) = &booted_generation {
+            if !all_links.iter().any(|link| link.booted) {
+                log::warn!(
+                    "The booted generation {} does not exist anymore and cannot be kept installed.",
+                    booted_generation.version
+                );
+            }
+        }
+
+        // The build times are needed to decide which generations to keep.
+        self.read_registration_times(&mut all_links);
+
+        let mut links_by_profile: BTreeMap<Option<String>, Vec<GenerationLink>> = BTreeMap::new();
+        for link in all_links {
+            links_by_profile
+                .entry(link.profile.clone())
+                .or_default()
+                .push(link);
+        }
+
+        let now = OffsetDateTime::now_utc();
+        let mut links = Vec::new();
+        for (profile, mut profile_links) in links_by_profile {
+            // Sort the links by version. When initrd secrets are appended to the initrd (i.e. they
+            // are not shipped as credentials), the links need to always be sorted to ensure the
+            // secrets of the latest generation are appended to the initrd when multiple
+            // generations point to the same initrd.
+            profile_links.sort_by_key(|l| l.version);
+
+            let configuration_limit = profile
+                .as_ref()
+                .and_then(|p| self.profile_configuration_limits.get(p))
+                .copied()
+                .unwrap_or(self.configuration_limit);
+
+            links.extend(
+                self.retention
+                    .select_links(profile_links, configuration_limit, now),
+            );
+        }
+        let rescue = self.read_rescue_generation();
+        self.install_generations_from_links(&links, rescue)?;
+
+        self.install_systemd_boot()
+            .map_err(|e| Error::SystemdBoot(e.into()))?;
+
+        self.install_random_seed()?;
+
+        self.install_efi_tools()?;
+
+        // Keep the files of malformed generations so that they are not deleted because lzbt
+        // cannot read them. This is a safeguard against catastrophic failure in case of unhandled
+        // upstream changes to NixOS. All other files are still garbage collected.
+        self.add_broken_generation_gc_roots();
+
+        log::info!("Collecting garbage...");
+        let start = Instant::now();
+        // Only collect garbage in these two directories. This way, no files that do not belong to
+        // the NixOS installation are deleted. Lanzatool takes full control over the esp/EFI/nixos
+        // directory and deletes ALL files that it doesn't know about. Dual- or multiboot setups
+        // that need files in this directory will NOT work unless the quarantine is enabled. Then,
+        // unknown files are only deleted after a number of successful installs.
+        match self.quarantine_installs {
+            Some(installs) => {
+                let quarantine = Quarantine::open(&self.esp_paths.nixos, installs)
+                    .context("Failed to open quarantine.")?;
+                self.gc_roots
+                    .collect_garbage_with_quarantine(&self.esp_paths.nixos, quarantine)?;
+            }
+            None => self.gc_roots.collect_garbage(&self.esp_paths.nixos)?,
+        }
+        // The esp/EFI/Linux directory is assumed to be potentially shared with other distros.
+        // Thus, only files that start with "nixos-" are garbage collected (i.e. potentially
+        // deleted).
+        self.gc_roots
+            .collect_garbage_with_filter(&self.esp_paths.linux, |p| {
+                p.file_name()
+                    .and_then(|n| n.to_str())
+                    .map_or(false, |n| n.starts_with("nixos-"))
+            })?;
+        // The esp/loader/entries directory belongs to systemd-boot and might contain entries
+        // from other distros. Only the entries lzbt creates for EFI tools are garbage
+        // collected.
+        self.gc_roots
+            .collect_garbage_with_filter(&self.esp_paths.loader_entries, |p| {
+                p.file_name()
+                    .and_then(|n| n.to_str())
+                    .is_some_and(|n| n.starts_with(efi_tools::LOADER_ENTRY_PREFIX))
+            })?;
+        // Type #1 entries that boot NixOS are left over from plain systemd-boot. They bypass the
+        // hashes embedded into the lanzaboote images and thus Secure Boot.
+        legacy_entries::neutralize_legacy_entries(
+            &self.esp_paths.loader_entries,
+            self.legacy_entry_policy,
+        )?;
+        log::info!("Collected garbage in {:.2?}.", start.elapsed());
+
+        if !self.broken_gens.is_empty() {
+            self.warn_about_broken_generations();
+        }
 
         log::info!("Successfully installed Lanzaboote.");
         Ok(())
     }
 
+    /// Read the generation the system is currently booted from.
+    ///
+    /// This generation is always kept installed so that there is a known-good generation to
+    /// return to if switching to a new generation fails. Failing to read it is not an error.
+    fn read_booted_generation(&self) -> Option<BootedGeneration> {
+        match BootedGeneration::from_efivarfs(&self.efivarfs) {
+            Ok(Some(booted_generation)) => {
+                log::debug!("Booted from {booted_generation:?}.");
+                Some(booted_generation)
+            }
+            Ok(None) => {
+                log::debug!(
+                    "Failed to find the booted generation in {:?}.",
+                    self.efivarfs
+                );
+                None
+            }
+            Err(e) => {
+                log::warn!("Failed to read the booted generation: {e:#}");
+                None
+            }
+        }
+    }
+
+    /// Use the time the generations were registered in the Nix store as their build time.
+    ///
+    /// Without a Nix installation or when the registration times cannot be read, the build times
+    /// stay approximated from the modification times of the generation links.
+    fn read_registration_times(&self, links: &mut [GenerationLink]) {
+        let Some(nix) = &self.nix else {
+            return;
+        };
+
+        let toplevels = links
+            .iter()
+            .map(|link| fs::canonicalize(&link.path).unwrap_or_else(|_| link.path.clone()))
+            .collect::<Vec<PathBuf>>();
+
+        match store::registration_times(nix, &toplevels) {
+            Ok(registration_times) => {
+                for (link, toplevel) in links.iter_mut().zip(&toplevels) {
+                    if let Some(registration_time) = registration_times.get(toplevel) {
+                        link.build_time = Some(BuildTime::Registered(*registration_time));
+                    }
+                }
+            }
+            Err(e) => log::warn!("Failed to read the build times of the generations: {e:#}"),
+        }
+    }
+
     /// Install all generations from the provided `GenerationLinks`.
     ///
-    /// Iterates over the links twice:
+    /// Reads the generations once and then iterates over them twice:
     ///     (1) First, building all unsigned artifacts and storing the mapping from source to
     ///     destination in `GenerationArtifacts`. `GenerationArtifacts` ensures that there are no
     ///     duplicate destination paths and thus ensures that the hashes embedded in the lanzaboote
@@ -134,225 +321,848 @@ impl Installer {
     /// This way, in the second step, all paths and thus all hashes for all generations are already
     /// known. The signed files can now be constructed with known good hashes **across** all
     /// generations.
-    fn install_generations_from_links(&mut self, links: &[GenerationLink]) -> Result<()> {
+    ///
+    /// Within each step, the generations are built in parallel. The time each step takes is logged.
+    ///
+    /// The rescue generation, if any, is installed together with the regular generations. Its
+    /// record is only written after all files are installed.
+    fn install_generations_from_links(
+        &mut self,
+        links: &[GenerationLink],
+        rescue: Option<(Generation, RescueState)>,
+    ) -> Result<()> {
+        let mut generations = self.read_generations(links)?;
+        let rescue_state = rescue.map(|(generation, state)| {
+            generations.push(generation);
+            state
+        });
+
         // This struct must live for the entire lifetime of this function so that the contained
         // tempdir does not go out of scope and thus does not get deleted.
         let mut generation_artifacts =
             GenerationArtifacts::new().context("Failed to create GenerationArtifacts.")?;
 
-        self.build_generation_artifacts_from_links(
+        let start = Instant::now();
+        self.build_generation_artifacts(
             &mut generation_artifacts,
-            links,
+            &generations,
             Self::build_unsigned_generation_artifacts,
         )
         .context("Failed to build unsigned generation artifacts.")?;
+        log::info!("Built unsigned artifacts in {:.2?}.", start.elapsed());
 
-        self.build_generation_artifacts_from_links(
+        let start = Instant::now();
+        self.build_generation_artifacts(
             &mut generation_artifacts,
-            links,
+            &generations,
             Self::build_signed_generation_artifacts,
         )
         .context("Failed to build signed generation artifacts.")?;
+        log::info!("Built signed artifacts in {:.2?}.", start.elapsed());
 
+        let start = Instant::now();
         generation_artifacts
             .install(&self.key_pair)
             .context("Failed to install files.")?;
+        log::info!("Signed and installed files in {:.2?}.", start.elapsed());
 
         // Sync files to persistent storage. This may improve the
         // chance of a consistent boot directory in case the system
         // crashes.
         sync();
 
+        if let Some(rescue_state) = rescue_state {
+            rescue_state
+                .write(&self.esp_paths.rescue_state)
+                .context("Failed to record the generation of the rescue image.")?;
+            log::info!("Installed rescue image from {:?}.", rescue_state.toplevel);
+        }
+
         Ok(())
     }
 
-    /// Build all generation artifacts from a list of `GenerationLink`s.
+    /// Refresh the random seed on the ESP and create the system token.
     ///
-    /// This function accepts a closure to build the generation artifacts for a single generation.
-    fn build_generation_artifacts_from_links<F>(
-        &mut self,
-        generation_artifacts: &mut GenerationArtifacts,
-        links: &[GenerationLink],
-        mut build_generation_artifacts: F,
-    ) -> Result<()>
-    where
-        F: FnMut(&mut Self, &Generation, &mut GenerationArtifacts) -> Result<()>,
-    {
-        let generations = links
-            .iter()
-            .filter_map(|link| {
-                let generation_result = Generation::from_link(link)
-                    .with_context(|| format!("Failed to build generation from link: {link:?}"));
-
-                // Ignore failing to read a generation so that old malformed generations do not stop
-                // lzbt from working.
-                if generation_result.is_err() {
-                    // If there is ANY malformed generation present, completely disable all garbage
-                    // collection to protect the old generations from being deleted. The user has
-                    // to manually intervene by getting rid of the old generations to re-enable
-                    // garbage collection. This safeguard against catastrophic failure in case of
-                    // unhandled upstream changes to NixOS.
-                    self.broken_gens.insert(link.version);
+    /// systemd-boot derives the seed it passes to the OS from both. This replaces what `bootctl
+    /// install` and `bootctl random-seed` do for plain systemd-boot. Failing to create the system
+    /// token is not an error because the system might not be booted via UEFI.
+    fn install_random_seed(&self) -> Result<()> {
+        random_seed::refresh_random_seed(&self.esp_paths.random_seed)
+            .context("Failed to refresh the random seed on the ESP.")?;
+
+        if let Err(e) = random_seed::ensure_system_token(&self.efivarfs) {
+            log::warn!("Failed to create the system token: {e:#}");
+        }
+
+        Ok(())
+    }
+
+    /// Read the generation to build the rescue image from.
+    ///
+    /// Returns `None` if there is no rescue image to build. An installed rescue image is then kept
+    /// together with the files it references. Failing to read or check the rescue generation is
+    /// not an error so that a broken rescue target does not prevent installing the regular
+    /// generations.
+    fn read_rescue_generation(&mut self) -> Option<(Generation, RescueState)> {
+        let installed = self.esp_paths.rescue_image.exists();
+
+        let rescue = self.rescue.clone().and_then(|target| {
+            self.try_read_rescue_generation(&target, installed)
+                .unwrap_or_else(|e| {
+                    log::warn!("Failed to read the rescue generation {target:?}: {e:#}");
+                    None
+                })
+        });
+
+        if rescue.is_none() && installed {
+            match rescue::referenced_files(&self.esp_paths) {
+                Ok(files) => self.gc_roots.extend(&files),
+                Err(e) => log::warn!("Failed to read the files of the rescue image: {e:#}"),
+            }
+        }
+
+        rescue
+    }
+
+    /// Read the rescue generation if it replaces the installed rescue image.
+    ///
+    /// The installed rescue image is only replaced by a newer generation, if replacing it was
+    /// requested explicitly or if it is not signed with the public key of the installer.
+    fn try_read_rescue_generation(
+        &self,
+        target: &Path,
+        installed: bool,
+    ) -> Result<Option<(Generation, RescueState)>> {
+        let mut link = rescue::rescue_link(target);
+        self.read_registration_times(std::slice::from_mut(&mut link));
+        let state = rescue::rescue_state(&link)?;
+
+        // An installed rescue image that is not signed with the current key would not boot. It is
+        // rebuilt even if it belongs to the same generation.
+        let rescue_image = &self.esp_paths.rescue_image;
+        let is_signed = installed && self.key_pair.verify(rescue_image);
+        if installed && !is_signed {
+            log::warn!("{rescue_image:?} is not signed. Rebuilding it...");
+        }
+
+        if is_signed && !self.replace_rescue {
+            if let Some(installed_state) = RescueState::read(&self.esp_paths.rescue_state)? {
+                if !installed_state.is_replaced_by(&state) {
+                    if installed_state.toplevel != state.toplevel {
+                        log::info!(
+                            "Keeping the rescue image from {:?} because {:?} is not newer. Replace it explicitly to install an older rescue image.",
+                            installed_state.toplevel,
+                            state.toplevel
+                        );
+                    }
+                    return Ok(None);
                 }
+            }
+        }
 
-                generation_result.ok()
+        let generation = Generation::from_link(&link, self.strict)?.into_rescue();
+        // Check the rescue generation here so that a broken one keeps the installed rescue image
+        // instead of aborting the install.
+        self.check_generation(&generation)?;
+        Ok(Some((generation, state)))
+    }
+
+    /// Read the generations from a list of `GenerationLink`s.
+    ///
+    /// Generations that cannot be read are recorded as broken generations. Excluded generations
+    /// and specialisations are skipped, as are the generations and specialisations dropped by the
+    /// image based retention rules. The specialisations of each generation directly follow the
+    /// generation in the returned list.
+    fn read_generations(&mut self, links: &[GenerationLink]) -> Result<Vec<Generation>> {
+        let mut generations = Vec::new();
+        for link in links {
+            match Generation::from_link(link, self.strict) {
+                Ok(generation) => generations.push(generation),
+                // Ignore failing to read a generation so that old malformed generations do not
+                // stop lzbt from working.
+                Err(error) => self.broken_gens.push(BrokenGeneration {
+                    profile_path: link.profile_path(),
+                    profile: link.profile.clone(),
+                    version: link.version,
+                    path: link.path.clone(),
+                    error,
+                }),
+            }
+        }
+
+        let generations = generations
+            .into_iter()
+            .filter(|generation| {
+                if generation.spec.lanzaboote.exclude {
+                    log::info!("Excluding generation {generation} from the boot menu.");
+                }
+                !generation.spec.lanzaboote.exclude
             })
             .collect::<Vec<Generation>>();
 
         if generations.is_empty() {
             // We can't continue, because we would remove all boot entries, if we did.
-            return Err(anyhow!("No bootable generations found! Aborting to avoid unbootable system. Please check for Lanzaboote updates!"));
+            return Err(Error::NoBootableGenerations.into());
+        }
+
+        // Rank the generations of each profile from newest (0) to oldest. The generations are
+        // sorted by version within each profile.
+        let mut ranks = vec![0; generations.len()];
+        let mut newer_generations: HashMap<Option<&str>, usize> = HashMap::new();
+        for (rank, generation) in ranks.iter_mut().zip(&generations).rev() {
+            let newer = newer_generations.entry(generation.profile()).or_default();
+            *rank = *newer;
+            *newer += 1;
         }
 
-        for generation in generations {
-            build_generation_artifacts(self, &generation, generation_artifacts)
-                .context("Failed to build generation artifacts.")?;
+        let mut candidates = Vec::new();
+        for (generation, rank) in generations.into_iter().zip(ranks) {
+            if !self.retention.installs_specialisations(rank) {
+                if !generation.spec.bootspec.specialisations.is_empty() {
+                    log::info!(
+                        "Dropping the specialisations of {}: exceeds the specialisations limit.",
+                        retention::describe(generation.profile(), generation.version())
+                    );
+                }
+                candidates.push((generation, Vec::new(), rank));
+                continue;
+            }
 
+            let mut specialised_generations = Vec::new();
             for (name, bootspec) in &generation.spec.bootspec.specialisations {
                 let specialised_generation = generation.specialise(name, bootspec)?;
 
-                build_generation_artifacts(self, &specialised_generation, generation_artifacts)
-                    .context("Failed to build generation artifacts for specialisation.")?;
+                if specialised_generation.spec.lanzaboote.exclude {
+                    log::info!(
+                        "Excluding specialisation {name} of generation {generation} from the boot menu."
+                    );
+                    continue;
+                }
+
+                specialised_generations.push(specialised_generation);
+            }
+
+            candidates.push((generation, specialised_generations, rank));
+        }
+
+        // The latest generation of each profile is never dropped because it is the one being
+        // switched to.
+        let image_candidates = candidates
+            .iter()
+            .map(
+                |(generation, specialised_generations, rank)| ImageCandidate {
+                    profile: generation.profile().map(String::from),
+                    version: generation.version(),
+                    build_time: generation.build_time().map(|t| t.time()),
+                    images: 1 + specialised_generations.len(),
+                    protected: generation.is_pinned() || generation.is_booted() || *rank == 0,
+                },
+            )
+            .collect::<Vec<ImageCandidate>>();
+        let kept = self.retention.select_images(&image_candidates);
+
+        let mut generations_to_build = Vec::new();
+        for ((generation, specialised_generations, _), kept) in candidates.into_iter().zip(kept) {
+            if kept {
+                generations_to_build.push(generation);
+                generations_to_build.extend(specialised_generations);
             }
         }
 
+        Ok(generations_to_build)
+    }
+
+    /// Build the generation artifacts of all generations.
+    ///
+    /// This function accepts a closure to build the generation artifacts for a single generation.
+    /// The closure is called for all generations in parallel. Afterwards, the artifacts of the
+    /// generations are merged into `GenerationArtifacts` in the order of the generations, so that
+    /// the result is the same as if they had been built one after another.
+    fn build_generation_artifacts<F>(
+        &mut self,
+        generation_artifacts: &mut GenerationArtifacts,
+        generations: &[Generation],
+        build_generation_artifacts: F,
+    ) -> Result<()>
+    where
+        F: Fn(&Self, &Generation, &GenerationArtifacts) -> Result<SingleGenerationArtifacts> + Sync,
+    {
+        let installer: &Self = self;
+        let shared_generation_artifacts: &GenerationArtifacts = generation_artifacts;
+        let results = parallel_map(generations, |generation| {
+            build_generation_artifacts(installer, generation, shared_generation_artifacts)
+                .with_context(|| match generation.is_specialised() {
+                    Some(_) => "Failed to build generation artifacts for specialisation.",
+                    None => "Failed to build generation artifacts.",
+                })
+        });
+
+        for artifacts in results {
+            let artifacts = artifacts?;
+            self.gc_roots.extend(&artifacts.gc_roots);
+            generation_artifacts.extend(artifacts.files);
+        }
+
         Ok(())
     }
 
     /// Build the unsigned generation artifacts for a single generation.
     ///
-    /// Stores the mapping from source to destination for the artifacts in the provided
-    /// `GenerationArtifacts`. Does not install any files to the ESP.
+    /// Returns the mapping from source to destination for the artifacts. Does not install any
+    /// files to the ESP.
     ///
     /// Because this function already has an complete view of all required paths in the ESP for
-    /// this generation, it stores all paths as GC roots.
+    /// this generation, it returns all paths as GC roots.
     fn build_unsigned_generation_artifacts(
-        &mut self,
+        &self,
         generation: &Generation,
-        generation_artifacts: &mut GenerationArtifacts,
-    ) -> Result<()> {
+        generation_artifacts: &GenerationArtifacts,
+    ) -> Result<SingleGenerationArtifacts> {
         let tempdir = &generation_artifacts.tempdir;
+        let mut artifacts = SingleGenerationArtifacts::default();
 
         let bootspec = &generation.spec.bootspec.bootspec;
 
-        let esp_gen_paths = EspGenerationPaths::new(&self.esp_paths, generation)?;
-        self.gc_roots.extend(esp_gen_paths.to_iter());
+        let extra_initrds = self.extra_initrds_of(generation);
+        let esp_gen_paths = EspGenerationPaths::new(&self.esp_paths, generation, &extra_initrds)?;
+
+        if let (Some(initrd_secrets_script), Some(key)) =
+            (&bootspec.initrd_secrets, self.initrd_secrets_credential_key)
+        {
+            // The secrets are shipped as an encrypted credential next to the lanzaboote image
+            // instead of being appended to the initrd. The stub picks it up at boot and passes it
+            // to the initrd. This way, the initrd on the ESP is exactly the initrd from the store
+            // and no secrets end up on the ESP in plain text.
+            let secrets_location = tempdir
+                .write_secure_file(b"")
+                .context("Failed to create tempfile for initrd secrets.")?;
+            append_initrd_secrets(initrd_secrets_script, &secrets_location)?;
+
+            let credential_location = tempdir.path().join(tmpname());
+            credential::encrypt(
+                &self.systemd,
+                key,
+                credential::INITRD_SECRETS_CREDENTIAL_NAME,
+                &secrets_location,
+                &credential_location,
+            )
+            .context("Failed to encrypt initrd secrets.")?;
+
+            artifacts.add_gc_roots([
+                &esp_gen_paths.credentials,
+                &esp_gen_paths.initrd_secrets_credential,
+            ]);
+            artifacts.add_unsigned(
+                &credential_location,
+                &esp_gen_paths.initrd_secrets_credential,
+            );
+        }
 
-        let initrd_content = fs::read(
-            bootspec
-                .initrd
-                .as_ref()
-                .context("Lanzaboote does not support missing initrd yet")?,
-        )?;
-        let initrd_location = tempdir
-            .write_secure_file(initrd_content)
-            .context("Failed to copy initrd to tempfile.")?;
-        if let Some(initrd_secrets_script) = &bootspec.initrd_secrets {
-            append_initrd_secrets(initrd_secrets_script, &initrd_location)?;
+        if generation.spec.lanzaboote.stub == StubKind::Fat {
+            // The kernel and the initrds are embedded into the fat image. They are not installed
+            // to the ESP separately.
+            artifacts.add_gc_roots([&esp_gen_paths.lanzaboote_image]);
+            return Ok(artifacts);
         }
 
+        artifacts.add_gc_roots(esp_gen_paths.to_iter());
+
+        let initrd_location = self.initrd_with_secrets(tempdir, bootspec)?;
+
         // The initrd and kernel don't need to be signed. The stub has their hashes embedded and
         // will refuse loading on hash mismatches.
         //
         // The kernel is not signed because systemd-boot could be tricked into loading the signed
         // kernel in combination with an malicious unsigned initrd. This could be achieved because
         // systemd-boot also honors the type #1 boot loader specification.
-        generation_artifacts.add_unsigned(&bootspec.kernel, &esp_gen_paths.kernel);
-        generation_artifacts.add_unsigned(&initrd_location, &esp_gen_paths.initrd);
+        artifacts.add_unsigned(&bootspec.kernel, &esp_gen_paths.kernel);
+        for (extra_initrd, extra_initrd_esp_path) in
+            extra_initrds.iter().zip(&esp_gen_paths.extra_initrds)
+        {
+            artifacts.add_unsigned(extra_initrd, extra_initrd_esp_path);
+        }
+        if let (Some(initrd_location), Some(initrd_esp_path)) =
+            (&initrd_location, &esp_gen_paths.initrd)
+        {
+            artifacts.add_unsigned(initrd_location, initrd_esp_path);
+        }
 
-        Ok(())
+        Ok(artifacts)
     }
 
     /// Build the signed generation artifacts for a single generation.
     ///
-    /// Stores the mapping from source to destination for the artifacts in the provided
-    /// `GenerationArtifacts`. Does not install any files to the ESP.
+    /// Returns the mapping from source to destination for the artifacts. Does not install any
+    /// files to the ESP.
     ///
     /// This function expects an already pre-populated `GenerationArtifacts`. It can only be called
     /// if ALL unsigned artifacts are already built and stored in `GenerationArtifacts`. More
     /// specifically, this function can only be called after `build_unsigned_generation_artifacts`
     /// has been executed.
     fn build_signed_generation_artifacts(
-        &mut self,
+        &self,
         generation: &Generation,
-        generation_artifacts: &mut GenerationArtifacts,
-    ) -> Result<()> {
+        generation_artifacts: &GenerationArtifacts,
+    ) -> Result<SingleGenerationArtifacts> {
         let tempdir = &generation_artifacts.tempdir;
 
         let bootspec = &generation.spec.bootspec.bootspec;
 
-        let esp_gen_paths = EspGenerationPaths::new(&self.esp_paths, generation)?;
+        let extension = &generation.spec.lanzaboote;
 
-        let kernel_cmdline =
-            assemble_kernel_cmdline(&bootspec.init, bootspec.kernel_params.clone());
+        let extra_initrds = self.extra_initrds_of(generation);
+        let esp_gen_paths = EspGenerationPaths::new(&self.esp_paths, generation, &extra_initrds)?;
 
-        let os_release = OsRelease::from_generation(generation)
-            .context("Failed to build OsRelease from generation.")?;
-        let os_release_path = tempdir
-            .write_secure_file(os_release.to_string().as_bytes())
-            .context("Failed to write os-release file.")?;
+        let kernel_cmdline = assemble_kernel_cmdline(
+            &bootspec.init,
+            bootspec
+                .kernel_params
+                .iter()
+                .chain(&extension.kernel_params)
+                .cloned()
+                .collect(),
+        );
+
+        let stub = self.stub_of(generation)?;
+        // Nothing is written to the ESP before the artifacts of all generations are built. Thus,
+        // a broken generation aborts the install before the ESP is touched. The rescue generation
+        // already passed the check when it was read.
+        if !generation.is_rescue() {
+            self.check_generation(generation)?;
+        }
 
-        let kernel_path: &Path = generation_artifacts
-            .files
-            .get(&esp_gen_paths.kernel)
-            .context("Failed to retrieve kernel path from GenerationArtifacts.")?
-            .into();
+        let os_release_path = match &extension.os_release {
+            Some(os_release_path) => os_release_path.clone(),
+            None => {
+                let os_release = OsRelease::from_generation(generation, &self.title_template)
+                    .context("Failed to build OsRelease from generation.")?;
+                tempdir
+                    .write_secure_file(os_release.to_string().as_bytes())
+                    .context("Failed to write os-release file.")?
+            }
+        };
 
-        let initrd_path = generation_artifacts
-            .files
-            .get(&esp_gen_paths.initrd)
-            .context("Failed to retrieve initrd path from GenerationArtifacts.")?
-            .into();
-
-        let lanzaboote_image = pe::lanzaboote_image(
-            tempdir,
-            &self.lanzaboote_stub,
-            &os_release_path,
-            &kernel_cmdline,
-            kernel_path,
-            initrd_path,
-            &esp_gen_paths,
-            &self.esp_paths.esp,
+        let lanzaboote_image = match extension.stub {
+            StubKind::Thin => {
+                let kernel_path: &Path = generation_artifacts
+                    .files
+                    .get(&esp_gen_paths.kernel)
+                    .context("Failed to retrieve kernel path from GenerationArtifacts.")?
+                    .into();
+
+                let initrd_paths = esp_gen_paths
+                    .initrds()
+                    .map(|initrd| {
+                        generation_artifacts
+                            .files
+                            .get(initrd)
+                            .map(Into::into)
+                            .with_context(|| {
+                                format!(
+                                    "Failed to retrieve initrd path {initrd:?} from GenerationArtifacts."
+                                )
+                            })
+                    })
+                    .collect::<Result<Vec<&Path>>>()?;
+
+                pe::lanzaboote_image(
+                    tempdir,
+                    stub,
+                    &os_release_path,
+                    &kernel_cmdline,
+                    kernel_path,
+                    &initrd_paths,
+                    &esp_gen_paths,
+                    &self.esp_paths.esp,
+                    &extension.sections,
+                )
+                .context("Failed to assemble lanzaboote image.")?
+            }
+            StubKind::Fat => {
+                let initrd_location = self.initrd_with_secrets(tempdir, bootspec)?;
+                let initrd_paths = extra_initrds
+                    .iter()
+                    .chain(&initrd_location)
+                    .map(PathBuf::as_path)
+                    .collect::<Vec<&Path>>();
+                let initrd = if initrd_paths.is_empty() {
+                    None
+                } else {
+                    Some(
+                        concatenate_initrds(tempdir, &initrd_paths)
+                            .context("Failed to concatenate initrds.")?,
+                    )
+                };
+
+                pe::lanzaboote_fat_image(
+                    tempdir,
+                    stub,
+                    &os_release_path,
+                    &kernel_cmdline,
+                    &bootspec.kernel,
+                    initrd.as_deref(),
+                    &extension.sections,
+                )
+                .context("Failed to assemble fat lanzaboote image.")?
+            }
+        };
+
+        let mut artifacts = SingleGenerationArtifacts::default();
+        artifacts.add_signed(&lanzaboote_image, &esp_gen_paths.lanzaboote_image);
+
+        Ok(artifacts)
+    }
+
+    /// Prepare taking over the fallback boot loader.
+    ///
+    /// Returns whether systemd-boot may be installed as the fallback boot loader. If the fallback
+    /// boot loader is systemd-boot or does not exist, it may always be installed. Otherwise, the
+    /// fallback policy decides and the foreign boot loader is backed up if requested.
+    fn take_over_fallback(&self) -> Result<bool> {
+        let efi_fallback = &self.esp_paths.efi_fallback;
+        if !fallback::is_foreign_boot_loader(efi_fallback) {
+            return Ok(true);
+        }
+
+        match self.fallback_policy {
+            FallbackPolicy::TakeOver => {
+                log::warn!(
+                    "Replacing the fallback boot loader {efi_fallback:?}, which is not systemd-boot..."
+                );
+            }
+            FallbackPolicy::Leave => {
+                log::info!(
+                    "Leaving the fallback boot loader {efi_fallback:?} alone because it is not systemd-boot."
+                );
+                return Ok(false);
+            }
+            FallbackPolicy::Backup => {
+                let backup = &self.esp_paths.efi_fallback_backup;
+                log::warn!(
+                    "Backing up the fallback boot loader {efi_fallback:?}, which is not systemd-boot, to {backup:?}..."
+                );
+                force_install(efi_fallback, backup).with_context(|| {
+                    format!("Failed to back up fallback boot loader {efi_fallback:?}")
+                })?;
+            }
+        }
+
+        Ok(true)
+    }
+
+    /// Return the stub a generation is booted by.
+    fn stub_of(&self, generation: &Generation) -> Result<&PathBuf> {
+        match generation.spec.lanzaboote.stub {
+            StubKind::Thin => Ok(&self.lanzaboote_stub),
+            StubKind::Fat => self.lanzaboote_fat_stub.as_ref().context(
+                "Generation requests a fat stub but LANZABOOTE_FAT_STUB env variable is not set",
+            ),
+        }
+    }
+
+    /// Read the machine types of the stubs.
+    ///
+    /// A stub that cannot be read is not an error here. Checking a generation that is booted by it
+    /// fails instead.
+    fn read_stub_machines(&mut self) {
+        let stubs = [
+            Some(&self.lanzaboote_stub),
+            self.lanzaboote_fat_stub.as_ref(),
+        ]
+        .into_iter()
+        .flatten()
+        .cloned()
+        .collect::<Vec<PathBuf>>();
+        for stub in stubs {
+            if let Ok(machine) = preflight::stub_machine(&stub) {
+                self.stub_machines.insert(stub, machine);
+            }
+        }
+    }
+
+    /// Check the kernel, the initrds and the command line of a generation before its image is
+    /// built.
+    ///
+    /// A failed check is reported as [`Error::Preflight`].
+    fn check_generation(&self, generation: &Generation) -> Result<()> {
+        let bootspec = &generation.spec.bootspec.bootspec;
+        let extra_initrds = self.extra_initrds_of(generation);
+        let kernel_cmdline = assemble_kernel_cmdline(
+            &bootspec.init,
+            bootspec
+                .kernel_params
+                .iter()
+                .chain(&generation.spec.lanzaboote.kernel_params)
+                .cloned()
+                .collect(),
+        );
+        let stub = self.stub_of(generation)?;
+        let machine = match self.stub_machines.get(stub) {
+            Some(machine) => *machine,
+            None => preflight::stub_machine(stub)?,
+        };
+        let initrds = extra_initrds
+            .iter()
+            .chain(&bootspec.initrd)
+            .map(PathBuf::as_path)
+            .collect::<Vec<&Path>>();
+
+        preflight::check_generation(
+            machine,
+            &bootspec.kernel,
+            &initrds,
+            &kernel_cmdline.join(" "),
         )
-        .context("Failed to assemble lanzaboote image.")?;
+        .map_err(|e| {
+            Error::Preflight {
+                generation: generation.to_string(),
+                source: e.into(),
+            }
+            .into()
+        })
+    }
+
+    /// Assemble the lanzaboote image of a single generation and sign it to `output`.
+    fn assemble_signed_image(&self, generation: &Generation, output: &Path) -> Result<()> {
+        let mut generation_artifacts =
+            GenerationArtifacts::new().context("Failed to create GenerationArtifacts.")?;
+
+        let unsigned_artifacts =
+            self.build_unsigned_generation_artifacts(generation, &generation_artifacts)?;
+        generation_artifacts.extend(unsigned_artifacts.files);
+        let signed_artifacts =
+            self.build_signed_generation_artifacts(generation, &generation_artifacts)?;
+        let (_, lanzaboote_image) = signed_artifacts
+            .files
+            .first()
+            .context("Failed to retrieve lanzaboote image from the generation artifacts.")?;
+
+        install_signed(&self.key_pair, lanzaboote_image.into(), output)
+    }
 
-        generation_artifacts.add_signed(&lanzaboote_image, &esp_gen_paths.lanzaboote_image);
+    /// Add the paths on the ESP that plausibly belong to the broken generations to the GC roots.
+    fn add_broken_generation_gc_roots(&mut self) {
+        for broken_gen in &self.broken_gens {
+            let paths = esp::broken_generation_paths(
+                &self.esp_paths,
+                broken_gen.profile.as_deref(),
+                broken_gen.version,
+                &broken_gen.path,
+            );
+            for path in &paths {
+                log::debug!("Keeping {path:?} of malformed generation {}.", broken_gen);
+            }
+            self.gc_roots.extend(&paths);
+        }
+    }
+
+    /// Warn about the broken generations and explain how to remove them.
+    fn warn_about_broken_generations(&self) {
+        let generations = self
+            .broken_gens
+            .iter()
+            .map(|broken_gen| {
+                format!(
+                    "- {broken_gen} ({}): {:#}",
+                    broken_gen.path.display(),
+                    broken_gen.error
+                )
+            })
+            .collect::<Vec<String>>()
+            .join("\n");
+
+        let mut versions_by_profile: BTreeMap<&Path, Vec<String>> = BTreeMap::new();
+        for broken_gen in &self.broken_gens {
+            versions_by_profile
+                .entry(&broken_gen.profile_path)
+                .or_default()
+                .push(broken_gen.version.to_string());
+        }
+        // This might produce a ridiculous message if you have a lot of malformed generations.
+        let commands = versions_by_profile
+            .iter()
+            .map(|(profile_path, versions)| {
+                format!(
+                    "`nix-env --profile {} --delete-generations {}`",
+                    profile_path.display(),
+                    versions.join(" ")
+                )
+            })
+            .collect::<Vec<String>>()
+            .join("\n");
+
+        let warning = indoc::formatdoc! {"
+            The following NixOS generations are malformed and do not contain a readable bootspec
+            document. They are not installed.
+            {generations}
+
+            Their files on the ESP are kept, all other files are garbage collected. Remove the
+            malformed generations to garbage collect their files with
+            {commands}
+        "};
+        log::warn!("{warning}");
+    }
+
+    /// Return the extra initrds of a generation.
+    ///
+    /// These are the extra initrds for all generations followed by the ones from the lanzaboote
+    /// bootspec extension of the generation.
+    fn extra_initrds_of(&self, generation: &Generation) -> Vec<PathBuf> {
+        self.extra_initrds
+            .iter()
+            .chain(&generation.spec.lanzaboote.initrds)
+            .cloned()
+            .collect()
+    }
+
+    /// Return the initrd of a generation with its initrd secrets.
+    ///
+    /// When the initrd secrets are shipped as a credential or there are no secrets, this is the
+    /// initrd from the store. Otherwise, the secrets are appended to a copy of the initrd.
+    ///
+    /// Returns `None` if the generation does not have an initrd.
+    fn initrd_with_secrets(
+        &self,
+        tempdir: &TempDir,
+        bootspec: &BootSpecV1,
+    ) -> Result<Option<PathBuf>> {
+        match (
+            &bootspec.initrd,
+            &bootspec.initrd_secrets,
+            self.initrd_secrets_credential_key,
+        ) {
+            (Some(initrd), Some(initrd_secrets_script), None) => {
+                let initrd_location = tempdir
+                    .write_secure_file(fs::read(initrd)?)
+                    .context("Failed to copy initrd to tempfile.")?;
+                append_initrd_secrets(initrd_secrets_script, &initrd_location)?;
+                Ok(Some(initrd_location))
+            }
+            (None, Some(_), None) => Err(anyhow!(
+                "Cannot append initrd secrets to a generation without an initrd."
+            )),
+            (initrd, _, _) => Ok(initrd.clone()),
+        }
+    }
+
+    /// Install the extra EFI tools together with their systemd-boot loader entries.
+    ///
+    /// The tools are signed and installed to the managed tools directory in esp/EFI/nixos. Because
+    /// systemd-boot does not discover arbitrary EFI binaries on its own, a Type #1 loader entry is
+    /// created for each tool. All installed paths are stored as GC roots.
+    fn install_efi_tools(&mut self) -> Result<()> {
+        if self.efi_tools.is_empty() {
+            return Ok(());
+        }
+
+        let tempdir = TempDir::new().context("Failed to create temporary directory.")?;
+
+        for tool in &self.efi_tools {
+            let tool_path = self.esp_paths.tools.join(tool.filename());
+            let entry_path = self
+                .esp_paths
+                .loader_entries
+                .join(tool.loader_entry_filename());
+
+            install_signed(&self.key_pair, &tool.path, &tool_path)
+                .with_context(|| format!("Failed to install EFI tool {}.", tool.name))?;
+
+            let entry = tool
+                .loader_entry(&self.esp_paths.esp, &tool_path)
+                .with_context(|| {
+                    format!("Failed to render loader entry for EFI tool {}.", tool.name)
+                })?;
+            let entry_location = tempdir
+                .write_secure_file(entry)
+                .context("Failed to write loader entry to tempfile.")?;
+            install(&entry_location, &entry_path).with_context(|| {
+                format!("Failed to install loader entry for EFI tool {}.", tool.name)
+            })?;
+
+            self.gc_roots.extend([
+                &self.esp_paths.tools,
+                &self.esp_paths.loader_entries,
+                &tool_path,
+                &entry_path,
+            ]);
+        }
 
         Ok(())
     }
 
     /// Install systemd-boot to ESP.
     ///
-    /// systemd-boot is only updated when a newer version is available OR when the currently
-    /// installed version is not signed. This enables switching to Lanzaboote without having to
-    /// manually delete previous unsigned systemd-boot binaries and minimizes the number of writes
-    /// to the ESP.
+    /// Whether systemd-boot is installed is decided by the systemd-boot policy. By default, it is
+    /// only updated when a newer version is available OR when the currently installed version is
+    /// not signed. This enables switching to Lanzaboote without having to manually delete
+    /// previous unsigned systemd-boot binaries and minimizes the number of writes to the ESP.
+    ///
+    /// Versions that are refused by the policy (e.g. because they are known to be broken) are
+    /// never installed. The installed systemd-boot is kept instead. If there is none or it is not
+    /// signed, installing fails.
     ///
-    /// Checking for the version also allows us to skip buggy systemd versions in the future.
+    /// A fallback boot loader that is not systemd-boot is handled according to the fallback
+    /// policy.
     fn install_systemd_boot(&self) -> Result<()> {
         let systemd_boot = self
             .systemd
             .join("lib/systemd/boot/efi/systemd-bootx64.efi");
 
-        let paths = [
-            (&systemd_boot, &self.esp_paths.efi_fallback),
-            (&systemd_boot, &self.esp_paths.systemd_boot),
-        ];
-
-        for (from, to) in paths {
-            let newer_systemd_boot_available = newer_systemd_boot(from, to)?;
-            if newer_systemd_boot_available {
-                log::info!("Updating {to:?}...")
-            };
-            let systemd_boot_is_signed = &self.key_pair.verify(to);
-            if !systemd_boot_is_signed {
-                log::warn!("${to:?} is not signed. Replacing it with a signed binary...")
-            };
-
-            if newer_systemd_boot_available || !systemd_boot_is_signed {
-                install_signed(&self.key_pair, from, to)
-                    .with_context(|| format!("Failed to install systemd-boot binary to: {to:?}"))?;
+        // If the version from the source binary cannot be read, something is irrecoverably wrong.
+        let available_version = SystemdVersion::from_systemd_boot_binary(&systemd_boot)
+            .with_context(|| {
+                format!("Failed to read systemd-boot version from {systemd_boot:?}.")
+            })?;
+
+        for to in [&self.esp_paths.efi_fallback, &self.esp_paths.systemd_boot] {
+            if to == &self.esp_paths.efi_fallback && !self.take_over_fallback()? {
+                continue;
+            }
+
+            // If the version cannot be read from the destination binary, it is malformed. It
+            // should be forcibly reinstalled.
+            let installed_version = to
+                .exists()
+                .then(|| SystemdVersion::from_systemd_boot_binary(to).ok())
+                .flatten();
+            let systemd_boot_is_signed = installed_version.is_some() && self.key_pair.verify(to);
+
+            match self
+                .systemd_boot_policy
+                .decide(&available_version, installed_version.as_ref())
+            {
+                SystemdBootAction::Install => {
+                    log::info!("Updating {to:?} to systemd-boot {available_version}...");
+                }
+                SystemdBootAction::Keep if !systemd_boot_is_signed => {
+                    log::warn!("{to:?} is not signed. Replacing it with a signed binary...");
+                }
+                SystemdBootAction::Keep => continue,
+                SystemdBootAction::Refuse(reason) => match installed_version {
+                    Some(installed_version) if systemd_boot_is_signed => {
+                        log::warn!(
+                            "Not installing systemd-boot {available_version} to {to:?}: {reason} Keeping systemd-boot {installed_version}."
+                        );
+                        continue;
+                    }
+                    // The installed systemd-boot would not boot with Secure Boot enabled.
+                    Some(installed_version) => {
+                        let to = to.display();
+                        return Err(anyhow!(
+                            "Refusing to install systemd-boot {available_version} to {to}: {reason} The installed systemd-boot {installed_version} is not signed."
+                        ));
+                    }
+                    None => {
+                        let to = to.display();
+                        return Err(anyhow!(
+                            "Refusing to install systemd-boot {available_version} to {to}: {reason}"
+                        ));
+                    }
+                },
             }
+
+            install_signed(&self.key_pair, &systemd_boot, to)
+                .with_context(|| format!("Failed to install systemd-boot binary to: {to:?}"))?;
         }
 
         install(
@@ -370,6 +1180,347 @@ impl Installer {
     }
 }
 
+/// Builds an [`Installer`].
+///
+/// The lanzaboote stub, systemd, the systemd-boot loader config, the key pair and the ESP are
+/// required. All other settings default to the defaults of `lzbt install`.
+pub struct InstallerBuilder {
+    lanzaboote_stub: Option<PathBuf>,
+    lanzaboote_fat_stub: Option<PathBuf>,
+    systemd: Option<PathBuf>,
+    nix: Option<PathBuf>,
+    systemd_boot_loader_config: Option<PathBuf>,
+    key_pair: Option<KeyPair>,
+    configuration_limit: usize,
+    profile_configuration_limits: HashMap<String, usize>,
+    esp: Option<PathBuf>,
+    generation_links: Vec<PathBuf>,
+    efi_tools: Vec<EfiTool>,
+    extra_initrds: Vec<PathBuf>,
+    initrd_secrets_credential_key: Option<CredentialKey>,
+    title_template: TitleTemplate,
+    quarantine_installs: Option<NonZeroUsize>,
+    strict: bool,
+    pins: Vec<Pin>,
+    pin_file: Option<PathBuf>,
+    efivarfs: PathBuf,
+    retention: RetentionPolicy,
+    systemd_boot_policy: SystemdBootPolicy,
+    fallback_policy: FallbackPolicy,
+    legacy_entry_policy: LegacyEntryPolicy,
+    rescue: Option<PathBuf>,
+    replace_rescue: bool,
+}
+
+impl Default for InstallerBuilder {
+    fn default() -> Self {
+        Self {
+            lanzaboote_stub: None,
+            lanzaboote_fat_stub: None,
+            systemd: None,
+            nix: None,
+            systemd_boot_loader_config: None,
+            key_pair: None,
+            configuration_limit: 1,
+            profile_configuration_limits: HashMap::new(),
+            esp: None,
+            generation_links: Vec::new(),
+            efi_tools: Vec::new(),
+            extra_initrds: Vec::new(),
+            initrd_secrets_credential_key: None,
+            title_template: TitleTemplate::default(),
+            quarantine_installs: None,
+            strict: false,
+            pins: Vec::new(),
+            pin_file: None,
+            efivarfs: PathBuf::from(efivars::DEFAULT_EFIVARFS),
+            retention: RetentionPolicy::default(),
+            systemd_boot_policy: SystemdBootPolicy::default(),
+            fallback_policy: FallbackPolicy::default(),
+            legacy_entry_policy: LegacyEntryPolicy::default(),
+            rescue: None,
+            replace_rescue: false,
+        }
+    }
+}
+
+impl InstallerBuilder {
+    /// Set the path of the (thin) lanzaboote stub.
+    pub fn lanzaboote_stub(mut self, path: impl Into<PathBuf>) -> Self {
+        self.lanzaboote_stub = Some(path.into());
+        self
+    }
+
+    /// Set the path of the fat lanzaboote stub.
+    ///
+    /// It is only needed for generations that request a fat stub.
+    pub fn lanzaboote_fat_stub(mut self, path: impl Into<PathBuf>) -> Self {
+        self.lanzaboote_fat_stub = Some(path.into());
+        self
+    }
+
+    /// Set the path of the systemd package that provides systemd-boot.
+    pub fn systemd(mut self, path: impl Into<PathBuf>) -> Self {
+        self.systemd = Some(path.into());
+        self
+    }
+
+    /// Set the path of the Nix package used to read the build times of the generations.
+    ///
+    /// Without it, the build times are approximated from the modification times of the generation
+    /// links.
+    pub fn nix(mut self, path: impl Into<PathBuf>) -> Self {
+        self.nix = Some(path.into());
+        self
+    }
+
+    /// Set the path of the systemd-boot loader config to install.
+    pub fn systemd_boot_loader_config(mut self, path: impl Into<PathBuf>) -> Self {
+        self.systemd_boot_loader_config = Some(path.into());
+        self
+    }
+
+    /// Set the key pair used to sign the installed binaries.
+    pub fn key_pair(mut self, key_pair: KeyPair) -> Self {
+        self.key_pair = Some(key_pair);
+        self
+    }
+
+    /// Set the number of generations to install. 0 means there is no limit.
+    pub fn configuration_limit(mut self, limit: usize) -> Self {
+        self.configuration_limit = limit;
+        self
+    }
+
+    /// Set the configuration limit of a specific profile.
+    pub fn profile_configuration_limit(mut self, profile: impl Into<String>, limit: usize) -> Self {
+        self.profile_configuration_limits
+            .insert(profile.into(), limit);
+        self
+    }
+
+    /// Set the mountpoint of the ESP.
+    pub fn esp(mut self, path: impl Into<PathBuf>) -> Self {
+        self.esp = Some(path.into());
+        self
+    }
+
+    /// Add generation links to install (e.g. from [`crate::discover_generation_links`]).
+    pub fn generation_links(mut self, links: impl IntoIterator<Item = PathBuf>) -> Self {
+        self.generation_links.extend(links);
+        self
+    }
+
+    /// Add extra EFI binaries to sign and add to the boot menu.
+    pub fn efi_tools(mut self, efi_tools: impl IntoIterator<Item = EfiTool>) -> Self {
+        self.efi_tools.extend(efi_tools);
+        self
+    }
+
+    /// Add initrds that are loaded before the initrd of each generation.
+    pub fn extra_initrds(mut self, initrds: impl IntoIterator<Item = PathBuf>) -> Self {
+        self.extra_initrds.extend(initrds);
+        self
+    }
+
+    /// Ship initrd secrets as a credential encrypted with `key` instead of appending them to the
+    /// initrd.
+    pub fn initrd_secrets_credential(mut self, key: CredentialKey) -> Self {
+        self.initrd_secrets_credential_key = Some(key);
+        self
+    }
+
+    /// Set the template for the titles of the boot menu entries.
+    pub fn title_template(mut self, title_template: TitleTemplate) -> Self {
+        self.title_template = title_template;
+        self
+    }
+
+    /// Move unknown files in esp/EFI/nixos to a quarantine and delete them after `installs`
+    /// successful installs instead of deleting them right away.
+    pub fn quarantine_installs(mut self, installs: NonZeroUsize) -> Self {
+        self.quarantine_installs = Some(installs);
+        self
+    }
+
+    /// Do not synthesize a bootspec for generations without a readable bootspec.
+    pub fn strict(mut self, strict: bool) -> Self {
+        self.strict = strict;
+        self
+    }
+
+    /// Add generations that are always installed regardless of the configuration limit.
+    pub fn pins(mut self, pins: impl IntoIterator<Item = Pin>) -> Self {
+        self.pins.extend(pins);
+        self
+    }
+
+    /// Set a file with one pinned generation per line that is read when the installer is built.
+    pub fn pin_file(mut self, path: impl Into<PathBuf>) -> Self {
+        self.pin_file = Some(path.into());
+        self
+    }
+
+    /// Set the mountpoint of efivarfs from which the booted generation is read.
+    pub fn efivarfs(mut self, path: impl Into<PathBuf>) -> Self {
+        self.efivarfs = path.into();
+        self
+    }
+
+    /// Set the retention rules in addition to the configuration limit.
+    pub fn retention(mut self, retention: RetentionPolicy) -> Self {
+        self.retention = retention;
+        self
+    }
+
+    /// Set which systemd-boot versions are installed.
+    pub fn systemd_boot_policy(mut self, systemd_boot_policy: SystemdBootPolicy) -> Self {
+        self.systemd_boot_policy = systemd_boot_policy;
+        self
+    }
+
+    /// Set what to do with a fallback boot loader that is not systemd-boot.
+    ///
+    /// Defaults to [`FallbackPolicy::Backup`]. Earlier versions always replaced the fallback boot
+    /// loader without a backup, which is [`FallbackPolicy::TakeOver`] now.
+    pub fn fallback_policy(mut self, fallback_policy: FallbackPolicy) -> Self {
+        self.fallback_policy = fallback_policy;
+        self
+    }
+
+    /// Set what to do with Type #1 loader entries that boot NixOS without lanzaboote.
+    pub fn legacy_entry_policy(mut self, legacy_entry_policy: LegacyEntryPolicy) -> Self {
+        self.legacy_entry_policy = legacy_entry_policy;
+        self
+    }
+
+    /// Build the rescue image from a generation link or a toplevel.
+    ///
+    /// The rescue image is never pruned. An installed rescue image is only replaced by a newer
+    /// generation unless [`InstallerBuilder::replace_rescue`] is set.
+    pub fn rescue(mut self, path: impl Into<PathBuf>) -> Self {
+        self.rescue = Some(path.into());
+        self
+    }
+
+    /// Replace the installed rescue image even if the rescue generation is not newer.
+    pub fn replace_rescue(mut self, replace_rescue: bool) -> Self {
+        self.replace_rescue = replace_rescue;
+        self
+    }
+
+    /// Build the installer.
+    ///
+    /// Fails if a required setting is missing or the pin file cannot be read.
+    pub fn build(self) -> crate::Result<Installer> {
+        let mut pins = self.pins;
+        if let Some(pin_file) = &self.pin_file {
+            pins.extend(pin::read_pin_file(pin_file).map_err(|e| Error::PinFile {
+                path: pin_file.clone(),
+                source: e.into(),
+            })?);
+        }
+
+        let esp_paths = EspPaths::new(self.esp.ok_or(Error::MissingSetting("esp"))?);
+        let mut gc_roots = Roots::new();
+        gc_roots.extend(esp_paths.to_iter());
+
+        Ok(Installer {
+            broken_gens: Vec::new(),
+            gc_roots,
+            lanzaboote_stub: self
+                .lanzaboote_stub
+                .ok_or(Error::MissingSetting("lanzaboote_stub"))?,
+            lanzaboote_fat_stub: self.lanzaboote_fat_stub,
+            stub_machines: HashMap::new(),
+            systemd: self.systemd.ok_or(Error::MissingSetting("systemd"))?,
+            nix: self.nix,
+            systemd_boot_loader_config: self
+                .systemd_boot_loader_config
+                .ok_or(Error::MissingSetting("systemd_boot_loader_config"))?,
+            key_pair: self.key_pair.ok_or(Error::MissingSetting("key_pair"))?,
+            configuration_limit: self.configuration_limit,
+            profile_configuration_limits: self.profile_configuration_limits,
+            esp_paths,
+            generation_links: self.generation_links,
+            efi_tools: self.efi_tools,
+            extra_initrds: self.extra_initrds,
+            initrd_secrets_credential_key: self.initrd_secrets_credential_key,
+            title_template: self.title_template,
+            quarantine_installs: self.quarantine_installs,
+            strict: self.strict,
+            pins,
+            efivarfs: self.efivarfs,
+            retention: self.retention,
+            systemd_boot_policy: self.systemd_boot_policy,
+            fallback_policy: self.fallback_policy,
+            legacy_entry_policy: self.legacy_entry_policy,
+            rescue: self.rescue,
+            replace_rescue: self.replace_rescue,
+        })
+    }
+}
+
+/// Return the paths of the EFI binaries in a directory whose file names start with `prefix`.
+///
+/// A missing directory does not contain any binaries.
+fn efi_binaries_in(directory: &Path, prefix: &str) -> crate::Result<Vec<PathBuf>> {
+    let entries = match fs::read_dir(directory) {
+        Ok(entries) => entries,
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(Vec::new()),
+        Err(e) => {
+            return Err(Error::Io {
+                path: directory.to_path_buf(),
+                source: e,
+            })
+        }
+    };
+
+    let mut binaries = Vec::new();
+    for entry in entries {
+        let path = entry
+            .map_err(|e| Error::Io {
+                path: directory.to_path_buf(),
+                source: e,
+            })?
+            .path();
+        let is_binary = path
+            .extension()
+            .is_some_and(|e| e.eq_ignore_ascii_case("efi"));
+        let has_prefix = path
+            .file_name()
+            .and_then(|n| n.to_str())
+            .is_some_and(|n| n.starts_with(prefix));
+        if is_binary && has_prefix && path.is_file() {
+            binaries.push(path);
+        }
+    }
+    binaries.sort();
+    Ok(binaries)
+}
+
+/// A generation whose bootspec cannot be read.
+struct BrokenGeneration {
+    /// The path of the profile the generation belongs to.
+    profile_path: PathBuf,
+    /// Name of the profile or `None` for the default system profile
+    profile: Option<String>,
+    version: u64,
+    /// The path of the generation link.
+    path: PathBuf,
+    /// Why the generation cannot be read.
+    error: anyhow::Error,
+}
+
+impl fmt::Display for BrokenGeneration {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match &self.profile {
+            Some(profile) => write!(f, "generation {} of profile {profile}", self.version),
+            None => write!(f, "generation {}", self.version),
+        }
+    }
+}
+
 /// A location in the ESP together with information whether the file
 /// needs to be signed.
 #[derive(Debug, Clone, PartialEq, Eq)]
@@ -419,40 +1570,70 @@ impl GenerationArtifacts {
     ///
     /// Adding the same file multiple times with the same source is ok
     /// and will drop the old source.
-    fn add_file(&mut self, from: FileSource, to: &Path) {
-        if let Some(_prev_from) = self.files.insert(to.to_path_buf(), from) {
+    fn add_file(&mut self, from: FileSource, to: PathBuf) {
+        if let Some(_prev_from) = self.files.insert(to, from) {
             // Should we log something here?
         }
     }
 
-    /// Add source and destination of a PE file to be signed.
+    /// Add the files of a single generation.
     ///
     /// Files are stored in the HashMap using their destination path as the key to ensure that the
     /// destination paths are unique.
+    fn extend(&mut self, files: Vec<(PathBuf, FileSource)>) {
+        for (to, from) in files {
+            self.add_file(from, to);
+        }
+    }
+
+    /// Install all files to the ESP.
+    ///
+    /// The files are installed in parallel because signing is comparatively slow.
+    fn install(&self, key_pair: &KeyPair) -> Result<()> {
+        let files = self.files.iter().collect::<Vec<(&PathBuf, &FileSource)>>();
+
+        parallel_map(&files, |(to, from)| match from {
+            FileSource::SignedFile(from) => install_signed(key_pair, from, to)
+                .with_context(|| format!("Failed to sign and install from {from:?} to {to:?}")),
+            FileSource::UnsignedFile(from) => install(from, to)
+                .with_context(|| format!("Failed to install from {from:?} to {to:?}")),
+        })
+        .into_iter()
+        .collect()
+    }
+}
+
+/// The artifacts of a single generation.
+///
+/// The artifacts of each generation are built independently of the other generations, which
+/// allows building them in parallel. They are merged into the `GenerationArtifacts` afterwards.
+#[derive(Default)]
+struct SingleGenerationArtifacts {
+    /// The destinations and sources of the files in the order they were added.
+    files: Vec<(PathBuf, FileSource)>,
+
+    /// The paths in the ESP that are used by the generation.
+    gc_roots: Vec<PathBuf>,
+}
+
+impl SingleGenerationArtifacts {
+    /// Add source and destination of a PE file to be signed.
     fn add_signed(&mut self, from: &Path, to: &Path) {
-        self.add_file(FileSource::SignedFile(from.to_path_buf()), to);
+        self.files
+            .push((to.to_path_buf(), FileSource::SignedFile(from.to_path_buf())));
     }
 
     /// Add source and destination of an arbitrary file.
     fn add_unsigned(&mut self, from: &Path, to: &Path) {
-        self.add_file(FileSource::UnsignedFile(from.to_path_buf()), to);
+        self.files.push((
+            to.to_path_buf(),
+            FileSource::UnsignedFile(from.to_path_buf()),
+        ));
     }
 
-    /// Install all files to the ESP.
-    fn install(&self, key_pair: &KeyPair) -> Result<()> {
-        for (to, from) in &self.files {
-            match from {
-                FileSource::SignedFile(from) => {
-                    install_signed(key_pair, from, to).with_context(|| {
-                        format!("Failed to sign and install from {from:?} to {to:?}")
-                    })?
-                }
-                FileSource::UnsignedFile(from) => install(from, to)
-                    .with_context(|| format!("Failed to install from {from:?} to {to:?}"))?,
-            }
-        }
-
-        Ok(())
+    /// Add paths in the ESP that must not be garbage collected.
+    fn add_gc_roots<'a>(&mut self, paths: impl IntoIterator<Item = &'a PathBuf>) {
+        self.gc_roots.extend(paths.into_iter().cloned());
     }
 }
 
@@ -469,10 +1650,19 @@ fn install_signed(key_pair: &KeyPair, from: &Path, to: &Path) -> Result<()> {
     ensure_parent_dir(&to_tmp);
     key_pair
         .sign_and_copy(from, &to_tmp)
-        .with_context(|| format!("Failed to copy and sign file from {from:?} to {to:?}"))?;
-    fs::rename(&to_tmp, to).with_context(|| {
-        format!("Failed to move temporary file {to_tmp:?} to final location {to:?}")
-    })?;
+        .with_context(|| format!("Failed to copy and sign file from {from:?} to {to:?}"))
+        .map_err(|e| Error::Signing {
+            path: to.to_path_buf(),
+            source: e.into(),
+        })?;
+    fs::rename(&to_tmp, to)
+        .with_context(|| {
+            format!("Failed to move temporary file {to_tmp:?} to final location {to:?}")
+        })
+        .map_err(|e| Error::EspWrite {
+            path: to.to_path_buf(),
+            source: e.into(),
+        })?;
     Ok(())
 }
 
@@ -495,15 +1685,37 @@ fn install(from: &Path, to: &Path) -> Result<()> {
 /// This function is only designed to copy files to the ESP. It sets the permission bits of the
 /// file at the destination to 0o755, the expected permissions for a vfat ESP. This is useful for
 /// producing file systems trees which can then be converted to a file system image.
-fn force_install(from: &Path, to: &Path) -> Result<()> {
+pub(crate) fn force_install(from: &Path, to: &Path) -> Result<()> {
     log::debug!("Installing {to:?}...");
     ensure_parent_dir(to);
-    atomic_copy(from, to)?;
-    set_permission_bits(to, 0o755)
-        .with_context(|| format!("Failed to set permission bits to 0o755 on file: {to:?}"))?;
+    atomic_copy(from, to)
+        .and_then(|_| {
+            set_permission_bits(to, 0o755)
+                .with_context(|| format!("Failed to set permission bits to 0o755 on file: {to:?}"))
+        })
+        .map_err(|e| Error::EspWrite {
+            path: to.to_path_buf(),
+            source: e.into(),
+        })?;
     Ok(())
 }
 
+/// Concatenate initrds into a single file.
+///
+/// Each initrd is padded to a multiple of 4 bytes, because the kernel expects each cpio archive to
+/// start at a 4 byte aligned offset. This is what the thin stub does at boot.
+fn concatenate_initrds(tempdir: &TempDir, initrds: &[&Path]) -> Result<PathBuf> {
+    let mut initrd_data = Vec::new();
+    for initrd in initrds {
+        initrd_data
+            .extend(fs::read(initrd).with_context(|| format!("Failed to read initrd {initrd:?}"))?);
+        let padding = (4 - initrd_data.len() % 4) % 4;
+        initrd_data.resize(initrd_data.len() + padding, 0);
+    }
+
+    tempdir.write_secure_file(initrd_data)
+}
+
 pub fn append_initrd_secrets(
     append_initrd_secrets_path: &Path,
     initrd_path: &PathBuf,
@@ -564,28 +1776,75 @@ fn ensure_parent_dir(path: &Path) {
     }
 }
 
-/// Determine if a newer systemd-boot version is available.
-///
-/// "Newer" can mean
-///   (1) no file exists at the destination,
-///   (2) the file at the destination is malformed,
-///   (3) a binary with a higher version is available.
-fn newer_systemd_boot(from: &Path, to: &Path) -> Result<bool> {
-    // If the file doesn't exists at the destination, it should be installed.
-    if !to.exists() {
-        return Ok(true);
-    }
-
-    // If the version from the source binary cannot be read, something is irrecoverably wrong.
-    let from_version = SystemdVersion::from_systemd_boot_binary(from)
-        .with_context(|| format!("Failed to read systemd-boot version from {from:?}."))?;
-
-    // If the version cannot be read from the destination binary, it is malformed. It should be
-    // forcibly reinstalled.
-    let to_version = match SystemdVersion::from_systemd_boot_binary(to) {
-        Ok(version) => version,
-        _ => return Ok(true),
-    };
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn complete_builder(esp: &Path) -> InstallerBuilder {
+        Installer::builder()
+            .lanzaboote_stub("/nix/store/lanzaboote-stub/stub.efi")
+            .systemd("/nix/store/systemd")
+            .systemd_boot_loader_config("/nix/store/loader.conf")
+            .key_pair(KeyPair::new(Path::new("db.pem"), Path::new("db.key")))
+            .esp(esp)
+    }
+
+    #[test]
+    fn build_with_all_required_settings() {
+        assert!(complete_builder(Path::new("/boot")).build().is_ok());
+    }
+
+    #[test]
+    fn build_fails_without_required_settings() {
+        let missing_setting = |builder: InstallerBuilder| match builder.build() {
+            Err(Error::MissingSetting(setting)) => setting,
+            Err(e) => panic!("Unexpected error: {e}"),
+            Ok(_) => panic!("Built installer without a required setting"),
+        };
+
+        let mut builder = complete_builder(Path::new("/boot"));
+        builder.esp = None;
+        assert_eq!(missing_setting(builder), "esp");
+
+        let mut builder = complete_builder(Path::new("/boot"));
+        builder.lanzaboote_stub = None;
+        assert_eq!(missing_setting(builder), "lanzaboote_stub");
+
+        let mut builder = complete_builder(Path::new("/boot"));
+        builder.systemd = None;
+        assert_eq!(missing_setting(builder), "systemd");
+
+        let mut builder = complete_builder(Path::new("/boot"));
+        builder.systemd_boot_loader_config = None;
+        assert_eq!(missing_setting(builder), "systemd_boot_loader_config");
 
-    Ok(from_version > to_version)
+        let mut builder = complete_builder(Path::new("/boot"));
+        builder.key_pair = None;
+        assert_eq!(missing_setting(builder), "key_pair");
+    }
+
+    #[test]
+    fn build_fails_with_invalid_pin_file() -> Result<()> {
+        let tmpdir = tempfile::tempdir()?;
+        let pin_file = tmpdir.path().join("pins");
+        fs::write(&pin_file, "latest\n")?;
+
+        let result = complete_builder(tmpdir.path()).pin_file(&pin_file).build();
+
+        assert!(matches!(result, Err(Error::PinFile { path, .. }) if path == pin_file));
+        Ok(())
+    }
+
+    #[test]
+    fn verify_fails_for_missing_systemd_boot() -> Result<()> {
+        let tmpdir = tempfile::tempdir()?;
+        let installer = complete_builder(tmpdir.path()).build()?;
+
+        let result = installer.verify();
+
+        assert!(
+            matches!(result, Err(Error::Io { path, .. }) if path == installer.esp_paths.systemd_boot)
+        );
+        Ok(())
+    }
 }
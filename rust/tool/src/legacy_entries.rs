@@ -0,0 +1,147 @@
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+
+use anyhow::{Context, Result};
+use clap::ValueEnum;
+
+use crate::efi_tools;
+
+/// The keys of a Type #1 loader entry that reference the binary systemd-boot boots.
+const BOOT_BINARY_KEYS: [&str; 2] = ["linux", "efi"];
+
+/// The location on the ESP of the kernels and initrds of NixOS.
+///
+/// Entries that boot the signed lanzaboote images in esp/EFI/Linux are not legacy entries. The stub
+/// ignores the load options from the entry and only boots what its image references.
+///
+/// Paths on the ESP are case-insensitive. They are compared in lowercase.
+const NIXOS_LOCATION: &str = "efi/nixos/";
+
+/// What to do with Type #1 loader entries (esp/loader/entries/*.conf) that boot NixOS.
+///
+/// Such entries are usually left over from booting with plain systemd-boot before migrating to
+/// lanzaboote. systemd-boot honors them next to the lanzaboote images. They can combine a kernel
+/// with an initrd and a command line that are not covered by any signature, which bypasses
+/// Secure Boot.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, ValueEnum)]
+pub enum LegacyEntryPolicy {
+    /// Only report them.
+    Warn,
+    /// Report and remove them.
+    #[default]
+    Remove,
+}
+
+/// Find the Type #1 loader entries in `loader_entries` that boot a NixOS kernel.
+///
+/// The entries lzbt creates for EFI tools are not considered. A missing directory does not contain
+/// any entries.
+pub fn find_legacy_entries(loader_entries: &Path) -> Result<Vec<PathBuf>> {
+    let entries = match fs::read_dir(loader_entries) {
+        Ok(entries) => entries,
+        Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(Vec::new()),
+        Err(e) => {
+            return Err(e).with_context(|| format!("Failed to read directory {loader_entries:?}"))
+        }
+    };
+
+    let mut legacy_entries = Vec::new();
+    for entry in entries {
+        let path = entry
+            .with_context(|| format!("Failed to read directory {loader_entries:?}"))?
+            .path();
+        let Some(file_name) = path.file_name().and_then(|n| n.to_str()) else {
+            continue;
+        };
+        if !file_name.ends_with(".conf") || file_name.starts_with(efi_tools::LOADER_ENTRY_PREFIX) {
+            continue;
+        }
+
+        let content = fs::read_to_string(&path)
+            .with_context(|| format!("Failed to read loader entry {path:?}"))?;
+        if boots_nixos(&content) {
+            legacy_entries.push(path);
+        }
+    }
+    legacy_entries.sort();
+
+    Ok(legacy_entries)
+}
+
+/// Report and, depending on the policy, remove the legacy Type #1 loader entries.
+pub fn neutralize_legacy_entries(loader_entries: &Path, policy: LegacyEntryPolicy) -> Result<()> {
+    for entry in find_legacy_entries(loader_entries)? {
+        match policy {
+            LegacyEntryPolicy::Warn => log::warn!(
+                "Loader entry {entry:?} boots NixOS without the protection of lanzaboote and \
+                 can be used to bypass Secure Boot."
+            ),
+            LegacyEntryPolicy::Remove => {
+                log::warn!(
+                    "Removing loader entry {entry:?} because it boots NixOS without the \
+                     protection of lanzaboote."
+                );
+                fs::remove_file(&entry)
+                    .with_context(|| format!("Failed to remove loader entry {entry:?}"))?;
+            }
+        }
+    }
+
+    Ok(())
+}
+
+/// Return whether a Type #1 loader entry boots a NixOS kernel.
+fn boots_nixos(content: &str) -> bool {
+    content
+        .lines()
+        .filter_map(|line| line.trim().split_once(char::is_whitespace))
+        .filter(|(key, _)| BOOT_BINARY_KEYS.contains(key))
+        .any(|(_, value)| {
+            let value = value.trim().trim_start_matches(['/', '\\']);
+            let value = value.replace('\\', "/").to_lowercase();
+            value.starts_with(NIXOS_LOCATION)
+        })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn detect_entries_of_plain_systemd_boot() {
+        let entry = "title NixOS\n\
+                     version Generation 42 NixOS 23.05, Linux Kernel 6.1.38, Built on 2023-07-10\n\
+                     linux /efi/nixos/4jyjspp6f0mi8hpnxdm9m0wgqrxc0ffs-linux-6.1.38-bzImage.efi\n\
+                     initrd /efi/nixos/fhpl7jd7w9ajjnys0bq4dh8bgbg0v4bf-initrd-linux-6.1.38-initrd.efi\n\
+                     options init=/nix/store/7fg2ahplqrq8i9wszzr6z4xwsyf9wrim-nixos-system/init\n\
+                     machine-id 3c2b5a8a14a24bb2a4e3ff9da1d1c0c5\n";
+        assert!(boots_nixos(entry));
+        assert!(!boots_nixos("efi \\EFI\\Linux\\nixos-generation-1.efi\n"));
+    }
+
+    #[test]
+    fn ignore_entries_of_other_operating_systems() {
+        assert!(!boots_nixos(
+            "title Ubuntu\nlinux /EFI/ubuntu/vmlinuz\ninitrd /EFI/ubuntu/initrd.img\n"
+        ));
+        assert!(!boots_nixos(
+            "title Windows\nefi /EFI/Microsoft/Boot/bootmgfw.efi\n"
+        ));
+        assert!(!boots_nixos("# linux /efi/nixos/kernel.efi\n"));
+    }
+
+    #[test]
+    fn keep_efi_tool_entries() -> Result<()> {
+        let loader_entries = tempfile::tempdir()?;
+        let legacy_entry = loader_entries.path().join("nixos-generation-1.conf");
+        fs::write(&legacy_entry, "linux /efi/nixos/kernel.efi\n")?;
+        fs::write(
+            loader_entries.path().join("nixos-tool-shell.conf"),
+            "efi /EFI/nixos/tools/shell.efi\n",
+        )?;
+
+        assert_eq!(find_legacy_entries(loader_entries.path())?, [legacy_entry]);
+        Ok(())
+    }
+}
@@ -0,0 +1,70 @@
+//! Install lanzaboote to an EFI System Partition.
+//!
+//! This is the library behind `lzbt`. It can be used to drive installs without going through the
+//! command line interface:
+//!
+//! ```no_run
+//! use lanzaboote_tool::{discover_generation_links, Installer, KeyPair};
+//!
+//! # fn main() -> lanzaboote_tool::Result<()> {
+//! let mut installer = Installer::builder()
+//!     .lanzaboote_stub("/run/current-system/lanzaboote/stub.efi")
+//!     .systemd("/run/current-system/systemd")
+//!     .systemd_boot_loader_config("/etc/lanzaboote/loader.conf")
+//!     .key_pair(KeyPair::new(
+//!         "/etc/secureboot/keys/db/db.pem".as_ref(),
+//!         "/etc/secureboot/keys/db/db.key".as_ref(),
+//!     ))
+//!     .esp("/boot")
+//!     .generation_links(discover_generation_links("/nix/var/nix/profiles")?)
+//!     .configuration_limit(10)
+//!     .build()?;
+//!
+//! installer.install()?;
+//! installer.verify()?;
+//! # Ok(())
+//! # }
+//! ```
+//!
+//! All fallible functions of the public API return the typed [`Error`].
+
+mod credential;
+mod efi_tools;
+mod efivars;
+mod error;
+mod esp;
+mod extension;
+mod fallback;
+mod gc;
+mod generation;
+mod install;
+mod legacy_entries;
+mod os_release;
+mod pe;
+mod pin;
+mod preflight;
+mod quarantine;
+mod random_seed;
+mod rescue;
+mod retention;
+mod signature;
+mod store;
+mod systemd;
+mod uninstall;
+mod utils;
+
+pub use credential::CredentialKey;
+pub use efi_tools::EfiTool;
+pub use efivars::DEFAULT_EFIVARFS;
+pub use error::{BoxError, Error, Result};
+pub use esp::EspPaths;
+pub use fallback::FallbackPolicy;
+pub use generation::{discover_generation_links, BuildTime, Generation};
+pub use install::{Installer, InstallerBuilder};
+pub use legacy_entries::LegacyEntryPolicy;
+pub use os_release::TitleTemplate;
+pub use pin::Pin;
+pub use retention::RetentionPolicy;
+pub use signature::KeyPair;
+pub use systemd::{SystemdBootPolicy, SystemdVersion};
+pub use uninstall::{UninstallAction, Uninstaller};
@@ -1,46 +1,105 @@
 use std::collections::BTreeMap;
 use std::fmt;
+use std::fs;
+use std::io;
+use std::str::FromStr;
 
 use anyhow::{Context, Result};
 
+use crate::error::Error;
 use crate::generation::Generation;
 
 /// An os-release file represented by a BTreeMap.
 ///
-/// This is implemented using a map, so that it can be easily extended in the future (e.g. by
-/// reading the original os-release and patching it).
+/// This is implemented using a map, so that the original os-release of a generation can be read
+/// and patched.
 ///
 /// The BTreeMap is used over a HashMap, so that the keys are ordered. This is irrelevant for
 /// systemd-boot (which does not care about order when reading the os-release file) but is useful
 /// for testing. Ordered keys allow using snapshot tests.
 pub struct OsRelease(pub BTreeMap<String, String>);
 
+/// Keys that are taken over from the os-release of the generation.
+const INHERITED_KEYS: [&str; 5] = ["NAME", "ID", "VERSION", "BUILD_ID", "IMAGE_ID"];
+
 impl OsRelease {
-    pub fn from_generation(generation: &Generation) -> Result<Self> {
-        let mut map = BTreeMap::new();
+    pub fn from_generation(
+        generation: &Generation,
+        title_template: &TitleTemplate,
+    ) -> Result<Self> {
+        let toplevel = &generation.spec.bootspec.bootspec.toplevel.0;
 
+        // Base the os-release on the os-release of the generation itself so that systemd-boot and
+        // `bootctl list` show the same name and ID as the booted system. Generations without an
+        // os-release in their toplevel fall back to the defaults of NixOS.
+        let os_release_path = toplevel.join("etc/os-release");
+        let generation_os_release = match fs::read_to_string(&os_release_path) {
+            Ok(s) => {
+                Self::from_str(&s)
+                    .context("Failed to parse os-release of generation.")?
+                    .0
+            }
+            Err(e) if e.kind() == io::ErrorKind::NotFound => BTreeMap::new(),
+            Err(e) => {
+                return Err(e)
+                    .with_context(|| format!("Failed to read os-release {os_release_path:?}"))
+            }
+        };
+
+        let mut map: BTreeMap<String, String> = INHERITED_KEYS
+            .iter()
+            .filter_map(|key| {
+                generation_os_release
+                    .get(*key)
+                    .map(|value| (String::from(*key), value.clone()))
+            })
+            .collect();
+        map.entry("NAME".into()).or_insert_with(|| "NixOS".into());
         // Because of a null pointer dereference, `bootctl` segfaults when no ID field is present
         // in the .osrel section of the stub.
         // Fixed in https://github.com/systemd/systemd/pull/25953
-        //
-        // Because the ID field here does not have the same meaning as in a real os-release file,
-        // it is fine to use a dummy value.
-        map.insert("ID".into(), String::from("lanza"));
-        map.insert(
-            "PRETTY_NAME".into(),
-            generation.spec.bootspec.bootspec.label.clone(),
-        );
-        map.insert("VERSION_ID".into(), generation.describe());
+        map.entry("ID".into()).or_insert_with(|| "nixos".into());
+
+        let title = title_template.render(generation, &map);
+        let title = if generation.is_rescue() {
+            format!("{title} (Rescue)")
+        } else {
+            title
+        };
+        map.insert("PRETTY_NAME".into(), title);
+
+        // systemd-boot sorts entries with the same sort key by version in descending order. It
+        // prefers IMAGE_VERSION over VERSION, which is the same for all generations of a NixOS
+        // release. Thus, the sortable version is set as both IMAGE_VERSION and VERSION_ID.
+        let version_id = sortable_version(generation);
+        map.insert("IMAGE_VERSION".into(), version_id.clone());
+        map.insert("VERSION_ID".into(), version_id);
+
+        // All generations of a profile share the same sort key so that they are grouped together
+        // and only ordered by their version.
+        let sort_key_base = map
+            .get("IMAGE_ID")
+            .or_else(|| map.get("ID"))
+            .cloned()
+            .unwrap_or_default();
+        // The rescue image is listed after the regular generations.
+        let sort_key = match generation.profile() {
+            _ if generation.is_rescue() => format!("{sort_key_base}-rescue"),
+            Some(profile) => format!("{sort_key_base}-{profile}"),
+            None => sort_key_base,
+        };
+        map.insert("SORT_KEY".into(), sort_key);
 
         Ok(Self(map))
     }
 
     /// Parse the string representation of a os-release file.
     ///
-    /// **Beware before reusing this function!**
-    ///
-    /// This parser might not parse all valid os-release files correctly. It is only designed to
-    /// read the `VERSION` key from the os-release of a systemd-boot binary.
+    /// Empty lines and comments are skipped. Values may be enclosed in single or double quotes,
+    /// which are stripped, and `\$`, `\"`, `` \` `` and `\\` are unescaped. This covers the
+    /// os-release of systemd-boot binaries and of NixOS generations, including the values written
+    /// by [`OsRelease`]'s `Display` implementation. Other shell syntax, e.g. a value that spans
+    /// multiple lines, is not supported.
     pub fn from_str(value: &str) -> Result<Self> {
         let mut map = BTreeMap::new();
 
@@ -49,17 +108,13 @@ impl OsRelease {
             .lines()
             .map(str::trim)
             .filter(|x| !x.starts_with('#') && !x.is_empty());
-        // Split into keys/values
-        let key_value_lines = lines.map(|x| x.split('=').collect::<Vec<&str>>());
-        for kv in key_value_lines {
-            let k = kv
-                .first()
-                .with_context(|| format!("Failed to get first element from {kv:?}"))?;
-            let v = kv
-                .get(1)
-                .map(|s| s.strip_prefix(|c| c == '"' || c == '\'').unwrap_or(s))
-                .map(|s| s.strip_suffix(|c| c == '"' || c == '\'').unwrap_or(s))
-                .with_context(|| format!("Failed to get second element from {kv:?}"))?;
+        // Split into keys/values. Only split at the first '=' because values can contain '='.
+        for line in lines {
+            let (k, v) = line
+                .split_once('=')
+                .with_context(|| format!("Failed to split {line:?} into key and value"))?;
+            let v = v.strip_prefix(|c| c == '"' || c == '\'').unwrap_or(v);
+            let v = v.strip_suffix(|c| c == '"' || c == '\'').unwrap_or(v);
             // Clean up the value. We already have the value without leading/tailing "
             // so we just need to unescape the string.
             let v = v
@@ -68,23 +123,169 @@ impl OsRelease {
                 .replace("\\`", "`")
                 .replace("\\\\", "\\");
 
-            map.insert(String::from(*k), v);
+            map.insert(String::from(k), v);
         }
 
         Ok(Self(map))
     }
 }
 
+/// Compute a version for a generation that systemd-boot sorts correctly.
+///
+/// systemd-boot sorts by version in descending order. Within a generation, the generation itself
+/// has to come before its specialisations. Thus, a generation N gets the version "N.1" and its
+/// specialisations get "N.0-<name>", which sort between generation N and generation N-1.
+fn sortable_version(generation: &Generation) -> String {
+    match generation.is_specialised() {
+        Some(specialisation_name) => {
+            // Versions may only contain lower-case letters, digits, '.', '_' and '-'.
+            let name = specialisation_name
+                .0
+                .chars()
+                .map(|c| match c.to_ascii_lowercase() {
+                    c @ ('a'..='z' | '0'..='9' | '.' | '_' | '-') => c,
+                    _ => '_',
+                })
+                .collect::<String>();
+            format!("{}.0-{}", generation.version(), name)
+        }
+        None => format!("{}.1", generation.version()),
+    }
+}
+
+/// A template for the title of boot menu entries.
+///
+/// The title is used as the `PRETTY_NAME` of the os-release. The following placeholders are
+/// replaced:
+///
+/// - `{label}`: the label of the bootspec
+/// - `{name}`: the `NAME` from the os-release of the generation
+/// - `{nixos_version}`: the NixOS version of the generation
+/// - `{kernel_version}`: the version of the kernel of the generation
+/// - `{generation}`: the version of the generation
+/// - `{build_time}`: the time the generation was added to this machine
+/// - `{profile}`: `" [<profile>]"` or nothing for the default system profile
+/// - `{specialisation}`: `" (<specialisation>)"` or nothing if the generation is not a
+///   specialisation
+/// - `{pinned}`: `" (pinned)"` or nothing if the generation is not pinned
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct TitleTemplate(String);
+
+impl TitleTemplate {
+    const PLACEHOLDERS: [&'static str; 9] = [
+        "label",
+        "name",
+        "nixos_version",
+        "kernel_version",
+        "generation",
+        "build_time",
+        "profile",
+        "specialisation",
+        "pinned",
+    ];
+
+    /// Render the title of a generation.
+    fn render(&self, generation: &Generation, os_release: &BTreeMap<String, String>) -> String {
+        let unknown = || String::from("Unknown");
+
+        let values = [
+            generation.spec.bootspec.bootspec.label.clone(),
+            os_release.get("NAME").cloned().unwrap_or_else(unknown),
+            generation.nixos_version().unwrap_or_else(unknown),
+            generation.kernel_version().unwrap_or_else(unknown),
+            generation.version().to_string(),
+            generation.describe_build_time(),
+            generation
+                .profile()
+                .map(|profile| format!(" [{profile}]"))
+                .unwrap_or_default(),
+            generation
+                .is_specialised()
+                .map(|name| format!(" ({name})"))
+                .unwrap_or_default(),
+            if generation.is_pinned() {
+                String::from(" (pinned)")
+            } else {
+                String::new()
+            },
+        ];
+
+        Self::PLACEHOLDERS
+            .iter()
+            .zip(values)
+            .fold(self.0.clone(), |title, (placeholder, value)| {
+                title.replace(&format!("{{{placeholder}}}"), &value)
+            })
+    }
+}
+
+impl Default for TitleTemplate {
+    fn default() -> Self {
+        Self(String::from(
+            "{name} {nixos_version} (Linux {kernel_version}), Generation {generation}{profile}{specialisation}{pinned}, Added on {build_time}",
+        ))
+    }
+}
+
+/// Parse a title template and check that it only contains known placeholders.
+impl FromStr for TitleTemplate {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let mut rest = s;
+        while let Some((_, after_brace)) = rest.split_once('{') {
+            let (placeholder, after_placeholder) =
+                after_brace.split_once('}').ok_or_else(|| {
+                    Error::InvalidArgument(format!(
+                        "Unterminated placeholder in title template: {s}"
+                    ))
+                })?;
+            if !Self::PLACEHOLDERS.contains(&placeholder) {
+                return Err(Error::InvalidArgument(format!(
+                    "Unknown placeholder {{{placeholder}}} in title template. Known placeholders: {}",
+                    Self::PLACEHOLDERS
+                        .map(|p| format!("{{{p}}}"))
+                        .join(", ")
+                )));
+            }
+            rest = after_placeholder;
+        }
+
+        Ok(Self(s.to_string()))
+    }
+}
+
 /// Display OsRelease in the format of an os-release file.
 impl fmt::Display for OsRelease {
     fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
         for (key, value) in &self.0 {
-            writeln!(f, "{}={}", key, value)?
+            writeln!(f, "{}={}", key, quote(value))?
         }
         Ok(())
     }
 }
 
+/// Quote a value for an os-release file if necessary.
+///
+/// os-release(5) requires values with spaces or other shell special characters to be enclosed in
+/// double quotes. Inside them, `\`, `"`, `$` and `` ` `` are escaped with a backslash.
+fn quote(value: &str) -> String {
+    let is_safe = |c: char| c.is_ascii_alphanumeric() || "._-+:/,@%".contains(c);
+    if !value.is_empty() && value.chars().all(is_safe) {
+        return value.to_string();
+    }
+
+    let mut quoted = String::from('"');
+    for c in value.chars() {
+        if matches!(c, '\\' | '"' | '$' | '`') {
+            quoted.push('\\');
+        }
+        quoted.push(c);
+    }
+    quoted.push('"');
+    quoted
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
@@ -129,4 +330,41 @@ mod tests {
 
         Ok(())
     }
+
+    #[test]
+    fn quotes_values_with_special_characters() -> Result<()> {
+        let os_release = OsRelease(BTreeMap::from([
+            ("ID".to_string(), "nixos".to_string()),
+            ("VERSION".to_string(), "23.05 (Stoat)".to_string()),
+            ("PRETTY_NAME".to_string(), "Say \"$HOME\"".to_string()),
+        ]));
+
+        let rendered = os_release.to_string();
+        assert_eq!(
+            rendered,
+            "ID=nixos\nPRETTY_NAME=\"Say \\\"\\$HOME\\\"\"\nVERSION=\"23.05 (Stoat)\"\n"
+        );
+
+        let parsed = OsRelease::from_str(&rendered)?;
+        assert_eq!(parsed.0, os_release.0);
+
+        Ok(())
+    }
+
+    #[test]
+    fn values_can_contain_equal_signs() -> Result<()> {
+        let os_release = OsRelease::from_str("HOME_URL=\"https://example.com/?a=b\"\n")?;
+
+        assert!(os_release.0["HOME_URL"] == "https://example.com/?a=b");
+
+        Ok(())
+    }
+
+    #[test]
+    fn parse_title_template() {
+        assert!(TitleTemplate::from_str("{label} - {generation}").is_ok());
+        assert!(TitleTemplate::from_str("No placeholders").is_ok());
+        assert!(TitleTemplate::from_str("{unknown}").is_err());
+        assert!(TitleTemplate::from_str("{label").is_err());
+    }
 }
@@ -1,3 +1,4 @@
+use std::collections::BTreeMap;
 use std::ffi::OsString;
 use std::fs;
 use std::os::unix::fs::MetadataExt;
@@ -21,9 +22,10 @@ pub fn lanzaboote_image(
     os_release: &Path,
     kernel_cmdline: &[String],
     kernel_path: &Path,
-    initrd_path: &Path,
+    initrd_paths: &[&Path],
     esp_gen_paths: &EspGenerationPaths,
     esp: &Path,
+    extra_sections: &BTreeMap<String, PathBuf>,
 ) -> Result<PathBuf> {
     // objcopy can only copy files into the PE binary. That's why we
     // have to write the contents of some bootspec properties to disk.
@@ -33,31 +35,101 @@ pub fn lanzaboote_image(
         tempdir.write_secure_file(esp_relative_uefi_path(esp, &esp_gen_paths.kernel)?)?;
     let kernel_hash_file = tempdir.write_secure_file(file_hash(kernel_path)?.as_slice())?;
 
-    let initrd_path_file =
-        tempdir.write_secure_file(esp_relative_uefi_path(esp, &esp_gen_paths.initrd)?)?;
-    let initrd_hash_file = tempdir.write_secure_file(file_hash(initrd_path)?.as_slice())?;
-
-    let os_release_offs = stub_offset(lanzaboote_stub)?;
-    let kernel_cmdline_offs = os_release_offs + file_size(os_release)?;
-    let initrd_path_offs = kernel_cmdline_offs + file_size(&kernel_cmdline_file)?;
-    let kernel_path_offs = initrd_path_offs + file_size(&initrd_path_file)?;
-    let initrd_hash_offs = kernel_path_offs + file_size(&kernel_path_file)?;
-    let kernel_hash_offs = initrd_hash_offs + file_size(&initrd_hash_file)?;
-
-    let sections = vec![
-        s(".osrel", os_release, os_release_offs),
-        s(".cmdline", kernel_cmdline_file, kernel_cmdline_offs),
-        s(".initrdp", initrd_path_file, initrd_path_offs),
-        s(".kernelp", kernel_path_file, kernel_path_offs),
-        s(".initrdh", initrd_hash_file, initrd_hash_offs),
-        s(".kernelh", kernel_hash_file, kernel_hash_offs),
-    ];
+    // Multiple initrds are stored as a newline separated list of paths and the concatenation of
+    // their hashes. The order of both is the order in which the initrds are passed to the kernel.
+    // Without any initrds, both sections are omitted.
+    let (initrd_path_file, initrd_hash_file) = if initrd_paths.is_empty() {
+        (None, None)
+    } else {
+        let initrd_uefi_paths = esp_gen_paths
+            .initrds()
+            .map(|initrd| esp_relative_uefi_path(esp, initrd))
+            .collect::<Result<Vec<String>>>()?;
+
+        let mut initrd_hashes = Vec::new();
+        for initrd_path in initrd_paths {
+            initrd_hashes.extend_from_slice(file_hash(initrd_path)?.as_slice());
+        }
+
+        (
+            Some(tempdir.write_secure_file(initrd_uefi_paths.join("\n"))?),
+            Some(tempdir.write_secure_file(initrd_hashes)?),
+        )
+    };
+
+    let sections = [
+        (".osrel", Some(os_release.to_path_buf())),
+        (".cmdline", Some(kernel_cmdline_file)),
+        (".initrdp", initrd_path_file),
+        (".kernelp", Some(kernel_path_file)),
+        (".initrdh", initrd_hash_file),
+        (".kernelh", Some(kernel_hash_file)),
+    ]
+    .into_iter()
+    .filter_map(|(name, file_path)| file_path.map(|file_path| (name.to_string(), file_path)))
+    .chain(extra_sections.clone());
+
+    let image_path = tempdir.path().join(tmpname());
+    wrap_in_pe(
+        lanzaboote_stub,
+        layout_sections(lanzaboote_stub, sections)?,
+        &image_path,
+    )?;
+    Ok(image_path)
+}
+
+/// Assemble a fat lanzaboote image.
+///
+/// Unlike the (thin) lanzaboote image, the kernel and the initrd are embedded into the image
+/// itself. Thus, they are covered by the signature of the image and do not need to be installed
+/// to the ESP.
+pub fn lanzaboote_fat_image(
+    tempdir: &TempDir,
+    lanzaboote_fat_stub: &Path,
+    os_release: &Path,
+    kernel_cmdline: &[String],
+    kernel_path: &Path,
+    initrd_path: Option<&Path>,
+    extra_sections: &BTreeMap<String, PathBuf>,
+) -> Result<PathBuf> {
+    let kernel_cmdline_file = tempdir.write_secure_file(kernel_cmdline.join(" "))?;
+
+    let sections = [
+        (".osrel", os_release.to_path_buf()),
+        (".cmdline", kernel_cmdline_file),
+        (".linux", kernel_path.to_path_buf()),
+    ]
+    .into_iter()
+    .chain(initrd_path.map(|initrd_path| (".initrd", initrd_path.to_path_buf())))
+    .map(|(name, file_path)| (name.to_string(), file_path))
+    .chain(extra_sections.clone());
 
     let image_path = tempdir.path().join(tmpname());
-    wrap_in_pe(lanzaboote_stub, sections, &image_path)?;
+    wrap_in_pe(
+        lanzaboote_fat_stub,
+        layout_sections(lanzaboote_fat_stub, sections)?,
+        &image_path,
+    )?;
     Ok(image_path)
 }
 
+/// Place sections one after another behind the last section of the stub.
+fn layout_sections(
+    stub: &Path,
+    sections: impl IntoIterator<Item = (String, PathBuf)>,
+) -> Result<Vec<Section>> {
+    let mut offset = stub_offset(stub)?;
+
+    sections
+        .into_iter()
+        .map(|(name, file_path)| {
+            let section = s(name, &file_path, offset);
+            offset += file_size(&file_path)?;
+            Ok(section)
+        })
+        .collect()
+}
+
 /// Take a PE binary stub and attach sections to it.
 ///
 /// The resulting binary is then written to a newly created file at the provided output path.
@@ -83,7 +155,7 @@ fn wrap_in_pe(stub: &Path, sections: Vec<Section>, output: &Path) -> Result<()>
 }
 
 struct Section {
-    name: &'static str,
+    name: String,
     file_path: PathBuf,
     offset: u64,
 }
@@ -106,7 +178,7 @@ impl Section {
     }
 }
 
-fn s(name: &'static str, file_path: impl AsRef<Path>, offset: u64) -> Section {
+fn s(name: String, file_path: impl AsRef<Path>, offset: u64) -> Section {
     Section {
         name,
         file_path: file_path.as_ref().into(),
@@ -171,19 +243,22 @@ fn file_size(path: impl AsRef<Path>) -> Result<u64> {
 
 /// Read the data from a section of a PE binary.
 ///
-/// The binary is supplied as a `u8` slice.
+/// The binary is supplied as a `u8` slice. It might be an arbitrary binary (e.g. the boot loader
+/// of another operating system), so malformed sections are not read instead of panicking.
 pub fn read_section_data<'a>(file_data: &'a [u8], section_name: &str) -> Option<&'a [u8]> {
     let pe_binary = goblin::pe::PE::parse(file_data).ok()?;
 
     pe_binary
         .sections
         .iter()
-        .find(|s| s.name().unwrap() == section_name)
+        .find(|s| s.name().ok() == Some(section_name))
         .and_then(|s| {
             let section_start: usize = s.pointer_to_raw_data.try_into().ok()?;
-            assert!(s.virtual_size <= s.size_of_raw_data);
-            let section_end: usize = section_start + usize::try_from(s.virtual_size).ok()?;
-            Some(&file_data[section_start..section_end])
+            // The virtual size is larger than the raw size if the section is padded with zeros
+            // when it is loaded. Only the raw data is contained in the file.
+            let section_size = s.virtual_size.min(s.size_of_raw_data);
+            let section_end: usize = section_start + usize::try_from(section_size).ok()?;
+            file_data.get(section_start..section_end)
         })
 }
 
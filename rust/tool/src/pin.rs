@@ -0,0 +1,128 @@
+use std::fmt;
+use std::fs;
+use std::path::Path;
+use std::str::FromStr;
+
+use anyhow::{Context, Result};
+
+use crate::error::Error;
+use crate::generation::{GenerationLink, DEFAULT_PROFILE};
+
+/// A pinned generation.
+///
+/// Pinned generations are always installed, regardless of the configuration limit, and thus are
+/// never garbage collected.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Pin {
+    /// Name of the profile or `None` for the default system profile
+    profile: Option<String>,
+    version: u64,
+}
+
+impl Pin {
+    /// Return whether the pin refers to the generation of a link.
+    pub(crate) fn matches(&self, link: &GenerationLink) -> bool {
+        self.profile == link.profile && self.version == link.version
+    }
+}
+
+/// Parse a pin from the format `[PROFILE=]VERSION` (e.g. "42" or "my-server=12").
+impl FromStr for Pin {
+    type Err = Error;
+
+    fn from_str(s: &str) -> crate::Result<Self> {
+        let (profile, version) = match s.split_once('=') {
+            Some((DEFAULT_PROFILE, version)) => (None, version),
+            Some((profile, version)) => (Some(profile.to_string()), version),
+            None => (None, s),
+        };
+        let version = version.parse::<u64>().map_err(|e| {
+            Error::InvalidArgument(format!("Failed to parse pinned generation {s}: {e}"))
+        })?;
+
+        Ok(Self { profile, version })
+    }
+}
+
+impl fmt::Display for Pin {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match &self.profile {
+            Some(profile) => write!(f, "generation {} of profile {profile}", self.version),
+            None => write!(f, "generation {}", self.version),
+        }
+    }
+}
+
+/// Read the pins from a pin file.
+///
+/// The pin file contains one pin per line in the format `[PROFILE=]VERSION`. Empty lines and lines
+/// starting with `#` are ignored. A missing pin file does not contain any pins.
+pub fn read_pin_file(path: &Path) -> Result<Vec<Pin>> {
+    if !path.exists() {
+        log::debug!("Pin file {path:?} does not exist.");
+        return Ok(Vec::new());
+    }
+
+    let pins =
+        fs::read_to_string(path).with_context(|| format!("Failed to read pin file: {path:?}"))?;
+    pins.lines()
+        .map(str::trim)
+        .filter(|line| !line.is_empty() && !line.starts_with('#'))
+        .map(Pin::from_str)
+        .collect::<crate::Result<Vec<Pin>>>()
+        .with_context(|| format!("Failed to parse pin file: {path:?}"))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parse_pins() -> Result<()> {
+        assert_eq!(
+            Pin::from_str("42")?,
+            Pin {
+                profile: None,
+                version: 42
+            }
+        );
+        assert_eq!(
+            Pin::from_str("my-server=12")?,
+            Pin {
+                profile: Some(String::from("my-server")),
+                version: 12
+            }
+        );
+        assert_eq!(Pin::from_str("system=42")?, Pin::from_str("42")?);
+        assert!(Pin::from_str("latest").is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn read_pins_from_file() -> Result<()> {
+        let tmpdir = tempfile::tempdir()?;
+        let pin_file = tmpdir.path().join("pins");
+        fs::write(&pin_file, "# Last known good\n42\n\nmy-server=12\n")?;
+
+        let pins = read_pin_file(&pin_file)?;
+
+        assert_eq!(
+            pins,
+            vec![Pin::from_str("42")?, Pin::from_str("my-server=12")?]
+        );
+        assert!(read_pin_file(&tmpdir.path().join("missing"))?.is_empty());
+        Ok(())
+    }
+
+    #[test]
+    fn match_pins_to_links() -> Result<()> {
+        let link = GenerationLink::from_path("/nix/var/nix/profiles/system-42-link")?;
+        let profile_link =
+            GenerationLink::from_path("/nix/var/nix/profiles/system-profiles/my-server-42-link")?;
+
+        let pin = Pin::from_str("42")?;
+        assert!(pin.matches(&link));
+        assert!(!pin.matches(&profile_link));
+        Ok(())
+    }
+}
@@ -0,0 +1,201 @@
+//! Validation of the inputs of a lanzaboote image before anything is written to the ESP.
+//!
+//! A broken bootspec would otherwise produce an image that only fails at the firmware. These
+//! checks mirror what the stub requires at boot.
+
+use std::fs::{self, File};
+use std::io::Read;
+use std::path::Path;
+
+use anyhow::{bail, Context, Result};
+use goblin::pe::PE;
+
+/// The subsystem of a PE binary that is an EFI application.
+const IMAGE_SUBSYSTEM_EFI_APPLICATION: u16 = 10;
+
+/// The maximum length of the kernel command line if the kernel does not state it.
+///
+/// This is `COMMAND_LINE_SIZE` of arm64 and of x86 kernels without a `cmdline_size` field in their
+/// setup header. It does not include the terminating NUL.
+const DEFAULT_COMMAND_LINE_SIZE: usize = 2048;
+
+/// The magic numbers at the start of initrds the kernel can unpack.
+const INITRD_MAGICS: [&[u8]; 9] = [
+    b"070701",                             // cpio (newc)
+    b"070702",                             // cpio (crc)
+    &[0x1f, 0x8b],                         // gzip
+    &[0xfd, b'7', b'z', b'X', b'Z', 0x00], // xz
+    &[0x28, 0xb5, 0x2f, 0xfd],             // zstd
+    &[0x02, 0x21, 0x4c, 0x18],             // lz4 (legacy)
+    b"BZh",                                // bzip2
+    &[0x5d, 0x00, 0x00],                   // lzma
+    &[0x89, b'L', b'Z', b'O'],             // lzo
+];
+
+/// The number of bytes at the start of an initrd that are read to recognize its format.
+///
+/// This is the length of the longest magic number in [`INITRD_MAGICS`].
+const INITRD_MAGIC_LEN: u64 = 6;
+
+/// Read the machine type of a stub.
+///
+/// The kernels of all generations are checked against it, so it only needs to be read once.
+pub fn stub_machine(stub: &Path) -> Result<u16> {
+    let stub_data = fs::read(stub).with_context(|| format!("Failed to read stub {stub:?}"))?;
+    Ok(PE::parse(&stub_data)
+        .with_context(|| format!("Failed to parse stub {stub:?}"))?
+        .header
+        .coff_header
+        .machine)
+}
+
+/// Check the kernel, the initrds and the command line of a generation.
+///
+/// The kernel has to be built for the machine type of the stub it is booted by (see
+/// [`stub_machine`]).
+pub fn check_generation(
+    machine: u16,
+    kernel: &Path,
+    initrds: &[&Path],
+    kernel_cmdline: &str,
+) -> Result<()> {
+    let kernel_data =
+        fs::read(kernel).with_context(|| format!("Failed to read kernel {kernel:?}"))?;
+    check_kernel(&kernel_data, machine).with_context(|| format!("Invalid kernel {kernel:?}"))?;
+
+    for initrd in initrds {
+        // Only the magic number is checked. Initrds can be large, so the rest is not read.
+        let mut initrd_start = Vec::new();
+        File::open(initrd)
+            .and_then(|file| file.take(INITRD_MAGIC_LEN).read_to_end(&mut initrd_start))
+            .with_context(|| format!("Failed to read initrd {initrd:?}"))?;
+        check_initrd(&initrd_start).with_context(|| format!("Invalid initrd {initrd:?}"))?;
+    }
+
+    check_kernel_cmdline(kernel_cmdline, command_line_size(&kernel_data))
+        .context("Invalid kernel command line")?;
+
+    Ok(())
+}
+
+/// Check that a kernel is an EFI application the stub can load.
+///
+/// The stub loads the kernel itself when Secure Boot is enabled. It does not support base
+/// relocations.
+fn check_kernel(kernel_data: &[u8], machine: u16) -> Result<()> {
+    let pe = PE::parse(kernel_data).context("The kernel is not a PE binary")?;
+
+    let kernel_machine = pe.header.coff_header.machine;
+    if kernel_machine != machine {
+        bail!(
+            "The kernel is built for machine type {} but the stub for {}.",
+            goblin::pe::header::machine_to_str(kernel_machine),
+            goblin::pe::header::machine_to_str(machine)
+        );
+    }
+
+    let Some(optional_header) = pe.header.optional_header else {
+        bail!("The kernel has no optional header.");
+    };
+    if optional_header.windows_fields.subsystem != IMAGE_SUBSYSTEM_EFI_APPLICATION {
+        bail!("The kernel is not an EFI application. Is it built with the EFI stub?");
+    }
+    if optional_header
+        .data_directories
+        .get_base_relocation_table()
+        .is_some()
+    {
+        bail!("The kernel has base relocations, which the stub does not support.");
+    }
+
+    Ok(())
+}
+
+/// Check that an initrd looks like a cpio archive or a compressed cpio archive.
+///
+/// Empty initrds are allowed. The stub does not pass them to the kernel.
+fn check_initrd(initrd_data: &[u8]) -> Result<()> {
+    if initrd_data.is_empty() || INITRD_MAGICS.iter().any(|m| initrd_data.starts_with(m)) {
+        Ok(())
+    } else {
+        bail!("The initrd is neither a cpio archive nor a compressed cpio archive.")
+    }
+}
+
+/// Check that the kernel command line can be passed to the kernel.
+///
+/// The stub passes it as a UCS-2 string, which cannot represent NUL characters and characters
+/// outside the Basic Multilingual Plane.
+fn check_kernel_cmdline(kernel_cmdline: &str, command_line_size: usize) -> Result<()> {
+    if let Some(c) = kernel_cmdline
+        .chars()
+        .find(|&c| c == '\0' || u32::from(c) > 0xffff)
+    {
+        bail!("The kernel command line contains the character {c:?}, which cannot be represented in UCS-2.");
+    }
+
+    if kernel_cmdline.len() > command_line_size {
+        bail!(
+            "The kernel command line is {} bytes long, but the kernel only accepts {command_line_size} bytes.",
+            kernel_cmdline.len()
+        );
+    }
+
+    Ok(())
+}
+
+/// Read the maximum length of the command line from the setup header of an x86 kernel.
+///
+/// See Documentation/arch/x86/boot.rst in the Linux source tree. The `cmdline_size` field is
+/// available since version 2.06 of the boot protocol.
+fn command_line_size(kernel_data: &[u8]) -> usize {
+    let field = |offset: usize, len: usize| kernel_data.get(offset..offset + len);
+
+    let is_setup_header = field(0x202, 4) == Some(b"HdrS");
+    let version = field(0x206, 2).map(|v| u16::from_le_bytes([v[0], v[1]]));
+    let cmdline_size = field(0x238, 4).map(|s| u32::from_le_bytes([s[0], s[1], s[2], s[3]]));
+
+    match (is_setup_header, version, cmdline_size) {
+        (true, Some(version), Some(cmdline_size)) if version >= 0x0206 => {
+            usize::try_from(cmdline_size).unwrap_or(usize::MAX)
+        }
+        _ => DEFAULT_COMMAND_LINE_SIZE,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn reject_kernels_that_are_not_pe_binaries() {
+        assert!(check_kernel(b"not a kernel", goblin::pe::header::COFF_MACHINE_X86_64).is_err());
+    }
+
+    #[test]
+    fn accept_cpio_and_compressed_initrds() {
+        assert!(check_initrd(b"").is_ok());
+        assert!(check_initrd(b"07070100000001").is_ok());
+        assert!(check_initrd(&[0x28, 0xb5, 0x2f, 0xfd, 0x00]).is_ok());
+        assert!(check_initrd(b"#!/bin/sh\n").is_err());
+    }
+
+    #[test]
+    fn reject_unrepresentable_kernel_cmdlines() {
+        assert!(check_kernel_cmdline("init=/nix/store/x/init quiet", 2048).is_ok());
+        assert!(check_kernel_cmdline("init=/init\0", 2048).is_err());
+        assert!(check_kernel_cmdline("splash=🦀", 2048).is_err());
+        assert!(check_kernel_cmdline(&"a".repeat(2049), 2048).is_err());
+    }
+
+    #[test]
+    fn read_command_line_size_from_setup_header() {
+        let mut kernel_data = vec![0; 0x240];
+        assert_eq!(command_line_size(&kernel_data), DEFAULT_COMMAND_LINE_SIZE);
+
+        kernel_data[0x202..0x206].copy_from_slice(b"HdrS");
+        kernel_data[0x206..0x208].copy_from_slice(&0x020f_u16.to_le_bytes());
+        kernel_data[0x238..0x23c].copy_from_slice(&4095_u32.to_le_bytes());
+        assert_eq!(command_line_size(&kernel_data), 4095);
+    }
+}
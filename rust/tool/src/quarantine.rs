@@ -0,0 +1,320 @@
+use std::collections::{BTreeMap, BTreeSet};
+use std::fs;
+use std::num::NonZeroUsize;
+use std::path::{Path, PathBuf};
+
+use anyhow::{Context, Result};
+use serde::{Deserialize, Serialize};
+
+use crate::esp::TOOLS_DIR;
+
+/// The name of the directory to which unknown paths are moved.
+pub const QUARANTINE_DIR: &str = ".quarantine";
+
+/// The name of the file that stores the state of the quarantine inside the quarantine directory.
+const STATE_FILE: &str = "state.json";
+
+/// The prefixes of the files lzbt installs, including those of older versions of lzbt.
+const PRODUCED_PREFIXES: [&str; 3] = ["kernel-", "initrd-", "nixos-"];
+
+/// The length of the hash part of a Nix store path.
+const STORE_PATH_HASH_LENGTH: usize = 32;
+
+/// The persistent state of a quarantine.
+///
+/// All paths are relative to the garbage collected directory.
+#[derive(Debug, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct QuarantineState {
+    /// The paths lzbt installed to the directory during the last install.
+    #[serde(default)]
+    produced: BTreeSet<PathBuf>,
+    /// The quarantined paths with the number of successful installs since they were quarantined.
+    #[serde(default)]
+    quarantined: BTreeMap<PathBuf, usize>,
+}
+
+/// A holding area for unknown paths found during garbage collection.
+///
+/// Paths that lzbt did not produce itself might belong to another operating system. Instead of
+/// deleting them right away, they are moved to a quarantine directory and only deleted after a
+/// number of successful installs. This gives the user the chance to restore them.
+///
+/// Paths that lzbt produced during a previous install are recorded in the state of the quarantine
+/// and can still be deleted immediately.
+pub struct Quarantine {
+    /// The garbage collected directory.
+    directory: PathBuf,
+    /// The directory to which unknown paths are moved.
+    quarantine_directory: PathBuf,
+    /// The number of successful installs after which quarantined paths are deleted.
+    installs: NonZeroUsize,
+    state: QuarantineState,
+    /// The paths that were quarantined during this install.
+    newly_quarantined: Vec<PathBuf>,
+    /// Whether the quarantine has no state yet because it is used for the first time.
+    first_run: bool,
+}
+
+impl Quarantine {
+    /// Open the quarantine of a directory.
+    ///
+    /// A missing state is not an error. In this case, nothing is quarantined yet and the paths in
+    /// the directory that are named like the files lzbt installs are considered to be produced by
+    /// lzbt.
+    pub fn open(directory: impl AsRef<Path>, installs: NonZeroUsize) -> Result<Self> {
+        let directory = directory.as_ref().to_path_buf();
+        let quarantine_directory = directory.join(QUARANTINE_DIR);
+        let state_path = quarantine_directory.join(STATE_FILE);
+
+        let first_run = !state_path.exists();
+        let state = if !first_run {
+            let raw = fs::read(&state_path)
+                .with_context(|| format!("Failed to read quarantine state: {state_path:?}"))?;
+            serde_json::from_slice(&raw)
+                .with_context(|| format!("Failed to parse quarantine state: {state_path:?}"))?
+        } else {
+            QuarantineState::default()
+        };
+
+        Ok(Self {
+            directory,
+            quarantine_directory,
+            installs,
+            state,
+            newly_quarantined: Vec::new(),
+            first_run,
+        })
+    }
+
+    /// Return the directory to which unknown paths are moved.
+    pub fn path(&self) -> &Path {
+        &self.quarantine_directory
+    }
+
+    /// Return whether lzbt produced the path during a previous install.
+    ///
+    /// Without a state, i.e. when the quarantine is enabled for the first time, this is decided by
+    /// the name of the path. Otherwise, every file lzbt installed before would be quarantined.
+    pub fn is_produced(&self, path: &Path) -> bool {
+        path.strip_prefix(&self.directory).is_ok_and(|relative| {
+            self.state.produced.contains(relative)
+                || (self.first_run && is_named_like_produced(relative))
+        })
+    }
+
+    /// Count a successful install for all quarantined paths and delete the ones that have been in
+    /// quarantine for the configured number of installs.
+    pub fn expire(&mut self) -> Result<()> {
+        let installs = self.installs.get();
+        let mut expired = Vec::new();
+
+        for (relative, installs_since_quarantine) in &mut self.state.quarantined {
+            *installs_since_quarantine += 1;
+            if *installs_since_quarantine >= installs {
+                expired.push(relative.clone());
+            }
+        }
+
+        for relative in expired {
+            let path = self.quarantine_directory.join(&relative);
+            log::info!(
+                "Deleting {:?} from quarantine...",
+                self.directory.join(&relative)
+            );
+            remove_path(&path)?;
+            self.state.quarantined.remove(&relative);
+        }
+
+        Ok(())
+    }
+
+    /// Move an unknown path into quarantine.
+    ///
+    /// An older quarantined path at the same location is replaced.
+    pub fn quarantine(&mut self, path: &Path) -> Result<()> {
+        let relative = path
+            .strip_prefix(&self.directory)
+            .with_context(|| format!("Cannot quarantine {path:?} outside of {:?}", self.directory))?
+            .to_path_buf();
+        let destination = self.quarantine_directory.join(&relative);
+
+        if destination.symlink_metadata().is_ok() {
+            remove_path(&destination)?;
+        }
+        if let Some(parent) = destination.parent() {
+            fs::create_dir_all(parent)
+                .with_context(|| format!("Failed to create quarantine directory: {parent:?}"))?;
+        }
+        fs::rename(path, &destination)
+            .with_context(|| format!("Failed to move {path:?} to quarantine {destination:?}"))?;
+
+        self.state.quarantined.insert(relative, 0);
+        self.newly_quarantined.push(path.to_path_buf());
+        Ok(())
+    }
+
+    /// Record the paths lzbt produced during this install, persist the state and report what was
+    /// quarantined.
+    pub fn close<'a>(mut self, produced: impl IntoIterator<Item = &'a PathBuf>) -> Result<()> {
+        self.state.produced = produced
+            .into_iter()
+            .filter_map(|path| path.strip_prefix(&self.directory).ok())
+            .filter(|relative| !relative.as_os_str().is_empty())
+            .map(Path::to_path_buf)
+            .collect();
+
+        fs::create_dir_all(&self.quarantine_directory).with_context(|| {
+            format!(
+                "Failed to create quarantine directory: {:?}",
+                self.quarantine_directory
+            )
+        })?;
+        let state_path = self.quarantine_directory.join(STATE_FILE);
+        let state = serde_json::to_vec_pretty(&self.state)
+            .context("Failed to serialize quarantine state.")?;
+        fs::write(&state_path, state)
+            .with_context(|| format!("Failed to write quarantine state: {state_path:?}"))?;
+
+        if !self.newly_quarantined.is_empty() {
+            let paths = self
+                .newly_quarantined
+                .iter()
+                .map(|path| format!("  {}", path.display()))
+                .collect::<Vec<String>>()
+                .join("\n");
+            let warning = indoc::formatdoc! {"
+                Moved the following unknown paths to {quarantine_directory:?}:
+                {paths}
+
+                They are deleted after {installs} more successful installs. Move them back to keep
+                them.
+            ",
+                quarantine_directory = self.quarantine_directory,
+                installs = self.installs,
+            };
+            log::warn!("{warning}");
+        }
+
+        Ok(())
+    }
+}
+
+/// Return whether a path is named like a file lzbt installs.
+///
+/// These are the kernels and initrds named after their store path (e.g.
+/// `<hash>-linux-6.1.1-bzImage.efi`), the kernels and initrds of older versions of lzbt (e.g.
+/// `kernel-6.1.1-<hash>.efi`), the lanzaboote images and the EFI tools.
+fn is_named_like_produced(relative: &Path) -> bool {
+    if relative.starts_with(TOOLS_DIR) {
+        return true;
+    }
+
+    let Some(file_name) = relative.to_str() else {
+        return false;
+    };
+    if !file_name.ends_with(".efi") {
+        return false;
+    }
+
+    let is_store_path_name = file_name.len() > STORE_PATH_HASH_LENGTH
+        && file_name.as_bytes()[STORE_PATH_HASH_LENGTH] == b'-'
+        && file_name[..STORE_PATH_HASH_LENGTH]
+            .chars()
+            .all(|c| c.is_ascii_digit() || c.is_ascii_lowercase());
+
+    is_store_path_name
+        || PRODUCED_PREFIXES
+            .iter()
+            .any(|prefix| file_name.starts_with(prefix))
+}
+
+/// Remove a file or a directory with all its contents.
+fn remove_path(path: &Path) -> Result<()> {
+    if path.is_dir() {
+        fs::remove_dir_all(path).with_context(|| format!("Failed to remove directory: {path:?}"))
+    } else {
+        fs::remove_file(path).with_context(|| format!("Failed to remove file: {path:?}"))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn persist_state() -> Result<()> {
+        let tmpdir = tempfile::tempdir()?;
+        let directory = tmpdir.path();
+        let installs = NonZeroUsize::new(2).unwrap();
+
+        let unknown_file = directory.join("unknown_file");
+        fs::File::create(&unknown_file)?;
+        let produced_file = directory.join("produced_file");
+
+        let mut quarantine = Quarantine::open(directory, installs)?;
+        quarantine.quarantine(&unknown_file)?;
+        quarantine.close([&produced_file])?;
+
+        let quarantine = Quarantine::open(directory, installs)?;
+        assert!(quarantine.is_produced(&produced_file));
+        assert!(!quarantine.is_produced(&unknown_file));
+        assert_eq!(
+            quarantine.state.quarantined.get(Path::new("unknown_file")),
+            Some(&0)
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn recognize_produced_paths_on_first_run() -> Result<()> {
+        let tmpdir = tempfile::tempdir()?;
+        let directory = tmpdir.path();
+        let installs = NonZeroUsize::new(2).unwrap();
+
+        let kernel = directory.join("4jyjspp6f0mi8hpnxdm9m0wgqrxc0ffs-linux-6.1.38-bzImage.efi");
+        let old_kernel = directory.join("kernel-6.1.1-2wsr9aq4ldaw4h6a5blrxzsa0fz4fmm0.efi");
+        let unknown_file = directory.join("other-os.efi");
+
+        let quarantine = Quarantine::open(directory, installs)?;
+        assert!(quarantine.is_produced(&kernel));
+        assert!(quarantine.is_produced(&old_kernel));
+        assert!(!quarantine.is_produced(&unknown_file));
+        quarantine.close([])?;
+
+        // Once there is a state, only the recorded paths are considered produced.
+        let quarantine = Quarantine::open(directory, installs)?;
+        assert!(!quarantine.is_produced(&kernel));
+        Ok(())
+    }
+
+    #[test]
+    fn delete_after_configured_installs() -> Result<()> {
+        let tmpdir = tempfile::tempdir()?;
+        let directory = tmpdir.path();
+        let installs = NonZeroUsize::new(2).unwrap();
+
+        let unknown_directory = directory.join("unknown_directory");
+        fs::create_dir(&unknown_directory)?;
+        fs::File::create(unknown_directory.join("unknown_file"))?;
+        let quarantined_directory = directory.join(QUARANTINE_DIR).join("unknown_directory");
+
+        let mut quarantine = Quarantine::open(directory, installs)?;
+        quarantine.expire()?;
+        quarantine.quarantine(&unknown_directory)?;
+        quarantine.close([])?;
+        assert!(!unknown_directory.exists());
+        assert!(quarantined_directory.join("unknown_file").exists());
+
+        let mut quarantine = Quarantine::open(directory, installs)?;
+        quarantine.expire()?;
+        quarantine.close([])?;
+        assert!(quarantined_directory.exists());
+
+        let mut quarantine = Quarantine::open(directory, installs)?;
+        quarantine.expire()?;
+        quarantine.close([])?;
+        assert!(!quarantined_directory.exists());
+        Ok(())
+    }
+}
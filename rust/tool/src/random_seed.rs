@@ -0,0 +1,138 @@
+use std::fs;
+use std::io::{self, Read, Write};
+use std::os::unix::fs::{OpenOptionsExt, PermissionsExt};
+use std::path::Path;
+
+use anyhow::{Context, Result};
+use sha2::{Digest, Sha256};
+
+use crate::efivars;
+
+/// The size of the random seed on the ESP and of the system token in bytes.
+///
+/// This is the size systemd-boot expects. It is the size of a SHA-256 digest.
+const RANDOM_SEED_SIZE: usize = 32;
+
+/// The attributes of the system token: non-volatile, boot service and runtime access.
+const SYSTEM_TOKEN_ATTRIBUTES: u32 = 0x7;
+
+/// Create or refresh the random seed systemd-boot reads from the ESP.
+///
+/// Like `bootctl random-seed`, an existing seed is not simply replaced but hashed together with
+/// fresh random data. This way, the new seed is at least as good as the old one even if the
+/// kernel's entropy pool is not yet initialized.
+///
+/// The seed is written atomically. It is created with mode 0o600, but on a vfat ESP the
+/// permissions come from the mount options. Thus, a seed that is readable by others is only warned
+/// about.
+pub fn refresh_random_seed(path: &Path) -> Result<()> {
+    let fresh = random_bytes()?;
+    let seed = match fs::read(path) {
+        Ok(old) => Sha256::new()
+            .chain_update(&old)
+            .chain_update(fresh)
+            .finalize()
+            .to_vec(),
+        Err(e) if e.kind() == io::ErrorKind::NotFound => fresh.to_vec(),
+        Err(e) => return Err(e).with_context(|| format!("Failed to read random seed {path:?}")),
+    };
+
+    if let Some(parent) = path.parent() {
+        fs::create_dir_all(parent)
+            .with_context(|| format!("Failed to create directory {parent:?}"))?;
+    }
+
+    let path_tmp = path.with_extension("tmp");
+    let mut file = fs::OpenOptions::new()
+        .write(true)
+        .create(true)
+        .truncate(true)
+        .mode(0o600)
+        .open(&path_tmp)
+        .with_context(|| format!("Failed to create random seed {path_tmp:?}"))?;
+    if file
+        .metadata()
+        .is_ok_and(|metadata| metadata.permissions().mode() & 0o077 != 0)
+    {
+        log::warn!(
+            "The random seed {path:?} is readable by users other than root. Mount the ESP with a restrictive umask, e.g. umask=0077."
+        );
+    }
+    file.write_all(&seed)
+        .and_then(|_| file.sync_all())
+        .with_context(|| format!("Failed to write random seed {path_tmp:?}"))?;
+
+    fs::rename(&path_tmp, path).with_context(|| {
+        format!("Failed to move temporary file {path_tmp:?} to final location {path:?}")
+    })
+}
+
+/// Create the system token in efivarfs if it does not exist yet.
+///
+/// systemd-boot only passes the random seed on the ESP to the OS if the `LoaderSystemToken`
+/// variable is set. The token identifies the installation so that machines installed from the
+/// same image do not end up with the same seed. Thus, an existing token is never replaced.
+pub fn ensure_system_token(efivarfs: &Path) -> Result<()> {
+    let name = "LoaderSystemToken";
+    if let Some(token) = efivars::read_loader_variable_data(efivarfs, name)? {
+        if token.len() < RANDOM_SEED_SIZE {
+            log::warn!(
+                "The system token is only {} bytes long. Remove the {name} EFI variable to have lzbt create a new one.",
+                token.len()
+            );
+        }
+        return Ok(());
+    }
+
+    efivars::write_loader_variable_data(efivarfs, name, SYSTEM_TOKEN_ATTRIBUTES, &random_bytes()?)?;
+    log::info!("Created the system token.");
+    Ok(())
+}
+
+/// Read random bytes from the kernel.
+fn random_bytes() -> Result<[u8; RANDOM_SEED_SIZE]> {
+    let mut bytes = [0; RANDOM_SEED_SIZE];
+    fs::File::open("/dev/urandom")
+        .and_then(|mut urandom| urandom.read_exact(&mut bytes))
+        .context("Failed to read random data from /dev/urandom")?;
+    Ok(bytes)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn refresh_existing_random_seed() -> Result<()> {
+        let tmpdir = tempfile::tempdir()?;
+        let path = tmpdir.path().join("loader/random-seed");
+
+        refresh_random_seed(&path)?;
+        let seed = fs::read(&path)?;
+        assert_eq!(seed.len(), RANDOM_SEED_SIZE);
+        assert_eq!(fs::metadata(&path)?.permissions().mode() & 0o777, 0o600);
+
+        refresh_random_seed(&path)?;
+        let refreshed_seed = fs::read(&path)?;
+        assert_eq!(refreshed_seed.len(), RANDOM_SEED_SIZE);
+        assert_ne!(refreshed_seed, seed);
+        Ok(())
+    }
+
+    #[test]
+    fn keep_existing_system_token() -> Result<()> {
+        let efivarfs = tempfile::tempdir()?;
+
+        ensure_system_token(efivarfs.path())?;
+        let token = efivars::read_loader_variable_data(efivarfs.path(), "LoaderSystemToken")?
+            .expect("System token was not created");
+        assert_eq!(token.len(), RANDOM_SEED_SIZE);
+
+        ensure_system_token(efivarfs.path())?;
+        assert_eq!(
+            efivars::read_loader_variable_data(efivarfs.path(), "LoaderSystemToken")?,
+            Some(token)
+        );
+        Ok(())
+    }
+}
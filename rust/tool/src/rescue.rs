@@ -0,0 +1,131 @@
+use std::fs;
+use std::path::{Path, PathBuf};
+
+use anyhow::{Context, Result};
+use serde::{Deserialize, Serialize};
+use walkdir::WalkDir;
+
+use crate::esp::{self, EspPaths};
+use crate::generation::{BuildTime, GenerationLink};
+
+/// The record of the generation the installed rescue image was built from.
+#[derive(Debug, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct RescueState {
+    /// The resolved toplevel of the generation.
+    pub toplevel: PathBuf,
+    /// The build time of the generation as a Unix timestamp if it is known.
+    pub build_time: Option<i64>,
+}
+
+impl RescueState {
+    /// Read the record from the ESP.
+    ///
+    /// Returns `None` if there is no record, e.g. because no rescue image is installed.
+    pub fn read(path: &Path) -> Result<Option<Self>> {
+        if !path.exists() {
+            return Ok(None);
+        }
+        let raw =
+            fs::read(path).with_context(|| format!("Failed to read rescue state: {path:?}"))?;
+        serde_json::from_slice(&raw)
+            .map(Some)
+            .with_context(|| format!("Failed to parse rescue state: {path:?}"))
+    }
+
+    /// Write the record to the ESP.
+    pub fn write(&self, path: &Path) -> Result<()> {
+        if let Some(parent) = path.parent() {
+            fs::create_dir_all(parent)
+                .with_context(|| format!("Failed to create directory {parent:?}"))?;
+        }
+        let raw = serde_json::to_vec_pretty(self).context("Failed to serialize rescue state")?;
+        fs::write(path, raw).with_context(|| format!("Failed to write rescue state: {path:?}"))
+    }
+
+    /// Return whether the rescue image should be rebuilt from the generation recorded in `other`.
+    ///
+    /// A generation with a different toplevel replaces the rescue image unless it is known to be
+    /// older.
+    pub fn is_replaced_by(&self, other: &Self) -> bool {
+        if self.toplevel == other.toplevel {
+            return false;
+        }
+        match (self.build_time, other.build_time) {
+            (Some(installed), Some(target)) => target > installed,
+            _ => true,
+        }
+    }
+}
+
+/// Create a link for the target of the rescue image.
+///
+/// The target is either a generation link (e.g. /nix/var/nix/profiles/system-42-link) or a
+/// toplevel. A toplevel does not have a version. It is installed as version 0.
+pub fn rescue_link(target: &Path) -> GenerationLink {
+    GenerationLink::from_path(target).unwrap_or_else(|_| GenerationLink {
+        version: 0,
+        profile: None,
+        path: target.to_path_buf(),
+        // The modification time of a store path carries no information.
+        build_time: None,
+        pinned: false,
+        booted: false,
+    })
+}
+
+/// Create the record for a rescue image built from a link.
+pub fn rescue_state(link: &GenerationLink) -> Result<RescueState> {
+    Ok(RescueState {
+        toplevel: fs::canonicalize(&link.path)
+            .with_context(|| format!("Failed to resolve toplevel of {:?}", link.path))?,
+        build_time: link
+            .build_time
+            .as_ref()
+            .map(BuildTime::time)
+            .map(|time| time.unix_timestamp()),
+    })
+}
+
+/// Return the files on the ESP that belong to the installed rescue image.
+///
+/// These are the kernel and the initrds from the `.kernelp` and `.initrdp` sections of the image
+/// and its credentials. Fat images do not reference a kernel or initrds.
+pub fn referenced_files(esp_paths: &EspPaths) -> Result<Vec<PathBuf>> {
+    let image = &esp_paths.rescue_image;
+
+    let mut files = esp::referenced_paths(&esp_paths.esp, image)?;
+
+    let credentials = esp::credentials_path(image);
+    if credentials.exists() {
+        for entry in WalkDir::new(&credentials) {
+            let entry =
+                entry.with_context(|| format!("Failed to read directory {credentials:?}"))?;
+            files.push(entry.into_path());
+        }
+    }
+
+    Ok(files)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn state(toplevel: &str, build_time: Option<i64>) -> RescueState {
+        RescueState {
+            toplevel: PathBuf::from(toplevel),
+            build_time,
+        }
+    }
+
+    #[test]
+    fn only_replace_with_newer_generations() {
+        let installed = state("/nix/store/a-nixos-system", Some(100));
+
+        assert!(!installed.is_replaced_by(&state("/nix/store/a-nixos-system", Some(200))));
+        assert!(installed.is_replaced_by(&state("/nix/store/b-nixos-system", Some(200))));
+        assert!(!installed.is_replaced_by(&state("/nix/store/b-nixos-system", Some(50))));
+        assert!(installed.is_replaced_by(&state("/nix/store/b-nixos-system", None)));
+    }
+}
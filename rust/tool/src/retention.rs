@@ -0,0 +1,246 @@
+use std::num::NonZeroUsize;
+
+use time::{Duration, OffsetDateTime};
+
+use crate::generation::GenerationLink;
+
+/// Retention rules in addition to the configuration limit.
+///
+/// Pinned generations and the booted generation are never dropped by any rule.
+#[derive(Debug, Clone, Default)]
+pub struct RetentionPolicy {
+    /// Keep generations built within this number of days even if they exceed the configuration
+    /// limit.
+    pub keep_days: Option<u64>,
+    /// The maximum number of images to install, counting each specialisation as an image.
+    pub max_images: Option<NonZeroUsize>,
+    /// Only install the specialisations of this number of the newest generations of each profile.
+    pub specialisations_limit: Option<usize>,
+}
+
+/// A generation that is a candidate for installation with the number of its images.
+#[derive(Debug, Clone)]
+pub struct ImageCandidate {
+    /// Name of the profile or `None` for the default system profile
+    pub profile: Option<String>,
+    pub version: u64,
+    pub build_time: Option<OffsetDateTime>,
+    /// The number of images of the generation, i.e. one plus the number of its specialisations
+    pub images: usize,
+    /// Whether the generation must not be dropped
+    pub protected: bool,
+}
+
+impl RetentionPolicy {
+    /// Select the links of a single profile to install.
+    ///
+    /// Expects the links to be sorted by version and returns them in the same order. The newest
+    /// `configuration_limit` generations are kept. A configuration limit of 0 means there is no
+    /// limit. Pinned generations and the booted generation are kept in addition and do not count
+    /// towards the limit. Generations newer than `keep_days` are kept even if they exceed the
+    /// limit.
+    pub(crate) fn select_links(
+        &self,
+        mut links: Vec<GenerationLink>,
+        configuration_limit: usize,
+        now: OffsetDateTime,
+    ) -> Vec<GenerationLink> {
+        if configuration_limit == 0 {
+            return links;
+        }
+
+        let keep_newer_than = self
+            .keep_days
+            .map(|days| now - Duration::days(days.try_into().unwrap_or(i64::MAX)));
+        let is_kept = |link: &GenerationLink| link.pinned || link.booted;
+        let is_recent = |link: &GenerationLink| {
+            keep_newer_than.is_some_and(|keep_newer_than| {
+                link.build_time
+                    .is_some_and(|build_time| build_time.time() > keep_newer_than)
+            })
+        };
+
+        // Because the links are sorted, the oldest other generations are skipped.
+        let mut other_to_skip = links
+            .iter()
+            .filter(|link| !is_kept(link))
+            .count()
+            .saturating_sub(configuration_limit);
+        links.retain(|link| {
+            if is_kept(link) || other_to_skip == 0 {
+                true
+            } else if is_recent(link) {
+                other_to_skip -= 1;
+                true
+            } else {
+                other_to_skip -= 1;
+                let age = match (self.keep_days, link.build_time.is_some()) {
+                    (Some(days), true) => format!(" and is older than {days} days"),
+                    (Some(_), false) => " and its build time is unknown".to_string(),
+                    (None, _) => String::new(),
+                };
+                log::info!(
+                    "Dropping {}: exceeds the configuration limit of {configuration_limit}{age}.",
+                    describe(link.profile.as_deref(), link.version),
+                );
+                false
+            }
+        });
+
+        links
+    }
+
+    /// Return whether the specialisations of a generation are installed.
+    ///
+    /// `rank` is the position of the generation among the generations of its profile, starting
+    /// with 0 for the newest one.
+    pub(crate) fn installs_specialisations(&self, rank: usize) -> bool {
+        self.specialisations_limit
+            .is_none_or(|specialisations_limit| rank < specialisations_limit)
+    }
+
+    /// Select the generations to install so that at most `max_images` images are installed.
+    ///
+    /// Returns whether each candidate is kept. The oldest generations are dropped first.
+    /// Protected generations are never dropped, even if this exceeds the maximum.
+    pub(crate) fn select_images(&self, candidates: &[ImageCandidate]) -> Vec<bool> {
+        let mut kept = vec![true; candidates.len()];
+        let Some(max_images) = self.max_images.map(NonZeroUsize::get) else {
+            return kept;
+        };
+
+        let mut images = candidates.iter().map(|c| c.images).sum::<usize>();
+        let mut by_age = (0..candidates.len()).collect::<Vec<usize>>();
+        by_age.sort_by_key(|i| (candidates[*i].build_time, candidates[*i].version));
+
+        for i in by_age {
+            if images <= max_images {
+                break;
+            }
+            let candidate = &candidates[i];
+            if candidate.protected {
+                continue;
+            }
+
+            kept[i] = false;
+            images -= candidate.images;
+            log::info!(
+                "Dropping {}: exceeds the maximum of {max_images} images.",
+                describe(candidate.profile.as_deref(), candidate.version)
+            );
+        }
+
+        if images > max_images {
+            log::warn!(
+                "Installing {images} images, which exceeds the maximum of {max_images} images, to keep pinned, booted and latest generations."
+            );
+        }
+
+        kept
+    }
+}
+
+/// Describe a generation for log messages.
+pub fn describe(profile: Option<&str>, version: u64) -> String {
+    match profile {
+        Some(profile) => format!("generation {version} of profile {profile}"),
+        None => format!("generation {version}"),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use crate::generation::BuildTime;
+
+    fn link(version: u64, days_old: i64, now: OffsetDateTime) -> GenerationLink {
+        let mut link =
+            GenerationLink::from_path(format!("/nix/var/nix/profiles/system-{version}-link"))
+                .unwrap();
+        link.build_time = Some(BuildTime::Registered(now - Duration::days(days_old)));
+        link
+    }
+
+    fn versions(links: &[GenerationLink]) -> Vec<u64> {
+        links.iter().map(|link| link.version).collect()
+    }
+
+    #[test]
+    fn keep_latest_and_recent_generations() {
+        let now = OffsetDateTime::now_utc();
+        let links = vec![
+            link(1, 30, now),
+            link(2, 20, now),
+            link(3, 5, now),
+            link(4, 1, now),
+        ];
+
+        let policy = RetentionPolicy::default();
+        assert_eq!(versions(&policy.select_links(links.clone(), 1, now)), [4]);
+        assert_eq!(
+            versions(&policy.select_links(links.clone(), 0, now)),
+            [1, 2, 3, 4]
+        );
+
+        let policy = RetentionPolicy {
+            keep_days: Some(10),
+            ..Default::default()
+        };
+        assert_eq!(versions(&policy.select_links(links, 1, now)), [3, 4]);
+    }
+
+    #[test]
+    fn keep_pinned_and_booted_generations() {
+        let now = OffsetDateTime::now_utc();
+        let mut links = vec![link(1, 30, now), link(2, 20, now), link(3, 5, now)];
+        links[0].pinned = true;
+        links[1].booted = true;
+
+        let policy = RetentionPolicy::default();
+        assert_eq!(versions(&policy.select_links(links, 1, now)), [1, 2, 3]);
+    }
+
+    #[test]
+    fn drop_oldest_generations_for_max_images() {
+        let candidate = |version, images, protected| ImageCandidate {
+            profile: None,
+            version,
+            build_time: None,
+            images,
+            protected,
+        };
+        let candidates = [
+            candidate(1, 1, true),
+            candidate(2, 3, false),
+            candidate(3, 1, false),
+            candidate(4, 3, true),
+        ];
+
+        let policy = RetentionPolicy {
+            max_images: NonZeroUsize::new(5),
+            ..Default::default()
+        };
+        assert_eq!(policy.select_images(&candidates), [true, false, true, true]);
+
+        let policy = RetentionPolicy {
+            max_images: NonZeroUsize::new(1),
+            ..Default::default()
+        };
+        assert_eq!(
+            policy.select_images(&candidates),
+            [true, false, false, true]
+        );
+    }
+
+    #[test]
+    fn limit_specialisations() {
+        let policy = RetentionPolicy {
+            specialisations_limit: Some(1),
+            ..Default::default()
+        };
+        assert!(policy.installs_specialisations(0));
+        assert!(!policy.installs_specialisations(1));
+        assert!(RetentionPolicy::default().installs_specialisations(5));
+    }
+}
@@ -5,12 +5,18 @@ use std::process::Command;
 
 use anyhow::{Context, Result};
 
+/// The key pair lzbt signs the binaries on the ESP with.
+///
+/// Signing and verifying use `sbsign` and `sbverify`, which have to be on PATH.
 pub struct KeyPair {
+    /// The private key in PEM format.
     pub private_key: PathBuf,
+    /// The certificate of the public key in PEM format.
     pub public_key: PathBuf,
 }
 
 impl KeyPair {
+    /// Create a key pair from the paths of the public key certificate and the private key.
     pub fn new(public_key: &Path, private_key: &Path) -> Self {
         Self {
             public_key: public_key.into(),
@@ -18,7 +24,7 @@ impl KeyPair {
         }
     }
 
-    pub fn sign_and_copy(&self, from: &Path, to: &Path) -> Result<()> {
+    pub(crate) fn sign_and_copy(&self, from: &Path, to: &Path) -> Result<()> {
         let args: Vec<OsString> = vec![
             OsString::from("--key"),
             self.private_key.clone().into(),
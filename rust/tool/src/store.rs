@@ -0,0 +1,143 @@
+use std::collections::HashMap;
+use std::path::{Path, PathBuf};
+use std::process::Command;
+
+use anyhow::{anyhow, Context, Result};
+use serde_json::Value;
+use time::OffsetDateTime;
+
+/// Read the times at which store paths were registered in the Nix store.
+///
+/// This is when a path was built on or copied to this machine, not necessarily when it was built.
+/// Unlike the modification time of a generation link, it does not change when a profile is copied.
+/// Paths without a registration time (e.g. because they are not valid) are missing from the
+/// returned map.
+pub fn registration_times(
+    nix: &Path,
+    paths: &[PathBuf],
+) -> Result<HashMap<PathBuf, OffsetDateTime>> {
+    if paths.is_empty() {
+        return Ok(HashMap::new());
+    }
+
+    let nix_bin = nix.join("bin/nix");
+    if let Some(path_info) = query_path_info(&nix_bin, paths)? {
+        return parse_registration_times(&path_info);
+    }
+
+    // A single invalid path fails the whole query. Query the paths one by one so that only the
+    // invalid paths miss their registration time.
+    let mut registration_times = HashMap::new();
+    for path in paths {
+        match query_path_info(&nix_bin, std::slice::from_ref(path))? {
+            Some(path_info) => registration_times.extend(parse_registration_times(&path_info)?),
+            None => log::debug!("Failed to query the path info of {path:?}."),
+        }
+    }
+    Ok(registration_times)
+}
+
+/// Run `nix path-info --json` for a list of paths.
+///
+/// Returns `None` if Nix fails to query the paths, e.g. because one of them is not valid.
+fn query_path_info(nix_bin: &Path, paths: &[PathBuf]) -> Result<Option<Value>> {
+    let output = Command::new(nix_bin)
+        .args([
+            "--extra-experimental-features",
+            "nix-command",
+            "path-info",
+            "--json",
+        ])
+        .args(paths)
+        .output()
+        .with_context(|| format!("Failed to run {nix_bin:?}."))?;
+    if !output.status.success() {
+        log::debug!(
+            "Failed to query path info: {}",
+            String::from_utf8_lossy(&output.stderr)
+        );
+        return Ok(None);
+    }
+
+    serde_json::from_slice(&output.stdout)
+        .map(Some)
+        .context("Failed to parse path info JSON.")
+}
+
+/// Extract the registration times from the JSON output of `nix path-info --json`.
+///
+/// Older versions of Nix return a list of objects that each contain their path. Newer versions
+/// return an object keyed by path.
+fn parse_registration_times(path_info: &Value) -> Result<HashMap<PathBuf, OffsetDateTime>> {
+    let entries: Vec<(&str, &Value)> = match path_info {
+        Value::Array(entries) => entries
+            .iter()
+            .filter_map(|entry| Some((entry.get("path")?.as_str()?, entry)))
+            .collect(),
+        Value::Object(entries) => entries
+            .iter()
+            .map(|(path, entry)| (path.as_str(), entry))
+            .collect(),
+        _ => return Err(anyhow!("Unexpected path info JSON: {path_info}")),
+    };
+
+    let mut registration_times = HashMap::new();
+    for (path, entry) in entries {
+        let Some(registration_time) = entry
+            .get("registrationTime")
+            .and_then(Value::as_i64)
+            .filter(|t| *t > 0)
+        else {
+            continue;
+        };
+
+        registration_times.insert(
+            PathBuf::from(path),
+            OffsetDateTime::from_unix_timestamp(registration_time)
+                .with_context(|| format!("Invalid registration time for {path}"))?,
+        );
+    }
+
+    Ok(registration_times)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use serde_json::json;
+
+    #[test]
+    fn parse_registration_times_from_list() -> Result<()> {
+        let path_info = json!([
+            { "path": "/nix/store/xxx-nixos-system", "registrationTime": 1672531200 },
+            { "path": "/nix/store/yyy-nixos-system", "valid": false },
+        ]);
+
+        let registration_times = parse_registration_times(&path_info)?;
+
+        assert_eq!(registration_times.len(), 1);
+        assert_eq!(
+            registration_times[Path::new("/nix/store/xxx-nixos-system")].unix_timestamp(),
+            1672531200
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn parse_registration_times_from_object() -> Result<()> {
+        let path_info = json!({
+            "/nix/store/xxx-nixos-system": { "registrationTime": 1672531200 },
+            "/nix/store/yyy-nixos-system": null,
+        });
+
+        let registration_times = parse_registration_times(&path_info)?;
+
+        assert_eq!(registration_times.len(), 1);
+        assert_eq!(
+            registration_times[Path::new("/nix/store/xxx-nixos-system")].unix_timestamp(),
+            1672531200
+        );
+        Ok(())
+    }
+}
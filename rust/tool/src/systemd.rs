@@ -1,10 +1,12 @@
 use std::ffi::CStr;
+use std::fmt;
 use std::fs;
 use std::path::Path;
 use std::str::FromStr;
 
 use anyhow::{Context, Result};
 
+use crate::error::Error;
 use crate::os_release::OsRelease;
 use crate::pe;
 
@@ -12,12 +14,22 @@ use crate::pe;
 ///
 /// The version is parsed into a u32 tuple because systemd does not follow strict semver
 /// conventions. A major version without a minor version, e.g. "252" is represented as `(252, 0)`.
-#[derive(PartialEq, PartialOrd, Eq, Debug)]
-pub struct SystemdVersion(u32, u32);
+/// Release candidates (e.g. "253-rc1" or "253~rc1") are ordered before the release.
+#[derive(PartialEq, PartialOrd, Eq, Ord, Debug, Clone)]
+pub struct SystemdVersion(u32, u32, Stage);
+
+/// The stage of a systemd release.
+///
+/// The order of the variants is significant: a release candidate is older than the release.
+#[derive(PartialEq, PartialOrd, Eq, Ord, Debug, Clone, Copy)]
+enum Stage {
+    ReleaseCandidate(u32),
+    Stable,
+}
 
 impl SystemdVersion {
     /// Read the systemd version from the `.osrel` section of a systemd-boot binary.
-    pub fn from_systemd_boot_binary(path: &Path) -> Result<Self> {
+    pub(crate) fn from_systemd_boot_binary(path: &Path) -> Result<Self> {
         let file_data = fs::read(path).with_context(|| format!("Failed to read file {path:?}"))?;
         let section_data = pe::read_section_data(&file_data, ".osrel")
             .with_context(|| format!("PE section '.osrel' is empty: {path:?}"))?;
@@ -38,27 +50,136 @@ impl SystemdVersion {
             .get("VERSION")
             .context("Failed to extract VERSION key from: {os_release:#?}")?;
 
-        Self::from_str(version_str)
+        Ok(Self::from_str(version_str)?)
     }
 }
 
 impl FromStr for SystemdVersion {
-    type Err = anyhow::Error;
+    type Err = Error;
 
     fn from_str(s: &str) -> Result<Self, Self::Err> {
-        let split_version = s
+        let invalid = || Error::InvalidArgument(format!("Failed to parse systemd version: {s}"));
+
+        let (version, stage) = match s.split_once(['-', '~']) {
+            Some((version, suffix)) => {
+                let release_candidate = suffix
+                    .strip_prefix("rc")
+                    .and_then(|rc| rc.parse::<u32>().ok())
+                    .ok_or_else(invalid)?;
+                (version, Stage::ReleaseCandidate(release_candidate))
+            }
+            None => (s, Stage::Stable),
+        };
+
+        let split_version = version
             .split('.')
             .take(2)
             .map(u32::from_str)
             .collect::<Result<Vec<u32>, std::num::ParseIntError>>()
-            .context("Failed to parse version string into u32 vector.")?;
+            .map_err(|_| invalid())?;
 
-        let major = split_version
-            .first()
-            .context("Failed to parse major version.")?;
+        let major = split_version.first().ok_or_else(invalid)?;
         let minor = split_version.get(1).unwrap_or(&0);
 
-        Ok(Self(major.to_owned(), minor.to_owned()))
+        Ok(Self(major.to_owned(), minor.to_owned(), stage))
+    }
+}
+
+impl fmt::Display for SystemdVersion {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self.1 {
+            0 => write!(f, "{}", self.0)?,
+            minor => write!(f, "{}.{minor}", self.0)?,
+        }
+        match self.2 {
+            Stage::ReleaseCandidate(release_candidate) => write!(f, "-rc{release_candidate}"),
+            Stage::Stable => Ok(()),
+        }
+    }
+}
+
+/// systemd-boot versions that lzbt refuses to install together with the reason.
+///
+/// Add a release here when it is known to break booting with lanzaboote. The reason is shown to
+/// the user and should say what is broken and which version to use instead.
+///
+/// No systemd-boot release is known to break booting with lanzaboote yet, so the list is empty.
+/// Users can refuse further versions with `--deny-systemd-boot-version`.
+const DENYLIST: &[(&str, &str)] = &[];
+
+/// What to do with an installed systemd-boot binary.
+#[derive(Debug, PartialEq, Eq)]
+pub(crate) enum SystemdBootAction {
+    /// Install the available systemd-boot.
+    Install,
+    /// Keep the installed systemd-boot.
+    Keep,
+    /// Do not install the available systemd-boot for the given reason.
+    Refuse(String),
+}
+
+/// Decides which systemd-boot version is installed.
+///
+/// By default, systemd-boot is only updated to newer versions. Versions on the shipped denylist or
+/// denied by the user are never installed.
+#[derive(Debug, Clone, Default)]
+pub struct SystemdBootPolicy {
+    /// Only install exactly this version of systemd-boot.
+    pub pinned_version: Option<SystemdVersion>,
+    /// Install the available systemd-boot even if it is older than the installed one.
+    pub allow_downgrade: bool,
+    /// Versions to refuse in addition to the shipped denylist.
+    pub denied_versions: Vec<SystemdVersion>,
+}
+
+impl SystemdBootPolicy {
+    /// Decide whether to install the available systemd-boot over the installed one.
+    ///
+    /// `installed` is `None` if systemd-boot is not installed or its version cannot be read.
+    pub(crate) fn decide(
+        &self,
+        available: &SystemdVersion,
+        installed: Option<&SystemdVersion>,
+    ) -> SystemdBootAction {
+        if let Some(reason) = self.denial_reason(available) {
+            return SystemdBootAction::Refuse(reason);
+        }
+        if let Some(pinned_version) = &self.pinned_version {
+            if available != pinned_version {
+                return SystemdBootAction::Refuse(format!(
+                    "systemd-boot is pinned to version {pinned_version}."
+                ));
+            }
+        }
+
+        match installed {
+            None => SystemdBootAction::Install,
+            Some(installed) if available > installed => SystemdBootAction::Install,
+            // An explicitly pinned version is installed even if it is a downgrade.
+            Some(installed)
+                if available < installed
+                    && (self.allow_downgrade || self.pinned_version.is_some()) =>
+            {
+                SystemdBootAction::Install
+            }
+            Some(_) => SystemdBootAction::Keep,
+        }
+    }
+
+    /// Return why a version must not be installed or `None` if it may be installed.
+    fn denial_reason(&self, version: &SystemdVersion) -> Option<String> {
+        let shipped = DENYLIST.iter().find_map(|(denied_version, reason)| {
+            let denied_version = SystemdVersion::from_str(denied_version)
+                .expect("Failed to parse systemd version on the denylist.");
+            (&denied_version == version)
+                .then(|| format!("systemd-boot {version} is on the denylist of lzbt: {reason}"))
+        });
+
+        shipped.or_else(|| {
+            self.denied_versions
+                .contains(version)
+                .then(|| format!("systemd-boot {version} is denied by the configuration."))
+        })
     }
 }
 
@@ -68,9 +189,20 @@ mod tests {
 
     #[test]
     fn parse_version_correctly() {
-        assert_eq!(parse_version("253"), SystemdVersion(253, 0));
-        assert_eq!(parse_version("252.4"), SystemdVersion(252, 4));
-        assert_eq!(parse_version("251.11"), SystemdVersion(251, 11));
+        assert_eq!(parse_version("253"), SystemdVersion(253, 0, Stage::Stable));
+        assert_eq!(
+            parse_version("252.4"),
+            SystemdVersion(252, 4, Stage::Stable)
+        );
+        assert_eq!(
+            parse_version("251.11"),
+            SystemdVersion(251, 11, Stage::Stable)
+        );
+        assert_eq!(
+            parse_version("253-rc1"),
+            SystemdVersion(253, 0, Stage::ReleaseCandidate(1))
+        );
+        assert_eq!(parse_version("254~rc2"), parse_version("254-rc2"));
     }
 
     #[test]
@@ -78,6 +210,9 @@ mod tests {
         assert!(parse_version("253") > parse_version("252"));
         assert!(parse_version("253") > parse_version("252.4"));
         assert!(parse_version("251.8") == parse_version("251.8"));
+        assert!(parse_version("253") > parse_version("253-rc3"));
+        assert!(parse_version("253-rc2") > parse_version("253-rc1"));
+        assert!(parse_version("253-rc1") > parse_version("252.4"));
     }
 
     #[test]
@@ -85,7 +220,88 @@ mod tests {
         parse_version_error("");
         parse_version_error("213;k;13");
         parse_version_error("-1.3.123");
-        parse_version_error("253-rc1");
+        parse_version_error("253-foo");
+        parse_version_error("253-rc");
+    }
+
+    #[test]
+    fn display_version() {
+        assert_eq!(parse_version("253").to_string(), "253");
+        assert_eq!(parse_version("252.4").to_string(), "252.4");
+        assert_eq!(parse_version("254~rc2").to_string(), "254-rc2");
+    }
+
+    #[test]
+    fn shipped_denylist_is_valid() {
+        for (version, reason) in DENYLIST {
+            parse_version(version);
+            assert!(!reason.is_empty());
+        }
+    }
+
+    #[test]
+    fn only_update_by_default() {
+        let policy = SystemdBootPolicy::default();
+        let available = parse_version("253");
+
+        assert_eq!(policy.decide(&available, None), SystemdBootAction::Install);
+        assert_eq!(
+            policy.decide(&available, Some(&parse_version("252.4"))),
+            SystemdBootAction::Install
+        );
+        assert_eq!(
+            policy.decide(&available, Some(&parse_version("253"))),
+            SystemdBootAction::Keep
+        );
+        assert_eq!(
+            policy.decide(&available, Some(&parse_version("254"))),
+            SystemdBootAction::Keep
+        );
+    }
+
+    #[test]
+    fn downgrade_when_allowed_or_pinned() {
+        let available = parse_version("253");
+        let installed = parse_version("254");
+
+        let policy = SystemdBootPolicy {
+            allow_downgrade: true,
+            ..Default::default()
+        };
+        assert_eq!(
+            policy.decide(&available, Some(&installed)),
+            SystemdBootAction::Install
+        );
+
+        let policy = SystemdBootPolicy {
+            pinned_version: Some(parse_version("253")),
+            ..Default::default()
+        };
+        assert_eq!(
+            policy.decide(&available, Some(&installed)),
+            SystemdBootAction::Install
+        );
+        assert!(matches!(
+            policy.decide(&parse_version("254"), Some(&available)),
+            SystemdBootAction::Refuse(_)
+        ));
+    }
+
+    #[test]
+    fn refuse_denied_versions() {
+        let policy = SystemdBootPolicy {
+            denied_versions: vec![parse_version("254-rc1")],
+            ..Default::default()
+        };
+
+        assert!(matches!(
+            policy.decide(&parse_version("254~rc1"), None),
+            SystemdBootAction::Refuse(_)
+        ));
+        assert_eq!(
+            policy.decide(&parse_version("254"), None),
+            SystemdBootAction::Install
+        );
     }
 
     fn parse_version(input: &str) -> SystemdVersion {
@@ -0,0 +1,300 @@
+use std::fmt;
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+
+use anyhow::{Context, Result};
+
+use crate::efi_tools;
+use crate::error::Error;
+use crate::esp::EspPaths;
+use crate::fallback;
+use crate::install::force_install;
+use crate::quarantine::QUARANTINE_DIR;
+
+/// A single step of uninstalling lanzaboote.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum UninstallAction {
+    /// Remove a file or a directory with all its contents.
+    Remove(PathBuf),
+    /// Copy a file (e.g. a backup or an unsigned systemd-boot) over an installed file.
+    Restore {
+        /// The file that is copied.
+        from: PathBuf,
+        /// The installed file that is overwritten.
+        to: PathBuf,
+    },
+}
+
+impl fmt::Display for UninstallAction {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Self::Remove(path) => write!(f, "Remove {path:?}"),
+            Self::Restore { from, to } => write!(f, "Restore {to:?} from {from:?}"),
+        }
+    }
+}
+
+/// Removes lanzaboote from an ESP.
+///
+/// Removes everything lzbt installed: the lanzaboote images in esp/EFI/Linux, the managed
+/// esp/EFI/nixos directory, the loader entries of the EFI tools, systemd-boot and the fallback
+/// boot loader if it is systemd-boot. A backup of a foreign fallback boot loader is restored by
+/// default. Foreign files are left untouched. This includes the files in the quarantine and the
+/// backup of a foreign fallback boot loader if it is not restored.
+pub struct Uninstaller {
+    esp_paths: EspPaths,
+    restore_fallback: bool,
+    systemd: Option<PathBuf>,
+    dry_run: bool,
+}
+
+impl Uninstaller {
+    /// Create an uninstaller for the ESP mounted at `esp`.
+    pub fn new(esp: impl AsRef<Path>) -> Self {
+        Self {
+            esp_paths: EspPaths::new(esp),
+            restore_fallback: true,
+            systemd: None,
+            dry_run: false,
+        }
+    }
+
+    /// Restore the foreign fallback boot loader that was backed up when it was taken over.
+    ///
+    /// Enabled by default. Without a backup, there is nothing to restore.
+    pub fn restore_fallback(mut self, restore_fallback: bool) -> Self {
+        self.restore_fallback = restore_fallback;
+        self
+    }
+
+    /// Replace the signed systemd-boot with the unsigned one from a systemd installation instead
+    /// of removing it.
+    pub fn restore_systemd_boot(mut self, systemd: impl Into<PathBuf>) -> Self {
+        self.systemd = Some(systemd.into());
+        self
+    }
+
+    /// Only log what would be done without changing the ESP.
+    pub fn dry_run(mut self, dry_run: bool) -> Self {
+        self.dry_run = dry_run;
+        self
+    }
+
+    /// Uninstall lanzaboote and return the steps that were taken.
+    ///
+    /// In a dry run, the steps are only returned and logged.
+    pub fn uninstall(&self) -> crate::Result<Vec<UninstallAction>> {
+        self.try_uninstall().map_err(|e| Error::Uninstall(e.into()))
+    }
+
+    fn try_uninstall(&self) -> Result<Vec<UninstallAction>> {
+        let actions = self.plan()?;
+
+        if self.dry_run {
+            for action in &actions {
+                log::info!("Would {}.", lowercase_first(&action.to_string()));
+            }
+            return Ok(actions);
+        }
+
+        log::info!("Uninstalling Lanzaboote from {:?}...", self.esp_paths.esp);
+        for action in &actions {
+            log::info!("{action}...");
+            match action {
+                UninstallAction::Remove(path) => remove_path(path)?,
+                UninstallAction::Restore { from, to } => force_install(from, to)
+                    .with_context(|| format!("Failed to restore {to:?} from {from:?}"))?,
+            }
+        }
+        log::info!("Successfully uninstalled Lanzaboote.");
+
+        Ok(actions)
+    }
+
+    /// Plan the steps to uninstall lanzaboote.
+    ///
+    /// Restoring the fallback boot loader comes before removing esp/EFI/nixos, which contains its
+    /// backup.
+    fn plan(&self) -> Result<Vec<UninstallAction>> {
+        let esp_paths = &self.esp_paths;
+        let mut actions = Vec::new();
+
+        actions.extend(
+            entries_with_prefix(&esp_paths.linux, "nixos-")?
+                .into_iter()
+                .map(UninstallAction::Remove),
+        );
+        actions.extend(
+            entries_with_prefix(&esp_paths.loader_entries, efi_tools::LOADER_ENTRY_PREFIX)?
+                .into_iter()
+                .map(UninstallAction::Remove),
+        );
+
+        let unsigned_systemd_boot = self
+            .systemd
+            .as_ref()
+            .map(|systemd| systemd.join("lib/systemd/boot/efi/systemd-bootx64.efi"));
+        let restore_systemd_boot = |path: &PathBuf| match &unsigned_systemd_boot {
+            Some(unsigned_systemd_boot) => UninstallAction::Restore {
+                from: unsigned_systemd_boot.clone(),
+                to: path.clone(),
+            },
+            None => UninstallAction::Remove(path.clone()),
+        };
+
+        let backup_exists = esp_paths.efi_fallback_backup.exists();
+        if self.restore_fallback && backup_exists {
+            actions.push(UninstallAction::Restore {
+                from: esp_paths.efi_fallback_backup.clone(),
+                to: esp_paths.efi_fallback.clone(),
+            });
+        } else if fallback::is_systemd_boot_binary(&esp_paths.efi_fallback) {
+            actions.push(restore_systemd_boot(&esp_paths.efi_fallback));
+        }
+        if self.restore_fallback && !backup_exists {
+            log::debug!(
+                "There is no backup of the fallback boot loader at {:?} to restore.",
+                esp_paths.efi_fallback_backup
+            );
+        }
+
+        if fallback::is_systemd_boot_binary(&esp_paths.systemd_boot) {
+            actions.push(restore_systemd_boot(&esp_paths.systemd_boot));
+            // Without systemd-boot, its configuration and random seed are not needed anymore.
+            if unsigned_systemd_boot.is_none() {
+                actions.extend(
+                    [
+                        &esp_paths.systemd_boot_loader_config,
+                        &esp_paths.random_seed,
+                    ]
+                    .into_iter()
+                    .filter(|path| path.exists())
+                    .map(|path| UninstallAction::Remove(path.clone())),
+                );
+            }
+        }
+
+        // The quarantine and a backup that is not restored contain foreign files.
+        let mut keep = vec![esp_paths.nixos.join(QUARANTINE_DIR)];
+        if !self.restore_fallback {
+            keep.push(esp_paths.efi_fallback_backup_dir.clone());
+        }
+        let nixos_entries = entries_with_prefix(&esp_paths.nixos, "")?;
+        if nixos_entries.iter().any(|path| keep.contains(path)) {
+            actions.extend(
+                nixos_entries
+                    .into_iter()
+                    .filter(|path| !keep.contains(path))
+                    .map(UninstallAction::Remove),
+            );
+            log::info!(
+                "Keeping {:?} because it contains files that were not installed by lzbt.",
+                esp_paths.nixos
+            );
+        } else if esp_paths.nixos.exists() {
+            actions.push(UninstallAction::Remove(esp_paths.nixos.clone()));
+        }
+
+        Ok(actions)
+    }
+}
+
+/// Return the entries of a directory whose file names start with `prefix` sorted by path.
+///
+/// A missing directory does not contain any entries.
+fn entries_with_prefix(directory: &Path, prefix: &str) -> Result<Vec<PathBuf>> {
+    let entries = match fs::read_dir(directory) {
+        Ok(entries) => entries,
+        Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(Vec::new()),
+        Err(e) => return Err(e).with_context(|| format!("Failed to read directory {directory:?}")),
+    };
+
+    let mut paths = Vec::new();
+    for entry in entries {
+        let path = entry
+            .with_context(|| format!("Failed to read directory {directory:?}"))?
+            .path();
+        if path
+            .file_name()
+            .and_then(|n| n.to_str())
+            .is_some_and(|n| n.starts_with(prefix))
+        {
+            paths.push(path);
+        }
+    }
+    paths.sort();
+    Ok(paths)
+}
+
+/// Remove a file or a directory with all its contents.
+fn remove_path(path: &Path) -> Result<()> {
+    if path.is_dir() {
+        fs::remove_dir_all(path).with_context(|| format!("Failed to remove directory: {path:?}"))
+    } else {
+        fs::remove_file(path).with_context(|| format!("Failed to remove file: {path:?}"))
+    }
+}
+
+/// Lowercase the first character of a sentence.
+fn lowercase_first(s: &str) -> String {
+    let mut chars = s.chars();
+    match chars.next() {
+        Some(first) => first.to_lowercase().chain(chars).collect(),
+        None => String::new(),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn plan_removal_of_installed_files_only() -> Result<()> {
+        let esp = tempfile::tempdir()?;
+        let esp_paths = EspPaths::new(esp.path());
+        let image = esp_paths.linux.join("nixos-generation-1.efi");
+        let foreign_image = esp_paths.linux.join("ubuntu.efi");
+        let tool_entry = esp_paths.loader_entries.join("nixos-tool-shell.conf");
+        let foreign_entry = esp_paths.loader_entries.join("ubuntu.conf");
+        let kernel = esp_paths.nixos.join("kernel-6.1.1-bzImage.efi");
+        let quarantined = esp_paths.nixos.join(QUARANTINE_DIR).join("other-os.efi");
+        for path in [
+            &image,
+            &foreign_image,
+            &tool_entry,
+            &foreign_entry,
+            &kernel,
+            &quarantined,
+        ] {
+            fs::create_dir_all(path.parent().unwrap())?;
+            fs::File::create(path)?;
+        }
+
+        let actions = Uninstaller::new(esp.path()).dry_run(true).uninstall()?;
+
+        assert_eq!(
+            actions,
+            [
+                UninstallAction::Remove(image),
+                UninstallAction::Remove(tool_entry),
+                UninstallAction::Remove(kernel.clone()),
+            ]
+        );
+        assert!(kernel.exists());
+        Ok(())
+    }
+
+    #[test]
+    fn remove_nixos_directory_without_foreign_files() -> Result<()> {
+        let esp = tempfile::tempdir()?;
+        let esp_paths = EspPaths::new(esp.path());
+        fs::create_dir_all(&esp_paths.tools)?;
+        fs::File::create(esp_paths.tools.join("shell.efi"))?;
+
+        Uninstaller::new(esp.path()).uninstall()?;
+
+        assert!(!esp_paths.nixos.exists());
+        Ok(())
+    }
+}
@@ -1,9 +1,13 @@
 use std::ffi::OsString;
 use std::fs;
-use std::io::Write;
+use std::io::{self, Write};
 use std::iter::repeat_with;
+use std::num::NonZeroUsize;
 use std::os::unix::fs::OpenOptionsExt;
+use std::panic;
 use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::thread;
 
 use anyhow::{Context, Result};
 use sha2::{Digest, Sha256};
@@ -69,8 +73,83 @@ pub fn tmpname() -> OsString {
 type Hash = sha2::digest::Output<Sha256>;
 
 /// Compute the SHA 256 hash of a file.
+///
+/// The file is streamed into the hasher so that large kernels and initrds are not read into
+/// memory completely.
 pub fn file_hash(file: &Path) -> Result<Hash> {
-    Ok(Sha256::digest(fs::read(file).with_context(|| {
-        format!("Failed to read file to hash: {file:?}")
-    })?))
+    let mut reader =
+        fs::File::open(file).with_context(|| format!("Failed to open file to hash: {file:?}"))?;
+    let mut hasher = Sha256::new();
+    io::copy(&mut reader, &mut hasher)
+        .with_context(|| format!("Failed to read file to hash: {file:?}"))?;
+    Ok(hasher.finalize())
+}
+
+/// Apply a function to all items in parallel and return the results in the order of the items.
+///
+/// The items are distributed over at most as many threads as there are CPUs available. A panic in
+/// any of the threads is propagated to the caller.
+pub fn parallel_map<T, R, F>(items: &[T], f: F) -> Vec<R>
+where
+    T: Sync,
+    R: Send,
+    F: Fn(&T) -> R + Sync,
+{
+    let threads = thread::available_parallelism()
+        .map(NonZeroUsize::get)
+        .unwrap_or(1)
+        .min(items.len());
+    let next_item = AtomicUsize::new(0);
+
+    let mut results = thread::scope(|scope| {
+        let workers = (0..threads)
+            .map(|_| {
+                scope.spawn(|| {
+                    let mut results = Vec::new();
+                    loop {
+                        let index = next_item.fetch_add(1, Ordering::Relaxed);
+                        let Some(item) = items.get(index) else {
+                            break;
+                        };
+                        results.push((index, f(item)));
+                    }
+                    results
+                })
+            })
+            .collect::<Vec<_>>();
+
+        workers
+            .into_iter()
+            .flat_map(|worker| worker.join().unwrap_or_else(|e| panic::resume_unwind(e)))
+            .collect::<Vec<(usize, R)>>()
+    });
+
+    results.sort_by_key(|(index, _)| *index);
+    results.into_iter().map(|(_, result)| result).collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parallel_map_keeps_order() {
+        let items = (0..100).collect::<Vec<u64>>();
+        let results = parallel_map(&items, |item| item * 2);
+        assert_eq!(results, (0..100).map(|item| item * 2).collect::<Vec<u64>>());
+    }
+
+    #[test]
+    fn parallel_map_empty() {
+        let results = parallel_map(&Vec::<u64>::new(), |item| *item);
+        assert!(results.is_empty());
+    }
+
+    #[test]
+    fn stream_file_hash() -> Result<()> {
+        let tempdir = TempDir::new()?;
+        let path = tempdir.write_secure_file(b"lanzaboote")?;
+        assert_eq!(file_hash(&path)?, Sha256::digest(b"lanzaboote"));
+        Ok(())
+    }
 }
@@ -3,9 +3,10 @@
 // https://stackoverflow.com/a/67902444
 #![allow(dead_code)]
 
-use std::ffi::OsStr;
+use std::ffi::{OsStr, OsString};
 use std::fs;
 use std::io::Write;
+use std::os::unix::fs::PermissionsExt;
 use std::os::unix::prelude::MetadataExt;
 use std::path::{Path, PathBuf};
 use std::process::Output;
@@ -37,6 +38,42 @@ pub fn setup_generation_link_from_toplevel(
     toplevel: &Path,
     profiles_directory: &Path,
     version: u64,
+) -> Result<PathBuf> {
+    setup_profile_generation_link_from_toplevel(toplevel, profiles_directory, "system", version)
+}
+
+/// Create a mock generation link for a specific profile.
+///
+/// Works like `setup_generation_link_from_toplevel` but names the link after the profile (e.g.
+/// "my-server-1-link" instead of "system-1-link").
+pub fn setup_profile_generation_link_from_toplevel(
+    toplevel: &Path,
+    profiles_directory: &Path,
+    profile: &str,
+    version: u64,
+) -> Result<PathBuf> {
+    write_generation_link(toplevel, profiles_directory, profile, version, json!({}))
+}
+
+/// Create a mock generation link with a lanzaboote bootspec extension.
+///
+/// Works like `setup_generation_link_from_toplevel` but adds the provided value as the
+/// `org.nixos-community.lanzaboote` extension to the bootspec.
+pub fn setup_generation_link_with_extension(
+    toplevel: &Path,
+    profiles_directory: &Path,
+    version: u64,
+    extension: serde_json::Value,
+) -> Result<PathBuf> {
+    write_generation_link(toplevel, profiles_directory, "system", version, extension)
+}
+
+fn write_generation_link(
+    toplevel: &Path,
+    profiles_directory: &Path,
+    profile: &str,
+    version: u64,
+    extension: serde_json::Value,
 ) -> Result<PathBuf> {
     let bootspec = json!({
         "org.nixos.bootspec.v1": {
@@ -57,10 +94,10 @@ pub fn setup_generation_link_from_toplevel(
           "toplevel": toplevel,
           "system": "x86_64-linux",
         },
-        "org.nixos-community.lanzaboote": { "osRelease": toplevel.join("os-release") }
+        "org.nixos-community.lanzaboote": extension
     });
 
-    let generation_link_path = profiles_directory.join(format!("system-{}-link", version));
+    let generation_link_path = profiles_directory.join(format!("{}-{}-link", profile, version));
     fs::create_dir(&generation_link_path)?;
 
     let bootspec_path = generation_link_path.join("boot.json");
@@ -91,18 +128,41 @@ pub fn setup_toplevel(tmpdir: &Path) -> Result<PathBuf> {
     let nixos_version_path = toplevel.join("nixos-version");
     let kernel_modules_path = toplevel.join("kernel-modules/lib/modules/6.1.1");
 
-    // To simplify the test setup, we use the systemd stub for all PE binaries used by lanzatool.
-    // Lanzatool doesn't care whether its actually a kernel or initrd but only whether it can
-    // manipulate the PE binary with objcopy and/or sign it with sbsigntool. For testing lanzatool
-    // in isolation this should suffice.
-    fs::copy(&test_systemd_stub, initrd_path)?;
-    fs::copy(&test_systemd_stub, kernel_path)?;
+    // To simplify the test setup, we use the systemd stub as the kernel. Lanzatool doesn't care
+    // whether its actually a kernel but only whether it passes the pre-flight validation and
+    // whether it can manipulate the PE binary with objcopy and/or sign it with sbsigntool. For
+    // testing lanzatool in isolation this should suffice. The initrd only needs to look like a
+    // cpio archive.
+    fs::write(initrd_path, b"070701 mock initrd")?;
+    fs::copy(&test_systemd_stub, &kernel_path)?;
+    remove_base_relocation_table(&kernel_path)?;
     fs::write(nixos_version_path, b"23.05")?;
     fs::create_dir_all(kernel_modules_path)?;
 
     Ok(toplevel)
 }
 
+/// Remove the base relocation table from the data directories of a PE binary.
+///
+/// The stub cannot load kernels with base relocations and lzbt refuses to install them.
+fn remove_base_relocation_table(path: &Path) -> Result<()> {
+    let mut file_data = fs::read(path)?;
+
+    let pe_header_offset = u32::from_le_bytes(file_data[0x3c..0x40].try_into()?) as usize;
+    let optional_header_offset = pe_header_offset + 24;
+    let magic = u16::from_le_bytes(
+        file_data[optional_header_offset..optional_header_offset + 2].try_into()?,
+    );
+    // The data directories follow the standard and Windows-specific fields, which are larger in
+    // PE32+ binaries.
+    let data_directories_offset = optional_header_offset + if magic == 0x20b { 112 } else { 96 };
+    let base_relocation_table_offset = data_directories_offset + 5 * 8;
+    file_data[base_relocation_table_offset..base_relocation_table_offset + 8].fill(0);
+
+    fs::write(path, file_data)?;
+    Ok(())
+}
+
 fn random_string(length: usize) -> String {
     thread_rng()
         .sample_iter(&Alphanumeric)
@@ -116,23 +176,59 @@ pub fn lanzaboote_install(
     config_limit: u64,
     esp_mountpoint: &Path,
     generation_links: impl IntoIterator<Item = impl AsRef<OsStr>>,
+) -> Result<Output> {
+    lanzaboote_install_with_args(
+        config_limit,
+        esp_mountpoint,
+        generation_links,
+        Vec::<&OsStr>::new(),
+    )
+}
+
+/// Call the `lanzaboote install` command with additional arguments.
+///
+/// The additional arguments are passed before the positional arguments. Unless they contain
+/// `--efivarfs`, an empty efivarfs is used so that the EFI variables of the machine running the
+/// tests do not influence them. Unless they contain `--systemd`, the systemd from the environment
+/// is used.
+pub fn lanzaboote_install_with_args(
+    config_limit: u64,
+    esp_mountpoint: &Path,
+    generation_links: impl IntoIterator<Item = impl AsRef<OsStr>>,
+    extra_args: impl IntoIterator<Item = impl AsRef<OsStr>>,
 ) -> Result<Output> {
     // To simplify the test setup, we use the systemd stub here instead of the lanzaboote stub. See
     // the comment in setup_toplevel for details.
     let test_systemd = systemd_location_from_env()?;
     let test_systemd_stub = format!("{test_systemd}/lib/systemd/boot/efi/linuxx64.efi.stub");
 
+    let extra_args = extra_args
+        .into_iter()
+        .map(|arg| arg.as_ref().to_os_string())
+        .collect::<Vec<OsString>>();
+    let empty_efivarfs = tempfile::tempdir()?;
+    let efivarfs_args = if extra_args.iter().any(|arg| arg == "--efivarfs") {
+        Vec::new()
+    } else {
+        vec![OsString::from("--efivarfs"), empty_efivarfs.path().into()]
+    };
+    let systemd_args = if extra_args.iter().any(|arg| arg == "--systemd") {
+        Vec::new()
+    } else {
+        vec![OsString::from("--systemd"), OsString::from(&test_systemd)]
+    };
+
     let test_loader_config_path = tempfile::NamedTempFile::new()?;
     let test_loader_config = r"timeout 0\nconsole-mode 1\n";
     fs::write(test_loader_config_path.path(), test_loader_config)?;
 
     let mut cmd = Command::cargo_bin("lzbt")?;
     let output = cmd
-        .env("LANZABOOTE_STUB", test_systemd_stub)
+        .env("LANZABOOTE_STUB", &test_systemd_stub)
+        .env("LANZABOOTE_FAT_STUB", &test_systemd_stub)
         .arg("-vv")
         .arg("install")
-        .arg("--systemd")
-        .arg(test_systemd)
+        .args(systemd_args)
         .arg("--systemd-boot-loader-config")
         .arg(test_loader_config_path.path())
         .arg("--public-key")
@@ -141,6 +237,8 @@ pub fn lanzaboote_install(
         .arg("tests/fixtures/uefi-keys/db.key")
         .arg("--configuration-limit")
         .arg(config_limit.to_string())
+        .args(efivarfs_args)
+        .args(extra_args)
         .arg(esp_mountpoint)
         .args(generation_links)
         .output()?;
@@ -159,6 +257,51 @@ pub fn lanzaboote_install(
     Ok(output)
 }
 
+/// Call the `lzbt uninstall` command with additional arguments.
+pub fn lanzaboote_uninstall(
+    esp_mountpoint: &Path,
+    extra_args: impl IntoIterator<Item = impl AsRef<OsStr>>,
+) -> Result<Output> {
+    let mut cmd = Command::cargo_bin("lzbt")?;
+    let output = cmd
+        .arg("-vv")
+        .arg("uninstall")
+        .args(extra_args)
+        .arg(esp_mountpoint)
+        .output()?;
+
+    print!("{}", String::from_utf8(output.stdout.clone())?);
+    print!("{}", String::from_utf8(output.stderr.clone())?);
+
+    for entry in walkdir::WalkDir::new(esp_mountpoint) {
+        println!("{}", entry?.path().display());
+    }
+
+    Ok(output)
+}
+
+/// Create a mock efivarfs in which the system was booted from an image.
+///
+/// Sets `LoaderImageIdentifier` like the stub does. Returns the path to the efivarfs.
+pub fn setup_efivarfs(tmpdir: &Path, image: &str) -> Result<PathBuf> {
+    let efivarfs = tmpdir.join("efivars");
+    fs::create_dir_all(&efivarfs)?;
+
+    // efivarfs prefixes the variable with its attributes.
+    let attributes: u32 = 0x6;
+    let data = attributes
+        .to_le_bytes()
+        .into_iter()
+        .chain(image.encode_utf16().chain([0]).flat_map(u16::to_le_bytes))
+        .collect::<Vec<u8>>();
+    fs::write(
+        efivarfs.join("LoaderImageIdentifier-4a67b082-0a4c-41cf-b6c7-440b29bb8c4f"),
+        data,
+    )?;
+
+    Ok(efivarfs)
+}
+
 /// Read location of systemd installation from an environment variable.
 fn systemd_location_from_env() -> Result<String> {
     let error_msg = "TEST_SYSTEMD environment variable is not set. TEST_SYSTEMD has to point to a systemd installation.
@@ -166,6 +309,49 @@ On a system with Nix installed, you can set it with: export TEST_SYSTEMD=$(nix-b
     std::env::var("TEST_SYSTEMD").context(error_msg)
 }
 
+/// Create a systemd installation with a mock `systemd-creds`.
+///
+/// Everything but `bin/systemd-creds` comes from the systemd installation used for testing. The
+/// mock "encrypts" a credential by copying it and records its arguments in `bin/systemd-creds.args`
+/// so that tests do not need a TPM.
+pub fn setup_systemd_with_mock_creds(tmpdir: &Path) -> Result<PathBuf> {
+    let systemd = tmpdir.join(format!("systemd-{}", random_string(8)));
+    fs::create_dir_all(systemd.join("bin"))?;
+    std::os::unix::fs::symlink(
+        PathBuf::from(systemd_location_from_env()?).join("lib"),
+        systemd.join("lib"),
+    )?;
+
+    let systemd_creds = systemd.join("bin/systemd-creds");
+    fs::write(
+        &systemd_creds,
+        "#!/bin/sh\nprintf '%s\\n' \"$@\" > \"$0.args\"\ncp \"$4\" \"$5\"\n",
+    )?;
+    fs::set_permissions(&systemd_creds, fs::Permissions::from_mode(0o755))?;
+
+    Ok(systemd)
+}
+
+/// Return the path to the systemd-boot binary of the systemd installation used for testing.
+///
+/// This is a valid EFI binary that can be used wherever an arbitrary EFI application is needed.
+pub fn systemd_boot_binary() -> Result<PathBuf> {
+    let test_systemd = systemd_location_from_env()?;
+    Ok(PathBuf::from(format!(
+        "{test_systemd}/lib/systemd/boot/efi/systemd-bootx64.efi"
+    )))
+}
+
+/// Return the path to the systemd-stub binary of the systemd installation used for testing.
+///
+/// This is a valid EFI binary that is not systemd-boot.
+pub fn systemd_stub_binary() -> Result<PathBuf> {
+    let test_systemd = systemd_location_from_env()?;
+    Ok(PathBuf::from(format!(
+        "{test_systemd}/lib/systemd/boot/efi/linuxx64.efi.stub"
+    )))
+}
+
 /// Look up the modification time (mtime) of a file.
 pub fn mtime(path: &Path) -> i64 {
     fs::metadata(path)
@@ -205,3 +391,19 @@ pub fn verify_signature(path: &Path) -> Result<bool> {
 pub fn count_files(path: &Path) -> Result<usize> {
     Ok(fs::read_dir(path)?.count())
 }
+
+/// Read the data from a section of a PE binary.
+pub fn pe_section<'a>(file_data: &'a [u8], section_name: &str) -> Option<&'a [u8]> {
+    let pe_binary = goblin::pe::PE::parse(file_data).ok()?;
+
+    pe_binary
+        .sections
+        .iter()
+        .find(|s| s.name().unwrap() == section_name)
+        .and_then(|s| {
+            let section_start: usize = s.pointer_to_raw_data.try_into().ok()?;
+            assert!(s.virtual_size <= s.size_of_raw_data);
+            let section_end: usize = section_start + usize::try_from(s.virtual_size).ok()?;
+            Some(&file_data[section_start..section_end])
+        })
+}
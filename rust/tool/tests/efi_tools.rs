@@ -0,0 +1,72 @@
+use std::fs;
+
+use anyhow::Result;
+use tempfile::tempdir;
+
+mod common;
+
+use common::{systemd_boot_binary, verify_signature};
+
+#[test]
+fn install_signed_efi_tools_with_loader_entries() -> Result<()> {
+    let esp = tempdir()?;
+    let tmpdir = tempdir()?;
+    let profiles = tempdir()?;
+    let generation_link = common::setup_generation_link(tmpdir.path(), profiles.path(), 1)
+        .expect("Failed to setup generation link");
+
+    let efi_tool = format!("memtest86={}", systemd_boot_binary()?.display());
+    let output0 = common::lanzaboote_install_with_args(
+        0,
+        esp.path(),
+        vec![&generation_link],
+        ["--efi-tool", &efi_tool],
+    )?;
+    assert!(output0.status.success());
+
+    let tool_path = esp.path().join("EFI/nixos/tools/memtest86.efi");
+    let entry_path = esp.path().join("loader/entries/nixos-tool-memtest86.conf");
+
+    assert!(verify_signature(&tool_path)?);
+    assert_eq!(
+        fs::read_to_string(&entry_path)?,
+        "title memtest86\nefi /EFI/nixos/tools/memtest86.efi\nsort-key nixos-tool-memtest86\n"
+    );
+
+    Ok(())
+}
+
+#[test]
+fn garbage_collect_removed_efi_tools() -> Result<()> {
+    let esp = tempdir()?;
+    let tmpdir = tempdir()?;
+    let profiles = tempdir()?;
+    let generation_link = common::setup_generation_link(tmpdir.path(), profiles.path(), 1)
+        .expect("Failed to setup generation link");
+
+    let efi_tool = format!("shell={}", systemd_boot_binary()?.display());
+    let output0 = common::lanzaboote_install_with_args(
+        0,
+        esp.path(),
+        vec![&generation_link],
+        ["--efi-tool", &efi_tool],
+    )?;
+    assert!(output0.status.success());
+
+    let tool_path = esp.path().join("EFI/nixos/tools/shell.efi");
+    let entry_path = esp.path().join("loader/entries/nixos-tool-shell.conf");
+    let unrelated_entry_path = esp.path().join("loader/entries/windows.conf");
+    fs::File::create(&unrelated_entry_path)?;
+    assert!(tool_path.exists());
+    assert!(entry_path.exists());
+
+    // Install again without the tool and assert that its files are garbage collected.
+    let output1 = common::lanzaboote_install(0, esp.path(), vec![&generation_link])?;
+    assert!(output1.status.success());
+
+    assert!(!tool_path.exists());
+    assert!(!entry_path.exists());
+    assert!(unrelated_entry_path.exists());
+
+    Ok(())
+}
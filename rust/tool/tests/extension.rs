@@ -0,0 +1,132 @@
+use std::fs;
+
+use anyhow::{Context, Result};
+use serde_json::json;
+use tempfile::tempdir;
+
+mod common;
+
+use common::{count_files, setup_generation_link_with_extension};
+
+#[test]
+fn append_kernel_params_and_sections_from_extension() -> Result<()> {
+    let esp = tempdir()?;
+    let tmpdir = tempdir()?;
+    let profiles = tempdir()?;
+    let toplevel = common::setup_toplevel(tmpdir.path())?;
+
+    let dtb = tmpdir.path().join("board.dtb");
+    fs::write(&dtb, b"Devicetree")?;
+
+    let generation_link = setup_generation_link_with_extension(
+        &toplevel,
+        profiles.path(),
+        1,
+        json!({
+            "kernelParams": ["quiet"],
+            "sections": { ".dtb": dtb },
+        }),
+    )?;
+
+    let output0 = common::lanzaboote_install(0, esp.path(), vec![generation_link])?;
+    assert!(output0.status.success());
+
+    let image = fs::read(esp.path().join("EFI/Linux/nixos-generation-1.efi"))?;
+    let cmdline =
+        common::pe_section(&image, ".cmdline").context("Failed to read .cmdline PE section.")?;
+    let devicetree =
+        common::pe_section(&image, ".dtb").context("Failed to read .dtb PE section.")?;
+
+    assert!(std::str::from_utf8(cmdline)?.ends_with(" quiet"));
+    assert_eq!(devicetree, b"Devicetree");
+
+    Ok(())
+}
+
+#[test]
+fn use_os_release_from_extension() -> Result<()> {
+    let esp = tempdir()?;
+    let tmpdir = tempdir()?;
+    let profiles = tempdir()?;
+    let toplevel = common::setup_toplevel(tmpdir.path())?;
+
+    let os_release = tmpdir.path().join("os-release");
+    fs::write(&os_release, b"ID=custom\nPRETTY_NAME=Custom\n")?;
+
+    let generation_link = setup_generation_link_with_extension(
+        &toplevel,
+        profiles.path(),
+        1,
+        json!({ "osRelease": os_release }),
+    )?;
+
+    let output0 = common::lanzaboote_install(0, esp.path(), vec![generation_link])?;
+    assert!(output0.status.success());
+
+    let image = fs::read(esp.path().join("EFI/Linux/nixos-generation-1.efi"))?;
+    let os_release_section =
+        common::pe_section(&image, ".osrel").context("Failed to read .osrel PE section.")?;
+
+    assert_eq!(os_release_section, b"ID=custom\nPRETTY_NAME=Custom\n");
+
+    Ok(())
+}
+
+#[test]
+fn do_not_install_excluded_generations() -> Result<()> {
+    let esp = tempdir()?;
+    let tmpdir = tempdir()?;
+    let profiles = tempdir()?;
+    let toplevel = common::setup_toplevel(tmpdir.path())?;
+
+    let generation_links = vec![
+        setup_generation_link_with_extension(&toplevel, profiles.path(), 1, json!({}))?,
+        setup_generation_link_with_extension(
+            &toplevel,
+            profiles.path(),
+            2,
+            json!({ "exclude": true }),
+        )?,
+    ];
+
+    let output0 = common::lanzaboote_install(0, esp.path(), generation_links)?;
+    assert!(output0.status.success());
+
+    assert!(esp.path().join("EFI/Linux/nixos-generation-1.efi").exists());
+    assert!(!esp.path().join("EFI/Linux/nixos-generation-2.efi").exists());
+
+    Ok(())
+}
+
+#[test]
+fn embed_kernel_and_initrd_into_fat_image() -> Result<()> {
+    let esp = tempdir()?;
+    let tmpdir = tempdir()?;
+    let profiles = tempdir()?;
+    let toplevel = common::setup_toplevel(tmpdir.path())?;
+
+    let generation_link = setup_generation_link_with_extension(
+        &toplevel,
+        profiles.path(),
+        1,
+        json!({ "stub": "fat" }),
+    )?;
+
+    let output0 = common::lanzaboote_install(0, esp.path(), vec![generation_link])?;
+    assert!(output0.status.success());
+
+    let image_path = esp.path().join("EFI/Linux/nixos-generation-1.efi");
+    assert!(common::verify_signature(&image_path)?);
+
+    let image = fs::read(image_path)?;
+    let kernel = common::pe_section(&image, ".linux").context("Failed to read .linux section.")?;
+    assert_eq!(kernel, fs::read(toplevel.join("kernel"))?);
+    assert!(common::pe_section(&image, ".initrd").is_some());
+    assert!(common::pe_section(&image, ".kernelp").is_none());
+
+    // The kernel and initrd are not installed separately.
+    let nixos_dir = esp.path().join("EFI/nixos");
+    assert!(!nixos_dir.exists() || count_files(&nixos_dir)? == 0);
+
+    Ok(())
+}
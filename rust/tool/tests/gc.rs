@@ -1,3 +1,4 @@
+use std::ffi::OsStr;
 use std::fs;
 use std::path::PathBuf;
 
@@ -83,3 +84,219 @@ fn keep_unrelated_files_on_esp() -> Result<()> {
 
     Ok(())
 }
+
+#[test]
+fn quarantine_unknown_files_in_nixos_directory() -> Result<()> {
+    let esp_mountpoint = tempdir()?;
+    let tmpdir = tempdir()?;
+    let profiles = tempdir()?;
+    let generation_links: Vec<PathBuf> = [1, 2]
+        .into_iter()
+        .map(|v| {
+            common::setup_generation_link(tmpdir.path(), profiles.path(), v)
+                .expect("Failed to setup generation link")
+        })
+        .collect();
+    let quarantine_args = ["--quarantine-installs", "2"];
+
+    let output0 = common::lanzaboote_install_with_args(
+        0,
+        esp_mountpoint.path(),
+        generation_links.clone(),
+        quarantine_args,
+    )?;
+    assert!(output0.status.success());
+
+    let unknown_file = esp_mountpoint.path().join("EFI/nixos/other-os.efi");
+    let quarantined_file = esp_mountpoint
+        .path()
+        .join("EFI/nixos/.quarantine/other-os.efi");
+    fs::File::create(&unknown_file)?;
+
+    // Install only the latest generation. The files of the first generation were installed by
+    // lzbt and are deleted right away.
+    let output1 = common::lanzaboote_install_with_args(
+        1,
+        esp_mountpoint.path(),
+        generation_links.clone(),
+        quarantine_args,
+    )?;
+    assert!(output1.status.success());
+    assert!(!unknown_file.exists());
+    assert!(quarantined_file.exists());
+    assert_eq!(
+        count_files(&esp_mountpoint.path().join("EFI/Linux"))?,
+        1,
+        "Wrong number of stubs after gc."
+    );
+
+    let output2 = common::lanzaboote_install_with_args(
+        1,
+        esp_mountpoint.path(),
+        generation_links.clone(),
+        quarantine_args,
+    )?;
+    assert!(output2.status.success());
+    assert!(quarantined_file.exists());
+
+    let output3 = common::lanzaboote_install_with_args(
+        1,
+        esp_mountpoint.path(),
+        generation_links,
+        quarantine_args,
+    )?;
+    assert!(output3.status.success());
+    assert!(!quarantined_file.exists());
+
+    Ok(())
+}
+
+#[test]
+fn keep_files_of_broken_generations_and_collect_others() -> Result<()> {
+    let esp_mountpoint = tempdir()?;
+    let tmpdir = tempdir()?;
+    let profiles = tempdir()?;
+    let generation_links: Vec<PathBuf> = [1, 2]
+        .into_iter()
+        .map(|v| {
+            common::setup_generation_link(tmpdir.path(), profiles.path(), v)
+                .expect("Failed to setup generation link")
+        })
+        .collect();
+
+    let output0 = common::lanzaboote_install(0, esp_mountpoint.path(), generation_links.clone())?;
+    assert!(output0.status.success());
+
+    // Break the first generation and add a file that does not belong to any generation.
+    fs::write(generation_links[0].join("boot.json"), "{}")?;
+    let unused_file = esp_mountpoint
+        .path()
+        .join("EFI/Linux/nixos-generation-3.efi");
+    fs::File::create(&unused_file)?;
+
+    let output1 = common::lanzaboote_install_with_args(
+        0,
+        esp_mountpoint.path(),
+        generation_links,
+        ["--strict"],
+    )?;
+    assert!(output1.status.success());
+    assert!(String::from_utf8(output1.stderr)?.contains("generation 1 ("));
+
+    let linux_dir = esp_mountpoint.path().join("EFI/Linux");
+    assert!(linux_dir.join("nixos-generation-1.efi").exists());
+    assert!(linux_dir.join("nixos-generation-2.efi").exists());
+    assert!(!unused_file.exists());
+
+    Ok(())
+}
+
+#[test]
+fn keep_pinned_generations() -> Result<()> {
+    let esp_mountpoint = tempdir()?;
+    let tmpdir = tempdir()?;
+    let profiles = tempdir()?;
+    let generation_links: Vec<PathBuf> = [1, 2, 3]
+        .into_iter()
+        .map(|v| {
+            common::setup_generation_link(tmpdir.path(), profiles.path(), v)
+                .expect("Failed to setup generation link")
+        })
+        .collect();
+
+    let output0 = common::lanzaboote_install(0, esp_mountpoint.path(), generation_links.clone())?;
+    assert!(output0.status.success());
+
+    // Only install the latest generation, but pin the first one.
+    let output1 = common::lanzaboote_install_with_args(
+        1,
+        esp_mountpoint.path(),
+        generation_links,
+        ["--pin", "1"],
+    )?;
+    assert!(output1.status.success());
+
+    let linux_dir = esp_mountpoint.path().join("EFI/Linux");
+    assert_eq!(
+        count_files(&linux_dir)?,
+        2,
+        "Wrong number of stubs after gc."
+    );
+    assert!(!linux_dir.join("nixos-generation-2.efi").exists());
+
+    let pinned_image = fs::read(linux_dir.join("nixos-generation-1.efi"))?;
+    let os_release = common::pe_section(&pinned_image, ".osrel").expect("Missing .osrel section");
+    assert!(String::from_utf8_lossy(os_release).contains("Generation 1 (pinned)"));
+
+    Ok(())
+}
+
+#[test]
+fn keep_booted_generation() -> Result<()> {
+    let esp_mountpoint = tempdir()?;
+    let tmpdir = tempdir()?;
+    let profiles = tempdir()?;
+    let generation_links: Vec<PathBuf> = [1, 2, 3]
+        .into_iter()
+        .map(|v| {
+            common::setup_generation_link(tmpdir.path(), profiles.path(), v)
+                .expect("Failed to setup generation link")
+        })
+        .collect();
+    let efivarfs = common::setup_efivarfs(tmpdir.path(), r"\EFI\Linux\nixos-generation-1.efi")?;
+
+    let output0 = common::lanzaboote_install(0, esp_mountpoint.path(), generation_links.clone())?;
+    assert!(output0.status.success());
+
+    // Only install the latest generation, but the system is booted from the first one.
+    let output1 = common::lanzaboote_install_with_args(
+        1,
+        esp_mountpoint.path(),
+        generation_links,
+        [OsStr::new("--efivarfs"), efivarfs.as_os_str()],
+    )?;
+    assert!(output1.status.success());
+
+    let linux_dir = esp_mountpoint.path().join("EFI/Linux");
+    assert_eq!(
+        count_files(&linux_dir)?,
+        2,
+        "Wrong number of stubs after gc."
+    );
+    assert!(linux_dir.join("nixos-generation-1.efi").exists());
+    assert!(linux_dir.join("nixos-generation-3.efi").exists());
+
+    Ok(())
+}
+
+#[test]
+fn keep_at_most_max_images() -> Result<()> {
+    let esp_mountpoint = tempdir()?;
+    let tmpdir = tempdir()?;
+    let profiles = tempdir()?;
+    let generation_links: Vec<PathBuf> = [1, 2, 3]
+        .into_iter()
+        .map(|v| {
+            common::setup_generation_link(tmpdir.path(), profiles.path(), v)
+                .expect("Failed to setup generation link")
+        })
+        .collect();
+
+    let output0 = common::lanzaboote_install_with_args(
+        0,
+        esp_mountpoint.path(),
+        generation_links,
+        ["--max-images", "2"],
+    )?;
+    assert!(output0.status.success());
+
+    let linux_dir = esp_mountpoint.path().join("EFI/Linux");
+    assert_eq!(
+        count_files(&linux_dir)?,
+        2,
+        "Wrong number of stubs after installation."
+    );
+    assert!(!linux_dir.join("nixos-generation-1.efi").exists());
+
+    Ok(())
+}
@@ -1,4 +1,6 @@
+use std::ffi::OsStr;
 use std::fs;
+use std::os::unix::fs::PermissionsExt;
 use std::path::{Path, PathBuf};
 
 use anyhow::{Context, Result};
@@ -7,7 +9,8 @@ use tempfile::{tempdir, TempDir};
 mod common;
 
 use common::{
-    count_files, hash_file, remove_signature, setup_generation_link_from_toplevel, verify_signature,
+    count_files, hash_file, remove_signature, setup_generation_link_from_toplevel,
+    setup_profile_generation_link_from_toplevel, verify_signature,
 };
 
 /// Install two generations that point at the same toplevel.
@@ -98,6 +101,121 @@ fn overwrite_unsigned_files() -> Result<()> {
     Ok(())
 }
 
+#[test]
+fn install_extra_initrds_before_generation_initrd() -> Result<()> {
+    let esp = tempdir()?;
+    let tmpdir = tempdir()?;
+    let profiles = tempdir()?;
+    let toplevel = common::setup_toplevel(tmpdir.path())?;
+
+    let microcode_dir = tmpdir.path().join("microcode");
+    fs::create_dir(&microcode_dir)?;
+    let microcode = microcode_dir.join("intel-ucode.img");
+    fs::write(&microcode, b"070701 Microcode")?;
+
+    let generation_link = setup_generation_link_from_toplevel(&toplevel, profiles.path(), 1)?;
+
+    let output0 = common::lanzaboote_install_with_args(
+        0,
+        esp.path(),
+        vec![generation_link],
+        [OsStr::new("--extra-initrd"), microcode.as_os_str()],
+    )?;
+    assert!(output0.status.success());
+
+    let microcode_path = nixos_path(&microcode, "intel-ucode.img")?;
+    let initrd_path = nixos_path(toplevel.join("initrd"), "initrd")?;
+    assert!(esp.path().join("EFI/nixos").join(&microcode_path).exists());
+
+    let image = fs::read(image_path(&esp, 1))?;
+    let initrd_paths =
+        common::pe_section(&image, ".initrdp").context("Failed to read .initrdp PE section.")?;
+    let initrd_hashes =
+        common::pe_section(&image, ".initrdh").context("Failed to read .initrdh PE section.")?;
+
+    assert_eq!(
+        std::str::from_utf8(initrd_paths)?,
+        format!(
+            "\\EFI\\nixos\\{}\n\\EFI\\nixos\\{}",
+            microcode_path.display(),
+            initrd_path.display()
+        )
+    );
+    assert_eq!(&initrd_hashes[..32], hash_file(&microcode).as_slice());
+    assert_eq!(initrd_hashes.len(), 64);
+
+    Ok(())
+}
+
+#[test]
+fn install_generations_of_multiple_profiles() -> Result<()> {
+    let esp = tempdir()?;
+    let tmpdir = tempdir()?;
+    let profiles = tempdir()?;
+    let toplevel = common::setup_toplevel(tmpdir.path())?;
+
+    let system_profiles = profiles.path().join("system-profiles");
+    fs::create_dir(&system_profiles)?;
+
+    let generation_links = vec![
+        setup_generation_link_from_toplevel(&toplevel, profiles.path(), 1)?,
+        setup_generation_link_from_toplevel(&toplevel, profiles.path(), 2)?,
+        setup_profile_generation_link_from_toplevel(&toplevel, &system_profiles, "my-server", 1)?,
+        setup_profile_generation_link_from_toplevel(&toplevel, &system_profiles, "my-server", 2)?,
+        setup_profile_generation_link_from_toplevel(&toplevel, &system_profiles, "my-server", 3)?,
+    ];
+
+    let output0 = common::lanzaboote_install_with_args(
+        1,
+        esp.path(),
+        generation_links,
+        ["--profile-configuration-limit", "my-server=2"],
+    )?;
+    assert!(output0.status.success());
+
+    let linux_dir = esp.path().join("EFI/Linux");
+    assert!(!image_path(&esp, 1).exists());
+    assert!(image_path(&esp, 2).exists());
+    assert!(!linux_dir.join("nixos-my-server-generation-1.efi").exists());
+    assert!(linux_dir.join("nixos-my-server-generation-2.efi").exists());
+    assert!(linux_dir.join("nixos-my-server-generation-3.efi").exists());
+    assert_eq!(count_files(&linux_dir)?, 3);
+
+    Ok(())
+}
+
+#[test]
+fn install_generation_without_initrd() -> Result<()> {
+    let esp = tempdir()?;
+    let tmpdir = tempdir()?;
+    let profiles = tempdir()?;
+    let toplevel = common::setup_toplevel(tmpdir.path())?;
+
+    let generation_link = setup_generation_link_from_toplevel(&toplevel, profiles.path(), 1)?;
+
+    // Remove the initrd from the bootspec.
+    let bootspec_path = generation_link.join("boot.json");
+    let mut bootspec: serde_json::Value = serde_json::from_slice(&fs::read(&bootspec_path)?)?;
+    bootspec["org.nixos.bootspec.v1"]
+        .as_object_mut()
+        .context("Failed to find bootspec")?
+        .remove("initrd");
+    fs::write(&bootspec_path, serde_json::to_vec(&bootspec)?)?;
+
+    let output0 = common::lanzaboote_install(0, esp.path(), vec![generation_link])?;
+    assert!(output0.status.success());
+
+    let image = fs::read(image_path(&esp, 1))?;
+    assert!(common::pe_section(&image, ".kernelp").is_some());
+    assert!(common::pe_section(&image, ".initrdp").is_none());
+    assert!(common::pe_section(&image, ".initrdh").is_none());
+
+    // Only the kernel is installed.
+    assert_eq!(count_files(&esp.path().join("EFI/nixos"))?, 1);
+
+    Ok(())
+}
+
 fn image_path(esp: &TempDir, version: u64) -> PathBuf {
     esp.path()
         .join(format!("EFI/Linux/nixos-generation-{version}.efi"))
@@ -119,3 +237,155 @@ fn nixos_path(path: impl AsRef<Path>, name: &str) -> Result<PathBuf> {
 
     Ok(PathBuf::from(nixos_filename))
 }
+
+#[test]
+fn remove_legacy_loader_entries() -> Result<()> {
+    let esp = tempdir()?;
+    let tmpdir = tempdir()?;
+    let profiles = tempdir()?;
+    let generation_link = common::setup_generation_link(tmpdir.path(), profiles.path(), 1)
+        .expect("Failed to setup generation link");
+
+    let loader_entries = esp.path().join("loader/entries");
+    fs::create_dir_all(&loader_entries)?;
+    let legacy_entry = loader_entries.join("nixos-generation-1.conf");
+    fs::write(
+        &legacy_entry,
+        "title NixOS\nlinux /efi/nixos/linux-bzImage.efi\ninitrd /efi/nixos/initrd.efi\n",
+    )?;
+    let foreign_entry = loader_entries.join("ubuntu.conf");
+    fs::write(&foreign_entry, "title Ubuntu\nlinux /EFI/ubuntu/vmlinuz\n")?;
+
+    let output0 = common::lanzaboote_install_with_args(
+        0,
+        esp.path(),
+        [&generation_link],
+        ["--legacy-entry-policy", "warn"],
+    )?;
+    assert!(output0.status.success());
+    assert!(legacy_entry.exists());
+
+    let output1 = common::lanzaboote_install(0, esp.path(), [&generation_link])?;
+    assert!(output1.status.success());
+    assert!(!legacy_entry.exists());
+    assert!(foreign_entry.exists());
+
+    Ok(())
+}
+
+#[test]
+fn refuse_to_install_invalid_initrd() -> Result<()> {
+    let esp = tempdir()?;
+    let tmpdir = tempdir()?;
+    let profiles = tempdir()?;
+    let toplevel = common::setup_toplevel(tmpdir.path())?;
+    fs::write(toplevel.join("initrd"), b"Not an initrd")?;
+    let generation_link = setup_generation_link_from_toplevel(&toplevel, profiles.path(), 1)?;
+
+    let output0 = common::lanzaboote_install(0, esp.path(), [generation_link])?;
+    assert!(!output0.status.success());
+    assert!(String::from_utf8(output0.stderr)?.contains("Invalid initrd"));
+
+    // Nothing is written to the ESP.
+    assert_eq!(count_files(esp.path())?, 0);
+
+    Ok(())
+}
+
+#[test]
+fn provision_random_seed_and_system_token() -> Result<()> {
+    let esp = tempdir()?;
+    let tmpdir = tempdir()?;
+    let profiles = tempdir()?;
+    let efivarfs = tempdir()?;
+    let generation_link = common::setup_generation_link(tmpdir.path(), profiles.path(), 1)
+        .expect("Failed to setup generation link");
+
+    let random_seed = esp.path().join("loader/random-seed");
+    let system_token = efivarfs
+        .path()
+        .join("LoaderSystemToken-4a67b082-0a4c-41cf-b6c7-440b29bb8c4f");
+
+    let install = || {
+        common::lanzaboote_install_with_args(
+            0,
+            esp.path(),
+            [&generation_link],
+            ["--efivarfs".as_ref(), efivarfs.path().as_os_str()],
+        )
+    };
+
+    let output0 = install()?;
+    assert!(output0.status.success());
+    let seed = fs::read(&random_seed)?;
+    assert_eq!(seed.len(), 32);
+    let token = fs::read(&system_token)?;
+    // The token is prefixed with its attributes.
+    assert_eq!(token.len(), 4 + 32);
+
+    // The seed is refreshed, the token is kept.
+    let output1 = install()?;
+    assert!(output1.status.success());
+    assert_ne!(fs::read(&random_seed)?, seed);
+    assert_eq!(fs::read(&system_token)?, token);
+
+    Ok(())
+}
+
+#[test]
+fn ship_initrd_secrets_as_credential() -> Result<()> {
+    let esp = tempdir()?;
+    let tmpdir = tempdir()?;
+    let profiles = tempdir()?;
+    let systemd = common::setup_systemd_with_mock_creds(tmpdir.path())?;
+    let toplevel = common::setup_toplevel(tmpdir.path())?;
+    let generation_link = setup_generation_link_from_toplevel(&toplevel, profiles.path(), 1)?;
+
+    let append_initrd_secrets = tmpdir.path().join("append-initrd-secrets");
+    fs::write(
+        &append_initrd_secrets,
+        "#!/bin/sh\nprintf 'secret' >> \"$1\"\n",
+    )?;
+    fs::set_permissions(&append_initrd_secrets, fs::Permissions::from_mode(0o755))?;
+    add_initrd_secrets(&generation_link, &append_initrd_secrets)?;
+
+    let output0 = common::lanzaboote_install_with_args(
+        0,
+        esp.path(),
+        [&generation_link],
+        [
+            "--systemd".as_ref(),
+            systemd.as_os_str(),
+            "--initrd-secrets-credential".as_ref(),
+            "tpm2".as_ref(),
+        ],
+    )?;
+    assert!(output0.status.success());
+
+    let credential = esp
+        .path()
+        .join("EFI/Linux/nixos-generation-1.efi.extra.d/initrd-secrets.cred");
+    assert_eq!(fs::read(credential)?, b"secret");
+    let systemd_creds_args = fs::read_to_string(systemd.join("bin/systemd-creds.args"))?;
+    assert!(systemd_creds_args.contains("--name=initrd-secrets\n"));
+    assert!(systemd_creds_args.contains("--with-key=tpm2\n"));
+
+    // The initrd on the ESP is the initrd from the store without the secrets.
+    let initrd = esp
+        .path()
+        .join("EFI/nixos")
+        .join(nixos_path(toplevel.join("initrd"), "initrd")?);
+    assert_eq!(hash_file(&initrd), hash_file(&toplevel.join("initrd")));
+
+    Ok(())
+}
+
+/// Add an `initrdSecrets` script to the bootspec of a generation link.
+fn add_initrd_secrets(generation_link: &Path, append_initrd_secrets: &Path) -> Result<()> {
+    let bootspec_path = generation_link.join("boot.json");
+    let mut bootspec: serde_json::Value = serde_json::from_slice(&fs::read(&bootspec_path)?)?;
+    bootspec["org.nixos.bootspec.v1"]["initrdSecrets"] = append_initrd_secrets.to_str().into();
+    fs::write(&bootspec_path, serde_json::to_vec(&bootspec)?)?;
+    filetime::set_file_mtime(generation_link, filetime::FileTime::zero())?;
+    Ok(())
+}
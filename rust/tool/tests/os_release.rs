@@ -23,14 +23,17 @@ fn generate_expected_os_release() -> Result<()> {
             .path()
             .join("EFI/Linux/nixos-generation-1.efi"),
     )?;
-    let os_release_section = pe_section(&stub_data, ".osrel")
+    let os_release_section = common::pe_section(&stub_data, ".osrel")
         .context("Failed to read .osrelease PE section.")?
         .to_owned();
 
     let expected = expect![[r#"
-        ID=lanza
-        PRETTY_NAME=LanzaOS
-        VERSION_ID=Generation 1, Built on 1970-01-01
+        ID=nixos
+        IMAGE_VERSION=1.1
+        NAME=NixOS
+        PRETTY_NAME="NixOS 23.05 (Linux 6.1.1), Generation 1, Added on 1970-01-01 00:00:00 UTC (link mtime)"
+        SORT_KEY=nixos
+        VERSION_ID=1.1
     "#]];
 
     expected.assert_eq(&String::from_utf8(os_release_section)?);
@@ -38,17 +41,59 @@ fn generate_expected_os_release() -> Result<()> {
     Ok(())
 }
 
-fn pe_section<'a>(file_data: &'a [u8], section_name: &str) -> Option<&'a [u8]> {
-    let pe_binary = goblin::pe::PE::parse(file_data).ok()?;
-
-    pe_binary
-        .sections
-        .iter()
-        .find(|s| s.name().unwrap() == section_name)
-        .and_then(|s| {
-            let section_start: usize = s.pointer_to_raw_data.try_into().ok()?;
-            assert!(s.virtual_size <= s.size_of_raw_data);
-            let section_end: usize = section_start + usize::try_from(s.virtual_size).ok()?;
-            Some(&file_data[section_start..section_end])
-        })
+#[test]
+fn generate_os_release_from_generation_os_release() -> Result<()> {
+    let esp_mountpoint = tempdir()?;
+    let tmpdir = tempdir()?;
+    let profiles = tempdir()?;
+    let toplevel = common::setup_toplevel(tmpdir.path())?;
+
+    fs::create_dir(toplevel.join("etc"))?;
+    fs::write(
+        toplevel.join("etc/os-release"),
+        indoc::indoc! {r#"
+            NAME=NixOS
+            ID=nixos
+            VERSION="23.05 (Stoat)"
+            BUILD_ID="23.05.20230101.abcdef"
+            IMAGE_ID=my-image
+            HOME_URL="https://nixos.org/"
+        "#},
+    )?;
+
+    let generation_link =
+        common::setup_generation_link_from_toplevel(&toplevel, profiles.path(), 1)?;
+
+    let output0 = common::lanzaboote_install_with_args(
+        0,
+        esp_mountpoint.path(),
+        vec![generation_link],
+        ["--title-template", "{label} {generation}"],
+    )?;
+    assert!(output0.status.success());
+
+    let stub_data = fs::read(
+        esp_mountpoint
+            .path()
+            .join("EFI/Linux/nixos-generation-1.efi"),
+    )?;
+    let os_release_section = common::pe_section(&stub_data, ".osrel")
+        .context("Failed to read .osrelease PE section.")?
+        .to_owned();
+
+    let expected = expect![[r#"
+        BUILD_ID=23.05.20230101.abcdef
+        ID=nixos
+        IMAGE_ID=my-image
+        IMAGE_VERSION=1.1
+        NAME=NixOS
+        PRETTY_NAME="LanzaOS 1"
+        SORT_KEY=my-image
+        VERSION="23.05 (Stoat)"
+        VERSION_ID=1.1
+    "#]];
+
+    expected.assert_eq(&String::from_utf8(os_release_section)?);
+
+    Ok(())
 }
@@ -0,0 +1,168 @@
+use std::fs;
+
+use anyhow::{Context, Result};
+use tempfile::tempdir;
+
+mod common;
+
+use common::hash_file;
+
+#[test]
+fn keep_rescue_image_and_its_files() -> Result<()> {
+    let esp = tempdir()?;
+    let tmpdir = tempdir()?;
+    let profiles = tempdir()?;
+    let generation_link1 = common::setup_generation_link(tmpdir.path(), profiles.path(), 1)?;
+    let generation_link2 = common::setup_generation_link(tmpdir.path(), profiles.path(), 2)?;
+
+    let rescue_image = esp.path().join("EFI/Linux/nixos-rescue.efi");
+
+    let output0 = common::lanzaboote_install_with_args(
+        1,
+        esp.path(),
+        [&generation_link1],
+        ["--rescue".as_ref(), generation_link1.as_os_str()],
+    )?;
+    assert!(output0.status.success());
+
+    let image = fs::read(&rescue_image)?;
+    let os_release = common::pe_section(&image, ".osrel").context("Missing .osrel section")?;
+    assert!(std::str::from_utf8(os_release)?.contains("(Rescue)"));
+    let rescue_hash = hash_file(&rescue_image);
+
+    // The rescue image and its kernel survive the garbage collection without being passed
+    // again.
+    let output1 = common::lanzaboote_install(1, esp.path(), [&generation_link2])?;
+    assert!(output1.status.success());
+    assert_eq!(hash_file(&rescue_image), rescue_hash);
+    let kernel_path = common::pe_section(&image, ".kernelp").context("Missing .kernelp section")?;
+    let kernel_path = std::str::from_utf8(kernel_path)?.replace('\\', "/");
+    assert!(esp
+        .path()
+        .join(kernel_path.trim_start_matches('/'))
+        .exists());
+
+    Ok(())
+}
+
+#[test]
+fn replace_rescue_image_only_when_requested() -> Result<()> {
+    let esp = tempdir()?;
+    let tmpdir = tempdir()?;
+    let profiles = tempdir()?;
+    let generation_link1 = common::setup_generation_link(tmpdir.path(), profiles.path(), 1)?;
+    let generation_link2 = common::setup_generation_link(tmpdir.path(), profiles.path(), 2)?;
+
+    let rescue_image = esp.path().join("EFI/Linux/nixos-rescue.efi");
+    let rescue_version = || -> Result<String> {
+        let image = fs::read(&rescue_image)?;
+        let os_release = common::pe_section(&image, ".osrel").context("Missing .osrel section")?;
+        Ok(std::str::from_utf8(os_release)?
+            .lines()
+            .find(|l| l.starts_with("VERSION_ID="))
+            .context("Missing VERSION_ID")?
+            .to_string())
+    };
+
+    let output0 = common::lanzaboote_install_with_args(
+        0,
+        esp.path(),
+        [&generation_link1, &generation_link2],
+        ["--rescue".as_ref(), generation_link2.as_os_str()],
+    )?;
+    assert!(output0.status.success());
+    assert_eq!(rescue_version()?, "VERSION_ID=2.1");
+
+    // Generation 1 is older and does not replace the rescue image.
+    let output1 = common::lanzaboote_install_with_args(
+        0,
+        esp.path(),
+        [&generation_link1, &generation_link2],
+        ["--rescue".as_ref(), generation_link1.as_os_str()],
+    )?;
+    assert!(output1.status.success());
+    assert_eq!(rescue_version()?, "VERSION_ID=2.1");
+
+    let output2 = common::lanzaboote_install_with_args(
+        0,
+        esp.path(),
+        [&generation_link1, &generation_link2],
+        [
+            "--rescue".as_ref(),
+            generation_link1.as_os_str(),
+            "--replace-rescue".as_ref(),
+        ],
+    )?;
+    assert!(output2.status.success());
+    assert_eq!(rescue_version()?, "VERSION_ID=1.1");
+
+    Ok(())
+}
+
+#[test]
+fn keep_rescue_image_if_rescue_generation_is_invalid() -> Result<()> {
+    let esp = tempdir()?;
+    let tmpdir = tempdir()?;
+    let broken_tmpdir = tempdir()?;
+    let profiles = tempdir()?;
+    let generation_link1 = common::setup_generation_link(tmpdir.path(), profiles.path(), 1)?;
+    let generation_link2 = common::setup_generation_link(tmpdir.path(), profiles.path(), 2)?;
+    let broken_toplevel = common::setup_toplevel(broken_tmpdir.path())?;
+    fs::write(broken_toplevel.join("initrd"), b"Not an initrd")?;
+    let broken_link =
+        common::setup_generation_link_from_toplevel(&broken_toplevel, profiles.path(), 3)?;
+
+    let rescue_image = esp.path().join("EFI/Linux/nixos-rescue.efi");
+
+    let output0 = common::lanzaboote_install_with_args(
+        0,
+        esp.path(),
+        [&generation_link1],
+        ["--rescue".as_ref(), generation_link1.as_os_str()],
+    )?;
+    assert!(output0.status.success());
+    let rescue_hash = hash_file(&rescue_image);
+
+    // The invalid rescue generation neither aborts the install nor replaces the rescue image.
+    let output1 = common::lanzaboote_install_with_args(
+        0,
+        esp.path(),
+        [&generation_link2],
+        [
+            "--rescue".as_ref(),
+            broken_link.as_os_str(),
+            "--replace-rescue".as_ref(),
+        ],
+    )?;
+    assert!(output1.status.success());
+    assert!(String::from_utf8(output1.stderr)?.contains("Invalid initrd"));
+    assert_eq!(hash_file(&rescue_image), rescue_hash);
+
+    Ok(())
+}
+
+#[test]
+fn rebuild_unsigned_rescue_image() -> Result<()> {
+    let esp = tempdir()?;
+    let tmpdir = tempdir()?;
+    let profiles = tempdir()?;
+    let generation_link = common::setup_generation_link(tmpdir.path(), profiles.path(), 1)?;
+
+    let rescue_image = esp.path().join("EFI/Linux/nixos-rescue.efi");
+    let rescue_args = ["--rescue".as_ref(), generation_link.as_os_str()];
+
+    let output0 =
+        common::lanzaboote_install_with_args(0, esp.path(), [&generation_link], rescue_args)?;
+    assert!(output0.status.success());
+
+    common::remove_signature(&rescue_image)?;
+    assert!(!common::verify_signature(&rescue_image)?);
+
+    // The rescue generation is unchanged, but the unsigned image is rebuilt anyway.
+    let output1 =
+        common::lanzaboote_install_with_args(0, esp.path(), [&generation_link], rescue_args)?;
+    assert!(output1.status.success());
+    assert!(common::verify_signature(&rescue_image)?);
+
+    Ok(())
+}
@@ -112,6 +112,93 @@ fn overwrite_unsigned_systemd_boot_binaries() -> Result<()> {
     Ok(())
 }
 
+#[test]
+fn keep_systemd_boot_binaries_when_version_is_refused() -> Result<()> {
+    let esp = tempdir()?;
+    let tmpdir = tempdir()?;
+    let profiles = tempdir()?;
+    let generation_link = common::setup_generation_link(tmpdir.path(), profiles.path(), 1)
+        .expect("Failed to setup generation link");
+    // No systemd-boot of this version is available.
+    let pin_args = ["--systemd-boot-version", "1"];
+
+    // Without an installed systemd-boot, refusing the available one fails the install.
+    let output0 =
+        common::lanzaboote_install_with_args(0, esp.path(), vec![&generation_link], pin_args)?;
+    assert!(!output0.status.success());
+    assert!(String::from_utf8(output0.stderr)?.contains("pinned to version 1"));
+
+    let output1 = common::lanzaboote_install(0, esp.path(), vec![&generation_link])?;
+    assert!(output1.status.success());
+    let systemd_boot_mtime1 = mtime(&systemd_boot_path(&esp));
+
+    let output2 =
+        common::lanzaboote_install_with_args(0, esp.path(), vec![generation_link], pin_args)?;
+    assert!(output2.status.success());
+    assert_eq!(
+        systemd_boot_mtime1,
+        mtime(&systemd_boot_path(&esp)),
+        "Refused systemd-boot binary was installed."
+    );
+
+    Ok(())
+}
+
+#[test]
+fn leave_foreign_fallback_boot_loader_alone() -> Result<()> {
+    let esp = tempdir()?;
+    let tmpdir = tempdir()?;
+    let profiles = tempdir()?;
+    let generation_link = common::setup_generation_link(tmpdir.path(), profiles.path(), 1)
+        .expect("Failed to setup generation link");
+
+    let systemd_boot_fallback_path = systemd_boot_fallback_path(&esp);
+    fs::create_dir_all(systemd_boot_fallback_path.parent().unwrap())?;
+    fs::copy(common::systemd_stub_binary()?, &systemd_boot_fallback_path)?;
+    let foreign_hash = hash_file(&systemd_boot_fallback_path);
+
+    let output0 = common::lanzaboote_install_with_args(
+        0,
+        esp.path(),
+        vec![generation_link],
+        ["--fallback-policy", "leave"],
+    )?;
+    assert!(output0.status.success());
+
+    assert_eq!(hash_file(&systemd_boot_fallback_path), foreign_hash);
+    assert!(verify_signature(&systemd_boot_path(&esp))?);
+
+    Ok(())
+}
+
+#[test]
+fn back_up_foreign_fallback_boot_loader() -> Result<()> {
+    let esp = tempdir()?;
+    let tmpdir = tempdir()?;
+    let profiles = tempdir()?;
+    let generation_link = common::setup_generation_link(tmpdir.path(), profiles.path(), 1)
+        .expect("Failed to setup generation link");
+
+    let systemd_boot_fallback_path = systemd_boot_fallback_path(&esp);
+    let backup_path = esp.path().join("EFI/nixos/fallback-backup/BOOTX64.EFI");
+    fs::create_dir_all(systemd_boot_fallback_path.parent().unwrap())?;
+    fs::copy(common::systemd_stub_binary()?, &systemd_boot_fallback_path)?;
+    let foreign_hash = hash_file(&systemd_boot_fallback_path);
+
+    let output0 = common::lanzaboote_install(0, esp.path(), vec![&generation_link])?;
+    assert!(output0.status.success());
+
+    assert!(verify_signature(&systemd_boot_fallback_path)?);
+    assert_eq!(hash_file(&backup_path), foreign_hash);
+
+    // The backup survives garbage collection.
+    let output1 = common::lanzaboote_install(0, esp.path(), vec![generation_link])?;
+    assert!(output1.status.success());
+    assert_eq!(hash_file(&backup_path), foreign_hash);
+
+    Ok(())
+}
+
 fn systemd_boot_path(esp: &tempfile::TempDir) -> PathBuf {
     esp.path().join("EFI/systemd/systemd-bootx64.efi")
 }
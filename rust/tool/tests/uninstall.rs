@@ -0,0 +1,124 @@
+use std::fs;
+use std::path::PathBuf;
+
+use anyhow::Result;
+use tempfile::tempdir;
+
+mod common;
+
+use common::{count_files, hash_file};
+
+#[test]
+fn dry_run_does_not_change_esp() -> Result<()> {
+    let esp = tempdir()?;
+    let tmpdir = tempdir()?;
+    let profiles = tempdir()?;
+    let generation_link = common::setup_generation_link(tmpdir.path(), profiles.path(), 1)
+        .expect("Failed to setup generation link");
+
+    let output0 = common::lanzaboote_install(0, esp.path(), vec![generation_link])?;
+    assert!(output0.status.success());
+    let files = walkdir::WalkDir::new(esp.path()).into_iter().count();
+
+    let output1 = common::lanzaboote_uninstall(esp.path(), ["--dry-run"])?;
+    assert!(output1.status.success());
+    assert_eq!(walkdir::WalkDir::new(esp.path()).into_iter().count(), files);
+
+    Ok(())
+}
+
+#[test]
+fn uninstall_leaves_foreign_files_alone() -> Result<()> {
+    let esp = tempdir()?;
+    let tmpdir = tempdir()?;
+    let profiles = tempdir()?;
+    let generation_link = common::setup_generation_link(tmpdir.path(), profiles.path(), 1)
+        .expect("Failed to setup generation link");
+
+    let output0 = common::lanzaboote_install(0, esp.path(), vec![generation_link])?;
+    assert!(output0.status.success());
+
+    let foreign_image = esp.path().join("EFI/Linux/ubuntu.efi");
+    fs::write(&foreign_image, b"ubuntu")?;
+
+    let output1 = common::lanzaboote_uninstall(esp.path(), Vec::<&str>::new())?;
+    assert!(output1.status.success());
+
+    assert!(foreign_image.exists());
+    assert_eq!(count_files(&esp.path().join("EFI/Linux"))?, 1);
+    assert!(!esp.path().join("EFI/nixos").exists());
+    assert!(!systemd_boot_path(&esp).exists());
+    assert!(!systemd_boot_fallback_path(&esp).exists());
+    assert!(!esp.path().join("loader/loader.conf").exists());
+
+    Ok(())
+}
+
+#[test]
+fn restore_backed_up_fallback_boot_loader() -> Result<()> {
+    let esp = tempdir()?;
+    let tmpdir = tempdir()?;
+    let profiles = tempdir()?;
+    let generation_link = common::setup_generation_link(tmpdir.path(), profiles.path(), 1)
+        .expect("Failed to setup generation link");
+
+    let systemd_boot_fallback_path = systemd_boot_fallback_path(&esp);
+    fs::create_dir_all(systemd_boot_fallback_path.parent().unwrap())?;
+    fs::copy(common::systemd_stub_binary()?, &systemd_boot_fallback_path)?;
+    let foreign_hash = hash_file(&systemd_boot_fallback_path);
+
+    let output0 = common::lanzaboote_install(0, esp.path(), vec![generation_link])?;
+    assert!(output0.status.success());
+    assert_ne!(hash_file(&systemd_boot_fallback_path), foreign_hash);
+
+    let unsigned_systemd_boot = common::systemd_boot_binary()?;
+    let systemd = unsigned_systemd_boot.ancestors().nth(5).unwrap();
+    let output1 = common::lanzaboote_uninstall(
+        esp.path(),
+        ["--restore-systemd-boot".as_ref(), systemd.as_os_str()],
+    )?;
+    assert!(output1.status.success());
+
+    assert_eq!(hash_file(&systemd_boot_fallback_path), foreign_hash);
+    assert_eq!(
+        hash_file(&systemd_boot_path(&esp)),
+        hash_file(&unsigned_systemd_boot)
+    );
+    assert!(!esp.path().join("EFI/nixos").exists());
+
+    Ok(())
+}
+
+#[test]
+fn keep_backed_up_fallback_boot_loader() -> Result<()> {
+    let esp = tempdir()?;
+    let tmpdir = tempdir()?;
+    let profiles = tempdir()?;
+    let generation_link = common::setup_generation_link(tmpdir.path(), profiles.path(), 1)
+        .expect("Failed to setup generation link");
+
+    let systemd_boot_fallback_path = systemd_boot_fallback_path(&esp);
+    fs::create_dir_all(systemd_boot_fallback_path.parent().unwrap())?;
+    fs::copy(common::systemd_stub_binary()?, &systemd_boot_fallback_path)?;
+    let foreign_hash = hash_file(&systemd_boot_fallback_path);
+
+    let output0 = common::lanzaboote_install(0, esp.path(), vec![generation_link])?;
+    assert!(output0.status.success());
+
+    let output1 = common::lanzaboote_uninstall(esp.path(), ["--no-restore-fallback"])?;
+    assert!(output1.status.success());
+
+    let backup = esp.path().join("EFI/nixos/fallback-backup/BOOTX64.EFI");
+    assert_eq!(hash_file(&backup), foreign_hash);
+    assert!(!systemd_boot_fallback_path.exists());
+
+    Ok(())
+}
+
+fn systemd_boot_path(esp: &tempfile::TempDir) -> PathBuf {
+    esp.path().join("EFI/systemd/systemd-bootx64.efi")
+}
+
+fn systemd_boot_fallback_path(esp: &tempfile::TempDir) -> PathBuf {
+    esp.path().join("EFI/BOOT/BOOTX64.EFI")
+}